@@ -1,104 +1,153 @@
 use std::collections::HashMap;
 use std::net::{ SocketAddr};
+use futures::future::join_all;
+use futures::Stream;
 use tokio::net::{TcpStream};
-use tracing::{debug, error};
+use tokio::sync::watch;
+use tracing::error;
 use errors::LedgerError;
-use network::{Data, p2p::process_incoming_data, p2p::send_data, p2p::SendEvent};
+use network::Message;
+use network::blocks::{BlockPage, BlockRange};
 use network::client2node::RequestType;
+use network::connection::{PeerConnection, PING_INTERVAL};
+use network::handshake::Handshake;
+use network::transport::{SecureStream, Transport};
+use state::Transaction;
+use state::chain_spec::ChainSpec;
 
 
 pub struct Client {
+    transport: Transport,
+    handshake: Handshake,
     peers: HashMap<u32, SocketAddr>,
+    /// connections to `peers`, opened on the first transaction
+    connections: Vec<PeerConnection>,
 }
 
 impl Client {
 
-    pub fn new() -> Self {
-        Self { peers: get_initial_peers() }
+    /// Client of the network of `chain_spec`, nodes of other chains refuse its transactions.
+    /// Transactions are sent to every node of `peers`
+    pub fn new(chain_spec: &ChainSpec, peers: Vec<SocketAddr>) -> Result<Self, LedgerError> {
+        let genesis = chain_spec.genesis_block()?;
+        Ok(Self {
+            transport: Transport::Plain,
+            handshake: Handshake::client(&chain_spec.chain_id, genesis.hash),
+            peers: (1..).zip(peers).collect(),
+            connections: vec![],
+        })
+    }
+
+    /// Transactions and API requests are sent over `transport`, it must match the transport of the nodes
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
     }
 
     pub fn add_peer() {
         todo!()
     }
 
-    pub async fn send_transaction_to_network(&mut self, transaction: Vec<u8>) {
-        let mut addresses = Vec::new();
-        self.peers.values().for_each(|addr| addresses.push(addr.clone()));
-        for (_, addr) in addresses.iter().enumerate() {
-            let addr = addr.clone();
-            let transaction_clone = transaction.clone();
-            let a = tokio::spawn(async move {
-                let stream = TcpStream::connect(addr.clone()).await;
-                if let Ok(mut stream) = stream {
-                    let res = send_data(
-                        &mut stream,
-                        transaction_clone.as_slice(),
-                        SendEvent::SendTransaction)
-                        .await;
-                    if res.is_err() {
-                        error!("error while sending command to peers : {}", res.err().unwrap());
-                    }
-                } else {
-                    error!("could not connect to node");
-                }
-            })
-                .await;
-            error!("{}", a.is_err());
+    /// Transaction is queued to every peer, connections are kept open for next transactions
+    pub async fn send_transaction_to_network(&mut self, transaction: Transaction) {
+        self.open_connections();
+        for connection in self.connections.iter() {
+            if let Err(e) = connection.send(Message::Transaction(transaction.clone())) {
+                error!("error while sending transaction to {}: {}", connection.address(), e);
+            }
+        }
+    }
+
+    /// Sends the transaction to every peer and waits for their replies. Succeeds if any node
+    /// has accepted it, otherwise returns the error it has been rejected with, e.g. `BadSignature`
+    pub async fn submit_transaction(&mut self, transaction: Transaction) -> Result<(), LedgerError> {
+        self.open_connections();
+        let replies = join_all(self.connections.iter()
+            .map(|connection| connection.request(Message::Transaction(transaction.clone()))))
+            .await;
+        let mut result = Err(LedgerError::NetworkError);
+        for reply in replies {
+            match reply {
+                Ok(_) => return Ok(()),
+                Err(e) => result = Err(e),
+            }
+        }
+        result
+    }
+
+    fn open_connections(&mut self) {
+        if self.connections.is_empty() {
+            let (_, handshake) = watch::channel(self.handshake.clone());
+            self.connections = self.peers.values()
+                .map(|addr| PeerConnection::open(*addr, self.transport.clone(), handshake.clone(), None, PING_INTERVAL))
+                .collect();
         }
     }
 
-    pub async fn client_request(node_addr: SocketAddr, request_type: RequestType)
-                                -> Result<Data, LedgerError>
+    /// Blocks of `range` in order of height, page by page as the node sends them
+    pub async fn blocks(&self, node_addr: SocketAddr, range: BlockRange)
+        -> Result<impl Stream<Item = Result<BlockPage, LedgerError>>, LedgerError>
+    {
+        let socket = self.connect_api(node_addr).await?;
+        network::client2node::request_blocks(socket, range).await
+    }
+
+    pub async fn client_request(&self, node_addr: SocketAddr, request_type: RequestType)
+                                -> Result<Message, LedgerError>
     {
-        let socket = TcpStream::connect(node_addr).await;
-        return if let Ok(mut socket) = socket {
-            match request_type {
-                RequestType::Blockchain { .. } => {
-                    return if let Ok(response) =
-                        network::client2node::client_request(&mut socket, request_type).await {
-                        Ok(response)
-                    } else {
-                        Err(LedgerError::ApiError)
-                    }
+        let mut socket = self.connect_api(node_addr).await?;
+        match request_type {
+            RequestType::Blocks(_) | RequestType::Bans => {
+                if let Ok(response) =
+                    network::client2node::client_request(&mut socket, request_type).await {
+                    Ok(response)
+                } else {
+                    Err(LedgerError::ApiError)
                 }
-                RequestType::Block { .. } => { todo!() }
-                RequestType::Transaction { .. } => { todo!() }
             }
-        } else {
-            error!("could not connect to node");
-            Err(LedgerError::NetworkError)
+            RequestType::Block { .. } => { todo!() }
+            RequestType::Transaction { .. } => { todo!() }
         }
     }
-}
 
-fn get_initial_peers() -> HashMap<u32, SocketAddr> {
-    HashMap::from([
-                (1, utils::socket_addr("1234")),
-                (2, utils::socket_addr("1235")),
-                (3, utils::socket_addr("1236"))
-            ])
+    /// API requests go over the transport of the client, as transactions do
+    async fn connect_api(&self, node_addr: SocketAddr) -> Result<SecureStream, LedgerError> {
+        let socket = TcpStream::connect(node_addr).await.map_err(|_| {
+            error!("could not connect to node");
+            LedgerError::NetworkError
+        })?;
+        self.transport.secure(socket, true).await
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use network::{ serialize_data};
     use state::{Transaction};
+    use state::chain_spec::ChainSpec;
     use crate::Client;
 
+    const CHAIN_SPEC: &str = include_str!("../../chain_spec.toml");
+
     #[tokio::test]
     async fn send_transaction_would_return_success() {
         let transaction = create_account_transaction();
-        let mut client = Client::new();
-        client.send_transaction_to_network(serialize_data(&transaction)).await;
+        let chain_spec = ChainSpec::from_toml(CHAIN_SPEC).unwrap();
+        let mut client = Client::new(&chain_spec, vec![utils::socket_addr("1234")]).unwrap();
+        client.send_transaction_to_network(transaction).await;
     }
 
     fn create_account_transaction() -> Transaction {
-        Transaction {
-            fee: 333,
-            commands: vec![state::Command::CreateAccount {
+        let (public_key, private_key) = crypto::generate_keypair();
+        Transaction::new_signed(
+            "ledger-local",
+            0,
+            333,
+            vec![state::Command::CreateAccount {
                 public_key: "12345".to_string(),
             }],
-        }
+            &public_key,
+            &private_key)
+            .unwrap()
     }
 }
 
@@ -1,5 +1,8 @@
-use blake2::{ Blake2s256, Digest};
+use blake2::Digest;
 use sha2::Sha256;
+use ursa::keys::{PrivateKey, PublicKey};
+use ursa::signatures::ed25519::Ed25519Sha512;
+use ursa::signatures::SignatureScheme;
 
 pub type Hash = Vec<u8>;
 pub const TARGET_HASH_PREFIX: &str = "00"; // TODO changing it depending on network size
@@ -27,16 +30,64 @@ pub fn hasher() -> Sha256 {
     Sha256::new()
 }
 
+/// Proof of work: hash must start with `difficulty` zero bytes
+pub fn is_hash_valid(hash: &Hash, difficulty: usize) -> bool {
+    hash.iter().take_while(|n| **n == 0u8).count() >= difficulty
+}
+
+/// New ed25519 key pair as (public key, private key) bytes
+pub fn generate_keypair() -> (Vec<u8>, Vec<u8>) {
+    let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+    (public_key.0.clone(), private_key.0.clone())
+}
+
+/// ed25519 signature of `message`, `None` for malformed private key
+pub fn sign(message: &[u8], private_key: &[u8]) -> Option<Vec<u8>> {
+    let private_key = PrivateKey(private_key.to_vec());
+    Ed25519Sha512::new().sign(message, &private_key).ok()
+}
+
+/// Checks ed25519 `signature` of `message` made by the owner of `public_key`
+pub fn verify_signature(message: &[u8], signature: &[u8], public_key: &[u8]) -> bool {
+    let public_key = PublicKey(public_key.to_vec());
+    Ed25519Sha512::new()
+        .verify(message, signature, &public_key)
+        .unwrap_or(false)
+}
+
 #[cfg(test)]
 mod tests {
 
-    use crate::hash;
+    use ursa::signatures::ed25519::Ed25519Sha512;
+    use ursa::signatures::SignatureScheme;
+    use crate::{generate_keypair, hash, sign, verify_signature};
 
     #[test]
     fn test_hash_function() {
         dbg!(hash(&generate_block()));
     }
 
+    #[test]
+    fn verify_signature_of_message() {
+        let scheme = Ed25519Sha512::new();
+        let (public_key, private_key) = scheme.keypair(None).unwrap();
+        let (other_public_key, _) = scheme.keypair(None).unwrap();
+        let message = hash(&generate_block());
+        let signature = scheme.sign(&message, &private_key).unwrap();
+        assert!(verify_signature(&message, &signature, &public_key.0));
+        assert!(!verify_signature(&message, &signature, &other_public_key.0));
+        assert!(!verify_signature(&hash(&message), &signature, &public_key.0));
+        assert!(!verify_signature(&message, &signature[1..], &public_key.0));
+    }
+
+    #[test]
+    fn sign_with_generated_keypair() {
+        let (public_key, private_key) = generate_keypair();
+        let message = hash(&generate_block());
+        let signature = sign(&message, &private_key).unwrap();
+        assert!(verify_signature(&message, &signature, &public_key));
+    }
+
     fn generate_block() -> Vec<u8> {
         String::from("ABRACADABRA!!!").as_bytes().to_vec()
     }
@@ -15,16 +15,127 @@ pub enum LedgerError {
     BlockError,
     #[error("Genesis block already exists")]
     GenesisBlockError,
+    #[error("Invalid node configuration")]
+    ConfigError,
+    #[error("Invalid chain specification")]
+    ChainSpecError,
     #[error("Synchronization error")]
     SyncError,
     #[error("Persistence error")]
     PersistenceError,
+    #[error("Key management error")]
+    KeyError,
     #[error("API error")]
     ApiError,
     #[error("Insufficient funds")]
     InsufficientFunds,
+    #[error("Transaction belongs to another chain")]
+    WrongChainId,
+    #[error("Bad signature")]
+    BadSignature,
+    #[error("Peer failed the handshake")]
+    HandshakeError,
+    #[error("Peer did not respond in time")]
+    TimeoutError,
+    #[error("Frame exceeds size limit")]
+    FrameSizeError,
+    #[error("Unsupported message version")]
+    VersionError,
     #[error("No such asset")] // TODO ugly name
     NoSuchAsset,
-    
+    #[error("Transaction is already pending")]
+    DuplicateTransaction,
+    #[error("Transaction pool is full")]
+    MempoolFull,
+    #[error("Too many pending transactions of the signer")]
+    TooManyPending,
+    #[error("Block is not on top of the best block")]
+    StaleBlock,
+    #[error("Fee of the replacement is too low")]
+    ReplacementUnderpriced,
 }
 
+impl LedgerError {
+
+    /// Code of the error in error replies to requests, codes of existing errors must not change
+    pub fn code(&self) -> u16 {
+        match self {
+            LedgerError::WrongCommandError => 1,
+            LedgerError::NetworkError => 2,
+            LedgerError::SerializeError => 3,
+            LedgerError::DeserializationError => 4,
+            LedgerError::BlockError => 5,
+            LedgerError::GenesisBlockError => 6,
+            LedgerError::ConfigError => 7,
+            LedgerError::ChainSpecError => 8,
+            LedgerError::SyncError => 9,
+            LedgerError::PersistenceError => 10,
+            LedgerError::KeyError => 11,
+            LedgerError::ApiError => 12,
+            LedgerError::InsufficientFunds => 13,
+            LedgerError::WrongChainId => 14,
+            LedgerError::BadSignature => 15,
+            LedgerError::HandshakeError => 16,
+            LedgerError::TimeoutError => 17,
+            LedgerError::FrameSizeError => 18,
+            LedgerError::VersionError => 19,
+            LedgerError::NoSuchAsset => 20,
+            LedgerError::DuplicateTransaction => 21,
+            LedgerError::MempoolFull => 22,
+            LedgerError::TooManyPending => 23,
+            LedgerError::StaleBlock => 24,
+            LedgerError::ReplacementUnderpriced => 25,
+        }
+    }
+
+    /// Error of an error reply, unknown codes of newer nodes are reported as `ApiError`
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            1 => LedgerError::WrongCommandError,
+            2 => LedgerError::NetworkError,
+            3 => LedgerError::SerializeError,
+            4 => LedgerError::DeserializationError,
+            5 => LedgerError::BlockError,
+            6 => LedgerError::GenesisBlockError,
+            7 => LedgerError::ConfigError,
+            8 => LedgerError::ChainSpecError,
+            9 => LedgerError::SyncError,
+            10 => LedgerError::PersistenceError,
+            11 => LedgerError::KeyError,
+            13 => LedgerError::InsufficientFunds,
+            14 => LedgerError::WrongChainId,
+            15 => LedgerError::BadSignature,
+            16 => LedgerError::HandshakeError,
+            17 => LedgerError::TimeoutError,
+            18 => LedgerError::FrameSizeError,
+            19 => LedgerError::VersionError,
+            20 => LedgerError::NoSuchAsset,
+            21 => LedgerError::DuplicateTransaction,
+            22 => LedgerError::MempoolFull,
+            23 => LedgerError::TooManyPending,
+            24 => LedgerError::StaleBlock,
+            25 => LedgerError::ReplacementUnderpriced,
+            _ => LedgerError::ApiError,
+        }
+    }
+}
+
+impl From<io::Error> for LedgerError {
+    fn from(_: io::Error) -> Self {
+        LedgerError::NetworkError
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::LedgerError;
+
+    #[test]
+    fn error_codes_round_trip() {
+        for code in 1..=25 {
+            assert_eq!(LedgerError::from_code(code).code(), code);
+        }
+        assert_eq!(LedgerError::from_code(0), LedgerError::ApiError);
+        assert_eq!(LedgerError::from_code(u16::MAX), LedgerError::ApiError);
+    }
+}
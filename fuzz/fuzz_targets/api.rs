@@ -0,0 +1,19 @@
+#![no_main]
+
+use std::sync::Arc;
+use libfuzzer_sys::fuzz_target;
+use network::client2node::node_response;
+use network::Message;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+// Request of the client API, answered the way the node does
+fuzz_target!(|data: &[u8]| {
+    futures::executor::block_on(async {
+        let (mut client, mut node) = tokio::io::duplex(data.len() + 64 * 1024);
+        client.write_all(data).await.unwrap();
+        client.shutdown().await.unwrap();
+        let response = |_, _| futures::stream::iter([Message::Ack(()), Message::Ack(())]);
+        let _ = node_response(&mut node, Arc::new(Mutex::new(())), response).await;
+    });
+});
@@ -0,0 +1,32 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use network::codec::MessageCodec;
+use network::compact::PartialBlock;
+use network::{Envelope, Message};
+use tokio_util::codec::Decoder;
+
+// Compact blocks rebuilt from the transactions received before them,
+// completed by the `BlockTransactions` that follow
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    let mut pool = Vec::new();
+    let mut partial: Option<PartialBlock> = None;
+    while let Ok(Some(Envelope { message, .. })) = MessageCodec::default().decode(&mut buf) {
+        match message {
+            Message::Transaction(transaction) => pool.push(transaction),
+            Message::CompactBlock(compact) => {
+                let block = compact.reconstruct(pool.iter());
+                let _ = block.missing();
+                partial = Some(block);
+            }
+            Message::BlockTransactions(missing) => {
+                if let Some(block) = partial.take() {
+                    let _ = block.fill(missing.transactions);
+                }
+            }
+            _ => {}
+        }
+    }
+});
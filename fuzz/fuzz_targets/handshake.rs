@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use network::handshake::{self, Handshake};
+
+// Handshake of an inbound connection, `data` is everything the peer sends
+fuzz_target!(|data: &[u8]| {
+    let local = Handshake::client("fuzz", vec![0; 32]);
+    let mut reader = data;
+    let mut writer = Vec::new();
+    let _ = futures::executor::block_on(handshake::accept(&mut reader, &mut writer, &local));
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use network::codec::MessageCodec;
+use network::Envelope;
+use network::handshake::features;
+use tokio_util::codec::{Decoder, Encoder};
+
+// Frames of the p2p protocol and the client API as read from a connection, compressed or not
+fuzz_target!(|data: &[u8]| {
+    let mut codec = MessageCodec::negotiated(features::COMPRESSION);
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(Envelope { id, message })) = codec.decode(&mut buf) {
+        // decoded messages get logged and relayed
+        let _ = message.to_string();
+        let mut out = BytesMut::new();
+        codec.encode(Envelope { id, message }, &mut out).expect("decoded message is encoded back");
+    }
+});
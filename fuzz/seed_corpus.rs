@@ -0,0 +1,124 @@
+//! Writes seed inputs of every fuzz target to `corpus/<target>`, built from real encodings of
+//! the genesis block of `chain_spec.toml` and signed transactions.
+//! Run from the `fuzz` directory: `cargo run --bin seed_corpus`
+
+use std::fs;
+use std::path::Path;
+use bytes::BytesMut;
+use network::blocks::{BlockPage, BlockRange};
+use network::codec::MessageCodec;
+use network::compact::{BlockTransactions, BlockTransactionsRequest, CompactBlock};
+use network::handshake::{features, Handshake};
+use network::{serialize_data, Envelope, InventoryItem, Message};
+use state::chain_spec::ChainSpec;
+use state::{Block, Command, Transaction};
+use tokio_util::codec::Encoder;
+
+fn main() {
+    let spec = ChainSpec::from_toml(include_str!("../chain_spec.toml")).unwrap();
+    let genesis = spec.genesis_block().unwrap();
+    let transactions = (1..=3).map(|fee| transaction(&spec.chain_id, fee)).collect::<Vec<_>>();
+    let mut block = Block {
+        id: genesis.id + 1,
+        timestamp: genesis.timestamp + 1,
+        previous_block_hash: Some(genesis.hash.clone()),
+        transactions: transactions.clone(),
+        ..Default::default()
+    };
+    block.hash = block.compute_hash();
+    let compact = CompactBlock::new(&block);
+
+    let message_seeds = [
+        ("genesis", frames(vec![Message::Block(genesis.clone())])),
+        ("block", frames(vec![Message::Block(block.clone())])),
+        ("transaction", frames(vec![Message::Transaction(transactions[0].clone())])),
+        ("blocks", frames(vec![
+            Message::Blocks(BlockPage { blocks: vec![genesis.clone()], next: Some(1), more: true }),
+            Message::Blocks(BlockPage { blocks: vec![block.clone()], next: None, more: false }),
+        ])),
+        ("inventory", frames(vec![Message::Inventory(vec![
+            InventoryItem::Block(block.hash.clone()),
+            InventoryItem::Transaction(transactions[0].id()),
+        ])])),
+        ("get_data", frames(vec![Message::GetData(vec![InventoryItem::Block(block.hash.clone())])])),
+        ("compact_block", frames(vec![Message::CompactBlock(compact.clone())])),
+        ("get_block_transactions", frames(vec![Message::GetBlockTransactions(BlockTransactionsRequest {
+            hash: block.hash.clone(),
+            indexes: vec![1],
+        })])),
+        ("peers", frames(vec![
+            Message::Peer("127.0.0.1:1234".to_string()),
+            Message::Peers([("127.0.0.1:1235".to_string(), "10".to_string())].into_iter().collect()),
+        ])),
+        ("keepalive", frames(vec![Message::Ping(1), Message::Pong(1)])),
+        ("error", frames(vec![Message::Error(12), Message::Ack(())])),
+        ("compressed_block", compressed_frame(Message::Block(block.clone()))),
+    ];
+    write_corpus("message", &message_seeds);
+
+    let mut handshake = Vec::new();
+    let data = serialize_data(Handshake::client("fuzz", vec![0; 32]));
+    handshake.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    handshake.extend_from_slice(&data);
+    // initiator accepts the responder's handshake
+    handshake.push(1);
+    write_corpus("handshake", &[("client", handshake)]);
+
+    write_corpus("compact_block", &[
+        ("rebuilt_from_pool", frames(vec![
+            Message::Transaction(transactions[0].clone()),
+            Message::Transaction(transactions[1].clone()),
+            Message::Transaction(transactions[2].clone()),
+            Message::CompactBlock(compact.clone()),
+        ])),
+        ("missing_transaction", frames(vec![
+            Message::Transaction(transactions[0].clone()),
+            Message::Transaction(transactions[2].clone()),
+            Message::CompactBlock(compact),
+            Message::BlockTransactions(BlockTransactions {
+                hash: block.hash.clone(),
+                transactions: vec![transactions[1].clone()],
+            }),
+        ])),
+    ]);
+
+    write_corpus("api", &[
+        ("get_blocks", frames(vec![Message::GetBlocks(BlockRange::new(0, 3))])),
+        ("get_bans", frames(vec![Message::GetBans(())])),
+        ("not_a_request", frames(vec![Message::Block(block)])),
+    ]);
+}
+
+fn transaction(chain_id: &str, fee: u32) -> Transaction {
+    let (public_key, private_key) = crypto::generate_keypair();
+    let commands = vec![
+        Command::CreateAccount { public_key: "12345".to_string() },
+        Command::TransferFunds { account_from_id: 0, account_to_id: 1, value: fee, asset_id: "TEST".to_string() },
+    ];
+    Transaction::new_signed(chain_id, 0, fee, commands, &public_key, &private_key).unwrap()
+}
+
+/// Messages framed as requests, the way they are sent over a connection
+fn frames(messages: Vec<Message>) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    for message in messages {
+        MessageCodec::default().encode(Envelope { id: 1, message }, &mut buf).unwrap();
+    }
+    buf.to_vec()
+}
+
+/// Message framed the way it is sent to a peer that supports compression
+fn compressed_frame(message: Message) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    MessageCodec::negotiated(features::COMPRESSION).encode(Envelope { id: 1, message }, &mut buf).unwrap();
+    buf.to_vec()
+}
+
+fn write_corpus(target: &str, seeds: &[(&str, Vec<u8>)]) {
+    let dir = Path::new("corpus").join(target);
+    fs::create_dir_all(&dir).unwrap();
+    for (name, data) in seeds {
+        fs::write(dir.join(name), data).unwrap();
+    }
+    println!("{}: {} seeds", target, seeds.len());
+}
@@ -4,6 +4,7 @@ use tokio::net::{TcpListener, TcpStream};
 async fn main() {
 }
 
+#[allow(dead_code)]
 async fn receiver(listener: TcpListener) -> TcpStream {
     let listener = listener.accept().await.unwrap().0;
     println!("listener: {}", &listener.peer_addr().unwrap());
@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+use state::Block;
+use crate::p2p::MAX_BLOCK_SIZE;
+use crate::serialize_data;
+
+/// Count of blocks served for one range request, the rest of the range is requested from the cursor
+pub const MAX_RANGE_BLOCKS: u32 = 1000;
+/// Size of blocks in one page, a bigger block goes alone
+const PAGE_SIZE: usize = MAX_BLOCK_SIZE as usize;
+
+/// Request of blocks by height, genesis is at height 0. Blocks `from..=to` are served in order,
+/// at most `limit` of them (and never more than `MAX_RANGE_BLOCKS`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockRange {
+    pub from: u64,
+    pub to: u64,
+    pub limit: u32,
+}
+
+/// Part of the reply to a `BlockRange` request. A reply is a sequence of pages with the id
+/// of the request, every page but the last one has `more` set
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BlockPage {
+    pub blocks: Vec<Block>,
+    /// Height of the next block of the range, `None` when the node has no more blocks of it.
+    /// On the last page it is the cursor the range is continued from
+    pub next: Option<u64>,
+    pub more: bool,
+}
+
+impl BlockRange {
+
+    /// Range up to the height `to`, as long as the node serves it at once
+    pub fn new(from: u64, to: u64) -> Self {
+        Self { from, to, limit: MAX_RANGE_BLOCKS }
+    }
+
+    /// Height after the last block served for the request
+    fn end(&self) -> u64 {
+        let limit = self.limit.min(MAX_RANGE_BLOCKS) as u64;
+        self.to.saturating_add(1).min(self.from.saturating_add(limit))
+    }
+
+    /// Page of the reply starting at height `next`, `chain` is indexed by height
+    pub fn page(&self, chain: &[Block], next: u64) -> BlockPage {
+        let known = chain.len() as u64;
+        let stop = self.end().min(known);
+        let mut blocks = Vec::new();
+        let mut size = 0;
+        let mut height = next;
+        while height < stop {
+            let block = &chain[height as usize];
+            let block_size = serialize_data(block).len();
+            if !blocks.is_empty() && size + block_size > PAGE_SIZE {
+                break
+            }
+            size += block_size;
+            blocks.push(block.clone());
+            height += 1;
+        }
+        BlockPage {
+            blocks,
+            next: (height <= self.to && height < known).then_some(height),
+            more: height < stop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use state::Block;
+    use crate::blocks::{BlockRange, MAX_RANGE_BLOCKS};
+
+    #[test]
+    fn range_served_in_order_up_to_limit() {
+        let chain = (0..10).map(|id| Block { id, ..Default::default() }).collect::<Vec<_>>();
+        let page = BlockRange { from: 2, to: 8, limit: 4 }.page(&chain, 2);
+        assert_eq!(page.blocks.iter().map(|b| b.id).collect::<Vec<_>>(), vec![2, 3, 4, 5]);
+        // the rest of the range is continued from the cursor
+        assert_eq!((page.next, page.more), (Some(6), false));
+        let page = BlockRange::new(6, 8).page(&chain, 6);
+        assert_eq!(page.blocks.iter().map(|b| b.id).collect::<Vec<_>>(), vec![6, 7, 8]);
+        assert_eq!((page.next, page.more), (None, false));
+        // the node has no blocks past its best one
+        let page = BlockRange::new(8, u64::MAX).page(&chain, 8);
+        assert_eq!((page.blocks.len(), page.next, page.more), (2, None, false));
+        assert!(BlockRange::new(20, 30).page(&chain, 20).blocks.is_empty());
+        assert_eq!(BlockRange { from: 0, to: u64::MAX, limit: u32::MAX }.end(), MAX_RANGE_BLOCKS as u64);
+    }
+
+    #[test]
+    fn big_blocks_split_into_pages() {
+        let big = |id| Block { id, signature: vec![0; 800 * 1024], ..Default::default() };
+        let chain = (0..5).map(big).collect::<Vec<_>>();
+        let range = BlockRange::new(0, 4);
+        let page = range.page(&chain, 0);
+        assert_eq!((page.blocks.len(), page.next, page.more), (2, Some(2), true));
+        let page = range.page(&chain, 4);
+        assert_eq!((page.blocks.len(), page.next, page.more), (1, None, false));
+    }
+}
@@ -1,118 +1,123 @@
-use std::future::Future;
-use std::io::{Error, ErrorKind};
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::Mutex;
+use tokio_util::codec::Framed;
+use futures::{stream, SinkExt, Stream, StreamExt};
 use tracing::error;
-use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
-use crate::{Data, DATA_LENGTH, deserialize_data, read_exact_async, serialize_data, write_all_async};
+use errors::LedgerError;
+use crate::{Envelope, Message};
+use crate::blocks::{BlockPage, BlockRange};
+use crate::codec::MessageCodec;
 
 pub enum RequestType {
 
-    Blockchain { height: u64 },
+    /// Blocks by height in order, answered with a sequence of `Message::Blocks` pages
+    Blocks(BlockRange),
 
     Block { hash: Vec<u8> },
 
     Transaction { hash: Vec<u8> },
 
+    /// Peers banned by the node, answered with `Message::NodeResponse`: IP address -> unix time the ban expires
+    Bans,
+
 }
 
-/// 1-st byte - request type, 2-nd byte = length of second value, 3-rd - second value,
-/// 4-th byte - length of 3-rd value, 5-th byte 3-rd value ...
-pub async fn client_request(mut socket: &mut TcpStream, request_type: RequestType)
-                            -> Result<Data, Error>
+/// Request of the API
+const REQUEST_ID: u32 = 1;
+
+/// Request and response are messages of the same codec as the p2p protocol,
+/// a failed request is answered with the code of its error.
+/// Pages of `RequestType::Blocks` are collected into one, `request_blocks` streams them
+pub async fn client_request<S>(socket: &mut S, request_type: RequestType)
+                               -> Result<Message, LedgerError>
+    where S: AsyncRead + AsyncWrite + Unpin
 {
-    match request_type {
-        RequestType::Blockchain { height } => {
-            let cmd_buf = [1u8];
-            write_all_async(socket, &cmd_buf).await?;
-            let height_buf = height.to_be_bytes();
-            write_all_async(socket, &height_buf).await?;
-            let data_buf = read_node_response(socket).await?;
-            let response = deserialize_data(data_buf.as_slice());
-            return if let Ok(blockchain) = response {
-                Ok(blockchain)
-            } else {
-                let err = response.err().unwrap();
-                error!("error response api: {}", &err);
-                Err(Error::from(ErrorKind::InvalidInput))
+    let request = match request_type {
+        RequestType::Blocks(range) => {
+            let mut pages = Box::pin(request_blocks(socket, range).await?);
+            let mut blocks = BlockPage::default();
+            while let Some(page) = pages.next().await {
+                let page = page?;
+                blocks.blocks.extend(page.blocks);
+                blocks.next = page.next;
             }
+            return Ok(Message::Blocks(blocks))
         }
-        RequestType::Block { hash } => {
-            todo!()
+        RequestType::Block { .. } | RequestType::Transaction { .. } => {
+            error!("request is not supported by the API yet");
+            return Err(LedgerError::WrongCommandError)
         }
-        RequestType::Transaction { hash } => {
-            todo!()
+        RequestType::Bans => Message::GetBans(()),
+    };
+    let mut framed = Framed::new(socket, MessageCodec::default());
+    framed.send(Envelope { id: REQUEST_ID, message: request }).await?;
+    match framed.next().await {
+        Some(response) => response?.into_reply(),
+        None => {
+            error!("node closed the connection without response");
+            Err(LedgerError::NetworkError)
         }
     }
 }
 
-async fn write_u32_to_buf(socket: &mut TcpStream, data: u32) -> Result<(), Error>
-{
-    let mut buf  = [0u8; 4];
-    <BigEndian as ByteOrder>::write_u32(&mut buf.as_mut(), data);
-    write_all_async(socket, buf.as_slice()).await?;
-    Ok(())
-}
-
-async fn write_string_to_buf(socket: &mut TcpStream, data: &str) -> Result<(), Error>
-{
-    let data_buf = data.as_bytes();
-    let data_buf_len = (data_buf.len() as u32).to_be_bytes();
-    write_all_async(socket, &data_buf_len).await?;
-    write_all_async(socket, data_buf).await?;
-    Ok(())
-}
-
-// TODO prevent copying bytes to vec
-async fn read_node_response(socket: &mut TcpStream)  -> Result<Vec<u8>, Error>  // , mut buf: &mut [u8]
+/// Pages of blocks of `range` as they arrive, the stream ends after the last page of the reply
+pub async fn request_blocks<S>(socket: S, range: BlockRange)
+    -> Result<impl Stream<Item = Result<BlockPage, LedgerError>>, LedgerError>
+    where S: AsyncRead + AsyncWrite + Unpin
 {
-    let mut len_buf = DATA_LENGTH;
-    read_exact_async(socket, &mut len_buf).await?;
-    let len = u32::from_be_bytes(len_buf);
-    let mut data_buf = vec![0; len as _];
-    read_exact_async(socket, &mut data_buf).await?;
-    Ok(data_buf)
+    let mut framed = Framed::new(socket, MessageCodec::default());
+    framed.send(Envelope { id: REQUEST_ID, message: Message::GetBlocks(range) }).await?;
+    Ok(stream::unfold(Some(framed), |framed| async move {
+        let mut framed = framed?;
+        let page = match framed.next().await {
+            Some(Ok(envelope)) if envelope.id == REQUEST_ID => match envelope.into_reply() {
+                Ok(Message::Blocks(page)) => Ok(page),
+                Ok(other) => {
+                    error!("unexpected reply to blocks request: {}", other.name());
+                    Err(LedgerError::WrongCommandError)
+                }
+                Err(e) => Err(e),
+            },
+            Some(Ok(_)) => Err(LedgerError::WrongCommandError),
+            Some(Err(e)) => Err(e),
+            None => {
+                error!("node closed the connection before the last page");
+                Err(LedgerError::NetworkError)
+            }
+        };
+        let more = matches!(page, Ok(BlockPage { more: true, .. }));
+        Some((page, more.then_some(framed)))
+    }))
 }
 
-pub async fn node_response<Miner, Func, Fut>(socket: &mut TcpStream,
-                                             miner: Arc<Mutex<Miner>>,
-                                             fn_blockchain_data: Func)
-                                             -> Result<(), Error>
-    where Func: Fn(Arc<Mutex<Miner>>, Option<RequestType>) -> Fut,
-          Fut: Future<Output = Vec<u8>>
+/// Answers one request read from `socket` with the messages of `fn_response`, in order.
+/// Malformed requests are errors and never panic
+pub async fn node_response<S, State, Func, St>(socket: &mut S,
+                                               state: Arc<Mutex<State>>,
+                                               fn_response: Func)
+                                               -> Result<(), LedgerError>
+    where S: AsyncRead + AsyncWrite + Unpin,
+          Func: Fn(Arc<Mutex<State>>, Option<RequestType>) -> St,
+          St: Stream<Item = Message>
 {
-    let mut cmd_buf = [0u8];
-    read_exact_async(socket, &mut cmd_buf).await?;
-    match cmd_buf[0] {
-        1u8 => {
-            let mut height_buf = [0u8; 8];
-            read_exact_async(socket, &mut height_buf).await?;
-            let height = height_buf.as_slice().read_u64::<BigEndian>();
-            return if let Ok(height) = height {
-                let request_type = RequestType::Blockchain { height };
-                let response_buf = fn_blockchain_data(miner, Some(request_type)).await;
-                let response_buf_len = (response_buf.len() as u32).to_be_bytes();
-                write_all_async(socket, &response_buf_len).await?;
-                write_all_async(socket, response_buf.as_slice()).await?;
-                Ok(())
-            } else {
-                Err(Error::from(ErrorKind::InvalidInput))
-            }
-        }
-        2u8 => {
-            todo!()
-            //Ok(())
-        }
-        3u8 => {
-            todo!()
-            //Ok(())
-        }
-        _ => {
-            error!("Api request error");
-            Err(Error::from(ErrorKind::InvalidInput))
+    let mut framed = Framed::new(socket, MessageCodec::default());
+    let Envelope { id, message } = framed.next().await.ok_or(LedgerError::NetworkError)??;
+    let request_type = match message {
+        Message::GetBlocks(range) => RequestType::Blocks(range),
+        Message::GetBans(()) => RequestType::Bans,
+        other => {
+            error!("Api request error: {}", other);
+            let error = LedgerError::WrongCommandError;
+            framed.send(Envelope { id, message: Message::Error(error.code()) }).await?;
+            return Err(error)
         }
+    };
+    let mut response = Box::pin(fn_response(state, Some(request_type)));
+    while let Some(message) = response.next().await {
+        framed.send(Envelope { id, message }).await?;
     }
+    Ok(())
 }
 
 //     MintTokens {
@@ -0,0 +1,307 @@
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use tracing::error;
+use errors::LedgerError;
+use crate::{Envelope, Message};
+use crate::handshake::features;
+
+/// Version of the frame layout, frames of other versions are rejected
+pub const WIRE_VERSION: u8 = 2;
+/// version: u8, message tag: u8, request id: u32, payload length: u32
+const HEADER_SIZE: usize = 10;
+/// Bit of the tag byte set on frames with compressed payload
+const COMPRESSED: u8 = 0x80;
+/// Payloads of this size and bigger are compressed when the peer supports compression
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Length-delimited frames of `Message`s, used by both the p2p and the client protocol.
+/// Payload length is checked against the limit of the message before the payload is buffered.
+/// Compressed payload is the size of the payload (u32, big endian) followed by the payload in the raw
+/// lz4 block format, not the lz4 frame format. The size is checked against the limit of the message
+/// before anything is decompressed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessageCodec {
+    compression: bool,
+}
+
+impl MessageCodec {
+
+    /// Codec of a connection with the features both sides have agreed on
+    pub fn negotiated(features: u64) -> Self {
+        Self { compression: features & features::COMPRESSION != 0 }
+    }
+}
+
+impl Encoder<Envelope> for MessageCodec {
+    type Error = LedgerError;
+
+    fn encode(&mut self, envelope: Envelope, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let Envelope { id, message } = envelope;
+        let mut payload = message.encode_payload();
+        if payload.len() > Message::max_size(message.tag()).unwrap_or_default() as usize {
+            error!("{} of {} bytes exceeds size limit", message.name(), payload.len());
+            return Err(LedgerError::FrameSizeError)
+        }
+        let mut tag = message.tag();
+        if self.compression && payload.len() >= COMPRESSION_THRESHOLD {
+            let mut compressed = (payload.len() as u32).to_be_bytes().to_vec();
+            compressed.extend_from_slice(&lz4_flex::block::compress(&payload));
+            // incompressible payload goes as is
+            if compressed.len() < payload.len() {
+                payload = compressed;
+                tag |= COMPRESSED;
+            }
+        }
+        dst.reserve(HEADER_SIZE + payload.len());
+        dst.put_u8(WIRE_VERSION);
+        dst.put_u8(tag);
+        dst.put_u32(id);
+        dst.put_u32(payload.len() as u32);
+        dst.put_slice(&payload);
+        Ok(())
+    }
+}
+
+/// Message nobody waits a reply to
+impl Encoder<Message> for MessageCodec {
+    type Error = LedgerError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode(Envelope::from(message), dst)
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Envelope;
+    type Error = LedgerError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HEADER_SIZE {
+            return Ok(None)
+        }
+        if src[0] != WIRE_VERSION {
+            error!("message of unsupported version {}", src[0]);
+            return Err(LedgerError::VersionError)
+        }
+        let compressed = src[1] & COMPRESSED != 0;
+        if compressed && !self.compression {
+            error!("compressed message on connection without compression");
+            return Err(LedgerError::WrongCommandError)
+        }
+        let tag = src[1] & !COMPRESSED;
+        let max_size = Message::max_size(tag).ok_or(LedgerError::WrongCommandError)?;
+        let id = u32::from_be_bytes([src[2], src[3], src[4], src[5]]);
+        let len = u32::from_be_bytes([src[6], src[7], src[8], src[9]]);
+        if len > max_size {
+            error!("message {} of {} bytes exceeds size limit", tag, len);
+            return Err(LedgerError::FrameSizeError)
+        }
+        let len = len as usize;
+        if src.len() < HEADER_SIZE + len {
+            src.reserve(HEADER_SIZE + len - src.len());
+            return Ok(None)
+        }
+        src.advance(HEADER_SIZE);
+        let payload = src.split_to(len);
+        let message = if compressed {
+            Message::decode_payload(tag, &decompress(&payload, max_size)?)?
+        } else {
+            Message::decode_payload(tag, &payload)?
+        };
+        Ok(Some(Envelope { id, message }))
+    }
+}
+
+/// Decompresses payload of a compressed frame, at most `max_size` bytes are ever allocated
+fn decompress(payload: &[u8], max_size: u32) -> Result<Vec<u8>, LedgerError> {
+    if payload.len() < 4 {
+        return Err(LedgerError::DeserializationError)
+    }
+    let size = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+    if size > max_size {
+        error!("compressed message of {} bytes exceeds size limit", size);
+        return Err(LedgerError::FrameSizeError)
+    }
+    let data = lz4_flex::block::decompress(&payload[4..], size as usize).map_err(|e| {
+        error!("could not decompress message: {}", e);
+        LedgerError::DeserializationError
+    })?;
+    if data.len() != size as usize {
+        error!("compressed message is {} bytes instead of {}", data.len(), size);
+        return Err(LedgerError::DeserializationError)
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use tokio_util::codec::{Decoder, Encoder};
+    use errors::LedgerError;
+    use state::{Block, Command, Transaction};
+    use crate::codec::{MessageCodec, COMPRESSED, COMPRESSION_THRESHOLD, HEADER_SIZE, WIRE_VERSION};
+    use crate::compact::CompactBlock;
+    use crate::handshake::features;
+    use crate::{Envelope, InventoryItem, Message};
+    use crate::blocks::{BlockPage, BlockRange};
+    use crate::p2p::{MAX_BLOCK_PAGE_SIZE, MAX_BLOCK_SIZE, MAX_TRANSACTION_SIZE};
+
+    #[test]
+    fn messages_round_trip() {
+        let mut buf = BytesMut::new();
+        let block = Block { id: 7, ..Default::default() };
+        MessageCodec::default().encode(Message::Block(block), &mut buf).unwrap();
+        MessageCodec::default().encode(Envelope { id: 3, message: Message::GetBans(()) }, &mut buf).unwrap();
+        let mut partial = buf.split_to(14);
+        assert!(MessageCodec::default().decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        assert!(matches!(MessageCodec::default().decode(&mut partial),
+            Ok(Some(Envelope { id: 0, message: Message::Block(b) })) if b.id == 7));
+        assert!(matches!(MessageCodec::default().decode(&mut partial),
+            Ok(Some(Envelope { id: 3, message: Message::GetBans(()) }))));
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn invalid_headers_rejected() {
+        let header = |version: u8, tag: u8, len: u32| {
+            let mut buf = BytesMut::from(&[version, tag][..]);
+            buf.extend_from_slice(&1u32.to_be_bytes());
+            buf.extend_from_slice(&len.to_be_bytes());
+            buf
+        };
+        assert_eq!(MessageCodec::default().decode(&mut header(WIRE_VERSION + 1, 1, 0)).err(), Some(LedgerError::VersionError));
+        assert_eq!(MessageCodec::default().decode(&mut header(WIRE_VERSION, 0, 0)).err(), Some(LedgerError::WrongCommandError));
+        // nothing is allocated for an oversized payload
+        let mut oversized = header(WIRE_VERSION, 1, MAX_BLOCK_SIZE + 1);
+        assert_eq!(MessageCodec::default().decode(&mut oversized).err(), Some(LedgerError::FrameSizeError));
+        assert!(oversized.capacity() < 1024);
+    }
+
+    #[test]
+    fn big_payloads_compressed() {
+        let mut codec = MessageCodec::negotiated(features::COMPRESSION);
+        let block = seed_block();
+        let mut plain = BytesMut::new();
+        MessageCodec::default().encode(Message::Block(block.clone()), &mut plain).unwrap();
+        let mut buf = BytesMut::new();
+        codec.encode(Message::Block(block.clone()), &mut buf).unwrap();
+        assert_eq!(buf[1], 1 | COMPRESSED);
+        assert!(buf.len() < plain.len() / 2);
+        assert!(matches!(codec.decode(&mut buf), Ok(Some(Envelope { message: Message::Block(b), .. })) if b.hash == block.hash && b.transactions.len() == 40));
+        // small payloads are sent as is
+        codec.encode(Message::Ping(7), &mut buf).unwrap();
+        assert!(buf.len() < HEADER_SIZE + COMPRESSION_THRESHOLD && buf[1] & COMPRESSED == 0);
+        assert!(matches!(codec.decode(&mut buf), Ok(Some(Envelope { message: Message::Ping(7), .. }))));
+        // compressed frames are a protocol violation unless compression was negotiated
+        codec.encode(Message::Block(block), &mut buf).unwrap();
+        assert_eq!(MessageCodec::default().decode(&mut buf).err(), Some(LedgerError::WrongCommandError));
+    }
+
+    #[test]
+    fn decompression_bombs_rejected() {
+        let mut codec = MessageCodec::negotiated(features::COMPRESSION);
+        let frame = |tag: u8, size: u32, data: &[u8]| {
+            let mut payload = size.to_be_bytes().to_vec();
+            payload.extend_from_slice(&lz4_flex::block::compress(data));
+            let mut buf = BytesMut::from(&[WIRE_VERSION, tag | COMPRESSED][..]);
+            buf.extend_from_slice(&1u32.to_be_bytes());
+            buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&payload);
+            buf
+        };
+        // a few bytes of a transaction claiming to decompress past its limit
+        let zeros = vec![0; MAX_TRANSACTION_SIZE as usize + 1];
+        let mut bomb = frame(2, zeros.len() as u32, &zeros);
+        assert!(bomb.len() < 1024);
+        assert_eq!(codec.decode(&mut bomb).err(), Some(LedgerError::FrameSizeError));
+        // the size is trusted only as far as the data agrees with it
+        let mut lying = frame(2, 16, &zeros);
+        assert_eq!(codec.decode(&mut lying).err(), Some(LedgerError::DeserializationError));
+        let mut short = frame(2, MAX_TRANSACTION_SIZE, &[0; 16]);
+        assert_eq!(codec.decode(&mut short).err(), Some(LedgerError::DeserializationError));
+    }
+
+    #[test]
+    fn mutated_frames_never_panic() {
+        let seeds = seed_frames();
+        let mut rng = StdRng::seed_from_u64(45);
+        for _ in 0..20_000 {
+            let mut frame = seeds[rng.gen_range(0..seeds.len())].clone();
+            for _ in 0..rng.gen_range(1..8) {
+                let i = rng.gen_range(0..frame.len());
+                match rng.gen_range(0..3) {
+                    0 => frame[i] = rng.gen(),
+                    1 => frame.truncate(i),
+                    _ => frame.insert(i, rng.gen()),
+                }
+                if frame.is_empty() {
+                    frame.push(WIRE_VERSION);
+                }
+            }
+            let mut buf = BytesMut::from(frame.as_slice());
+            while let Ok(Some(Envelope { message, .. })) = MessageCodec::negotiated(features::COMPRESSION).decode(&mut buf) {
+                // decoded messages get logged
+                let _ = message.to_string();
+            }
+            // never more than the largest frame is buffered, whatever the input claims
+            assert!(buf.capacity() <= (2 * frame.len()).max(HEADER_SIZE + MAX_BLOCK_PAGE_SIZE as usize));
+        }
+    }
+
+    #[test]
+    fn empty_block_decoded_and_displayed() {
+        // found by fuzzing: logging a block without transactions panicked
+        let mut buf = BytesMut::new();
+        MessageCodec::default().encode(Message::Block(Block::default()), &mut buf).unwrap();
+        let envelope = MessageCodec::default().decode(&mut buf).unwrap().unwrap();
+        assert!(envelope.message.to_string().starts_with("data (block)"));
+    }
+
+    fn seed_block() -> Block {
+        let transaction = Transaction {
+            chain_id: "test".to_string(),
+            signer: vec![1; 32],
+            nonce: 0,
+            fee: 10,
+            commands: vec![Command::TransferFunds { account_from_id: 0, account_to_id: 1, value: 5, asset_id: "TEST".to_string() }],
+            signature: vec![2; 64],
+        };
+        Block { id: 1, hash: vec![3; 32], transactions: vec![transaction; 40], ..Default::default() }
+    }
+
+    fn seed_frames() -> Vec<Vec<u8>> {
+        let transaction = Transaction {
+            chain_id: "test".to_string(),
+            signer: vec![1; 32],
+            nonce: 0,
+            fee: 10,
+            commands: vec![Command::CreateAccount { public_key: "12345".to_string() }],
+            signature: vec![2; 64],
+        };
+        let block = Block { id: 1, hash: vec![3; 32], transactions: vec![transaction.clone(); 3], ..Default::default() };
+        let messages = vec![
+            Message::Block(block.clone()),
+            Message::Transaction(transaction.clone()),
+            Message::CompactBlock(CompactBlock::new(&block)),
+            Message::Inventory(vec![InventoryItem::Block(block.hash.clone()), InventoryItem::Transaction(transaction.id())]),
+            Message::Peers([("127.0.0.1:1235".to_string(), "10".to_string())].into_iter().collect()),
+            Message::GetBlocks(BlockRange::new(0, 3)),
+            Message::Blocks(BlockPage { blocks: vec![block.clone()], next: Some(2), more: true }),
+            Message::Ping(7),
+        ];
+        let mut frames = messages.into_iter()
+            .map(|message| {
+                let mut buf = BytesMut::new();
+                MessageCodec::default().encode(Envelope { id: 1, message }, &mut buf).unwrap();
+                buf.to_vec()
+            })
+            .collect::<Vec<_>>();
+        let mut buf = BytesMut::new();
+        MessageCodec::negotiated(features::COMPRESSION).encode(Message::Block(seed_block()), &mut buf).unwrap();
+        frames.push(buf.to_vec());
+        frames
+    }
+}
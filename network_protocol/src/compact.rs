@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use errors::LedgerError;
+use state::{Block, Transaction};
+
+/// Block relayed as its header and short ids of its transactions,
+/// receivers rebuild it from transactions of their pools
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactBlock {
+    /// Block without transactions
+    pub header: Block,
+    pub short_ids: Vec<u64>,
+}
+
+/// Request of transactions of a compact block by their indexes in the block
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockTransactionsRequest {
+    pub hash: Vec<u8>,
+    pub indexes: Vec<u32>,
+}
+
+/// Transactions requested by `BlockTransactionsRequest`, in order of the request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockTransactions {
+    pub hash: Vec<u8>,
+    pub transactions: Vec<Transaction>,
+}
+
+/// Short id of a transaction: first 8 bytes of sha256 over the block hash and the transaction id.
+/// Salting with the block hash keeps collisions from repeating across blocks
+pub fn short_id(block_hash: &[u8], transaction_id: &[u8]) -> u64 {
+    let mut buf = Vec::with_capacity(block_hash.len() + transaction_id.len());
+    buf.extend_from_slice(block_hash);
+    buf.extend_from_slice(transaction_id);
+    let hash = crypto::hash(&buf);
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+impl CompactBlock {
+
+    pub fn new(block: &Block) -> Self {
+        Self {
+            header: Block { transactions: vec![], ..block.clone() },
+            short_ids: block.transactions.iter()
+                .map(|transaction| short_id(&block.hash, &transaction.id()))
+                .collect(),
+        }
+    }
+
+    /// Fills in transactions found in `pool`, a short id matching several transactions is left missing
+    pub fn reconstruct<'a>(self, pool: impl IntoIterator<Item = &'a Transaction>) -> PartialBlock {
+        let mut index = HashMap::new();
+        for (i, short_id) in self.short_ids.iter().enumerate() {
+            index.insert(*short_id, i);
+        }
+        let mut transactions = vec![None; self.short_ids.len()];
+        let mut ambiguous = Vec::new();
+        for transaction in pool {
+            let Some(&i) = index.get(&short_id(&self.header.hash, &transaction.id())) else {
+                continue
+            };
+            if transactions[i].is_some() {
+                ambiguous.push(i);
+            }
+            transactions[i] = Some(transaction.clone());
+        }
+        for i in ambiguous {
+            transactions[i] = None;
+        }
+        PartialBlock { header: self.header, transactions }
+    }
+}
+
+/// Compact block waiting for its missing transactions
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    header: Block,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+
+    pub fn hash(&self) -> &[u8] {
+        &self.header.hash
+    }
+
+    /// Indexes of transactions the block is missing
+    pub fn missing(&self) -> Vec<u32> {
+        self.transactions.iter().enumerate()
+            .filter(|(_, transaction)| transaction.is_none())
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    /// Completes the block with the missing transactions in order, the result has to match
+    /// the block hash
+    pub fn fill(self, missing: Vec<Transaction>) -> Result<Block, LedgerError> {
+        let mut missing = missing.into_iter();
+        let mut transactions = Vec::with_capacity(self.transactions.len());
+        for transaction in self.transactions {
+            match transaction.or_else(|| missing.next()) {
+                Some(transaction) => transactions.push(transaction),
+                None => return Err(LedgerError::BlockError),
+            }
+        }
+        if missing.next().is_some() {
+            return Err(LedgerError::BlockError)
+        }
+        let block = Block { transactions, ..self.header };
+        if block.compute_hash() != block.hash {
+            return Err(LedgerError::BlockError)
+        }
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use state::{Block, Transaction};
+    use crate::compact::CompactBlock;
+
+    #[test]
+    fn block_rebuilt_from_pool_and_missing_transactions() {
+        let transactions = (1..=3).map(transaction).collect::<Vec<_>>();
+        let block = block(transactions.clone());
+        let pool = [transactions[0].clone(), transactions[2].clone(), transaction(4)];
+        let partial = CompactBlock::new(&block).reconstruct(pool.iter());
+        assert_eq!(partial.missing(), vec![1]);
+        assert!(partial.clone().fill(vec![]).is_err());
+        assert!(partial.clone().fill(vec![transaction(4)]).is_err());
+        let rebuilt = partial.fill(vec![transactions[1].clone()]).unwrap();
+        assert_eq!(rebuilt.hash, block.hash);
+        assert_eq!(rebuilt.transactions.len(), 3);
+    }
+
+    #[test]
+    fn compact_block_is_small() {
+        let block = block((1..=50).map(transaction).collect());
+        let compact = CompactBlock::new(&block);
+        assert!(compact.header.transactions.is_empty());
+        assert!(crate::serialize_data(&compact).len() * 4 < crate::serialize_data(&block).len());
+    }
+
+    fn block(transactions: Vec<Transaction>) -> Block {
+        let mut block = Block { id: 1, transactions, ..Default::default() };
+        block.hash = block.compute_hash();
+        block
+    }
+
+    fn transaction(fee: u32) -> Transaction {
+        let (public_key, private_key) = crypto::generate_keypair();
+        Transaction::new_signed("test", 0, fee, vec![], &public_key, &private_key).unwrap()
+    }
+}
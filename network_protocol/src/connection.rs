@@ -0,0 +1,373 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+use errors::LedgerError;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use crate::{Envelope, Message, RequestId};
+use crate::codec::MessageCodec;
+use crate::handshake::{self, Handshake};
+use crate::p2p::{is_protocol_violation, read_envelope, write_envelope, MessageReader, MessageWriter};
+use crate::transport::Transport;
+
+/// Count of messages waiting to be written to a peer, newer messages are dropped when it is full
+pub const OUTBOUND_QUEUE_SIZE: usize = 64;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Time to wait for the reply to a request
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default time between pings of a connection
+pub const PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Count of replies of a stream waiting to be taken, the stream fails when it is full
+/// so that the reader never waits for it
+const STREAM_BUFFER: usize = 16;
+
+/// Requests waiting for their replies, by request id
+type Responders = Arc<Mutex<HashMap<RequestId, Responder>>>;
+/// Nonce and send time of the ping waiting for its pong
+type PendingPing = Arc<Mutex<Option<(u64, Instant)>>>;
+
+/// Request waiting for its reply or, if answered with a sequence of messages, for all of them
+#[derive(Debug)]
+enum Responder {
+    Reply(oneshot::Sender<Envelope>),
+    Stream(mpsc::Sender<Envelope>),
+}
+
+/// What happened to a connection, reported to the owner of `PeerConnection`
+#[derive(Debug)]
+pub enum ConnectionEvent {
+    Connected(SocketAddr, Handshake),
+    /// Connection could not be established or has been lost, it will be retried after backoff
+    Failed(SocketAddr),
+    Received(SocketAddr, Message),
+    /// Round-trip time of a ping
+    Latency(SocketAddr, Duration),
+    /// Peer has broken the protocol, the connection is closed
+    Violation(SocketAddr),
+}
+
+/// Long-lived connection to a peer. Messages are queued and written by the connection task,
+/// which reconnects with exponential backoff whenever the connection is lost.
+/// The peer is pinged every `ping_interval`, the connection is considered lost if a ping
+/// is not answered until the next one. Clones share the connection, the task stops when all of them
+/// are dropped
+#[derive(Debug, Clone)]
+pub struct PeerConnection {
+    address: SocketAddr,
+    queue: mpsc::Sender<Envelope>,
+    responders: Responders,
+    next_id: Arc<AtomicU32>,
+}
+
+impl PeerConnection {
+
+    /// Handshake is taken from `handshake` every time the connection is (re)established,
+    /// messages received from the peer and connection status are sent to `events`
+    pub fn open(address: SocketAddr,
+                transport: Transport,
+                handshake: watch::Receiver<Handshake>,
+                events: Option<mpsc::Sender<ConnectionEvent>>,
+                ping_interval: Duration)
+        -> Self
+    {
+        let (queue, queue_rx) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+        let responders = Responders::default();
+        let task_responders = responders.clone();
+        tokio::spawn(async move {
+            let channels = Channels { events, queue: queue_rx, responders: task_responders };
+            run(address, transport, handshake, channels, ping_interval).await
+        });
+        Self { address, queue, responders, next_id: Arc::new(AtomicU32::new(1)) }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn send(&self, message: Message) -> Result<(), LedgerError> {
+        self.queue(Envelope::from(message))
+    }
+
+    /// Sends `message` as a request and waits for its reply, many requests may wait on one connection.
+    /// Requests in flight fail when the connection is lost
+    pub async fn request(&self, message: Message) -> Result<Message, LedgerError> {
+        let id = self.next_id();
+        let (responder, reply) = oneshot::channel();
+        self.responders.lock().unwrap().insert(id, Responder::Reply(responder));
+        if let Err(e) = self.queue(Envelope { id, message }) {
+            self.responders.lock().unwrap().remove(&id);
+            return Err(e)
+        }
+        match tokio::time::timeout(REQUEST_TIMEOUT, reply).await {
+            Ok(Ok(envelope)) => envelope.into_reply(),
+            Ok(Err(_)) => Err(LedgerError::NetworkError),
+            Err(_) => {
+                self.responders.lock().unwrap().remove(&id);
+                Err(LedgerError::TimeoutError)
+            }
+        }
+    }
+
+    /// Sends `message` as a request answered with a sequence of messages, e.g. pages of blocks.
+    /// The stream fails if more than `STREAM_BUFFER` replies wait to be taken
+    pub fn request_stream(&self, message: Message) -> Result<ReplyStream, LedgerError> {
+        let id = self.next_id();
+        let (responder, replies) = mpsc::channel(STREAM_BUFFER);
+        self.responders.lock().unwrap().insert(id, Responder::Stream(responder));
+        let stream = ReplyStream { id, replies, responders: self.responders.clone() };
+        self.queue(Envelope { id, message })?;
+        Ok(stream)
+    }
+
+    fn next_id(&self) -> RequestId {
+        // id 0 is never a request
+        self.next_id.fetch_add(1, Ordering::Relaxed).max(1)
+    }
+
+    fn queue(&self, envelope: Envelope) -> Result<(), LedgerError> {
+        self.queue.try_send(envelope).map_err(|e| {
+            error!("could not queue message to peer {}: {}", self.address, e);
+            LedgerError::NetworkError
+        })
+    }
+}
+
+/// Replies to a request of `PeerConnection::request_stream` in order they arrive,
+/// the request is forgotten when the stream is dropped
+#[derive(Debug)]
+pub struct ReplyStream {
+    id: RequestId,
+    replies: mpsc::Receiver<Envelope>,
+    responders: Responders,
+}
+
+impl ReplyStream {
+
+    /// Next reply, fails when the connection is lost or the peer is silent for `REQUEST_TIMEOUT`
+    pub async fn next(&mut self) -> Result<Message, LedgerError> {
+        match tokio::time::timeout(REQUEST_TIMEOUT, self.replies.recv()).await {
+            Ok(Some(envelope)) => envelope.into_reply(),
+            Ok(None) => Err(LedgerError::NetworkError),
+            Err(_) => Err(LedgerError::TimeoutError),
+        }
+    }
+}
+
+impl Drop for ReplyStream {
+    fn drop(&mut self) {
+        self.responders.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Connects to the peer, secures the connection with `transport` and performs the handshake as initiator
+pub async fn connect(address: SocketAddr, transport: &Transport, local: &Handshake)
+    -> Result<(Handshake, MessageReader, MessageWriter), LedgerError>
+{
+    let socket = TcpStream::connect(address).await.map_err(|e| {
+        debug!("could not connect to {}: {}", address, e);
+        LedgerError::NetworkError
+    })?;
+    let (remote_key, mut reader, mut writer) = transport.upgrade(socket, true).await?;
+    let remote = handshake::initiate(&mut reader, &mut writer, local).await
+        .map_err(|_| LedgerError::HandshakeError)?;
+    check_node_id(&remote, remote_key)?;
+    let codec = MessageCodec::negotiated(local.common_features(&remote));
+    Ok((remote, FramedRead::new(reader, codec), FramedWrite::new(writer, codec)))
+}
+
+/// Secures connection of a peer that has connected to us and performs the handshake
+pub async fn accept(socket: TcpStream, transport: &Transport, local: &Handshake)
+    -> Result<(Handshake, MessageReader, MessageWriter), LedgerError>
+{
+    let (remote_key, mut reader, mut writer) = transport.upgrade(socket, false).await?;
+    let remote = handshake::accept(&mut reader, &mut writer, local).await?;
+    check_node_id(&remote, remote_key)?;
+    let codec = MessageCodec::negotiated(local.common_features(&remote));
+    Ok((remote, FramedRead::new(reader, codec), FramedWrite::new(writer, codec)))
+}
+
+/// Node must present the key it has been authenticated with by the transport
+fn check_node_id(remote: &Handshake, remote_key: Option<Vec<u8>>) -> Result<(), LedgerError> {
+    match remote_key {
+        Some(key) if !remote.node_id.is_empty() && remote.node_id != key => {
+            error!("node id of peer does not match its transport key");
+            Err(LedgerError::HandshakeError)
+        }
+        _ => Ok(())
+    }
+}
+
+/// Channels between `PeerConnection` and its task
+struct Channels {
+    events: Option<mpsc::Sender<ConnectionEvent>>,
+    queue: mpsc::Receiver<Envelope>,
+    responders: Responders,
+}
+
+async fn run(address: SocketAddr,
+             transport: Transport,
+             handshake: watch::Receiver<Handshake>,
+             mut channels: Channels,
+             ping_interval: Duration)
+{
+    let mut pending = VecDeque::new();
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let local = handshake.borrow().clone();
+        match connect(address, &transport, &local).await {
+            Ok((remote, reader, writer)) => {
+                info!("connected to peer {}", address);
+                backoff = INITIAL_BACKOFF;
+                notify(&channels.events, ConnectionEvent::Connected(address, remote)).await;
+                let closed = serve(address, reader, writer, &mut channels, &mut pending, ping_interval).await;
+                if closed {
+                    return
+                }
+                channels.responders.lock().unwrap().clear();
+                warn!("connection to peer {} lost", address);
+            }
+            Err(e) => {
+                error!("could not connect to peer {}: {}", address, e);
+            }
+        }
+        notify(&channels.events, ConnectionEvent::Failed(address)).await;
+        // keep queueing messages while waiting for reconnection
+        let deadline = Instant::now() + backoff;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => break,
+                message = channels.queue.recv() => match message {
+                    Some(envelope) => push_pending(address, &mut pending, envelope),
+                    None => return,
+                }
+            }
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Writes queued messages and pings to the peer while the reader task passes replies to waiting
+/// requests and other messages to `events`, until the connection is lost or the peer stops
+/// answering pings. Returns true if `PeerConnection` has been dropped
+async fn serve(address: SocketAddr,
+               mut reader: MessageReader,
+               mut writer: MessageWriter,
+               channels: &mut Channels,
+               pending: &mut VecDeque<Envelope>,
+               ping_interval: Duration)
+    -> bool
+{
+    let events = channels.events.clone();
+    let responders = channels.responders.clone();
+    let ping = PendingPing::default();
+    let pong = ping.clone();
+    let mut reader_task = tokio::spawn(async move {
+        loop {
+            match read_envelope(&mut reader).await {
+                Ok(Some(Envelope { message: Message::Pong(nonce), .. })) => {
+                    let sent = {
+                        let mut pong = pong.lock().unwrap();
+                        match *pong {
+                            Some((expected, sent)) if expected == nonce => pong.take().map(|_| sent),
+                            _ => None,
+                        }
+                    };
+                    if let Some(sent) = sent {
+                        notify(&events, ConnectionEvent::Latency(address, sent.elapsed())).await;
+                    }
+                }
+                Ok(Some(envelope)) => {
+                    let responder = {
+                        let mut responders = responders.lock().unwrap();
+                        match responders.get(&envelope.id) {
+                            Some(Responder::Stream(replies)) => Some(Responder::Stream(replies.clone())),
+                            Some(Responder::Reply(_)) => responders.remove(&envelope.id),
+                            None => None,
+                        }
+                    };
+                    match responder {
+                        Some(Responder::Reply(responder)) => {
+                            let _ = responder.send(envelope);
+                        }
+                        // a slow stream must not hold up pongs and other messages of the peer
+                        Some(Responder::Stream(replies)) => {
+                            let id = envelope.id;
+                            if replies.try_send(envelope).is_err() {
+                                warn!("replies to request {} of peer {} are not taken, request dropped", id, address);
+                                responders.lock().unwrap().remove(&id);
+                            }
+                        }
+                        None => notify(&events, ConnectionEvent::Received(address, envelope.message)).await,
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!("error while reading data from peer {}: {}", address, e);
+                    if is_protocol_violation(&e) {
+                        notify(&events, ConnectionEvent::Violation(address)).await;
+                    }
+                    break
+                }
+            }
+        }
+    });
+    let mut keepalive = tokio::time::interval_at(Instant::now() + ping_interval, ping_interval);
+    let closed = loop {
+        if let Some(envelope) = pending.pop_front() {
+            match write_envelope(&mut writer, envelope.clone()).await {
+                Ok(()) => {}
+                // oversized message would never be sent, it is dropped
+                Err(LedgerError::FrameSizeError) => {}
+                Err(e) => {
+                    error!("error while sending data to peer {}: {}", address, e);
+                    pending.push_front(envelope);
+                    break false
+                }
+            }
+            continue
+        }
+        tokio::select! {
+            message = channels.queue.recv() => match message {
+                Some(envelope) => pending.push_back(envelope),
+                None => break true,
+            },
+            _ = keepalive.tick() => {
+                let mut ping = ping.lock().unwrap();
+                if ping.is_some() {
+                    warn!("peer {} does not answer pings", address);
+                    break false
+                }
+                let nonce = rand::random();
+                *ping = Some((nonce, Instant::now()));
+                pending.push_front(Envelope::from(Message::Ping(nonce)));
+            }
+            _ = &mut reader_task => break false,
+        }
+    };
+    reader_task.abort();
+    closed
+}
+
+fn push_pending(address: SocketAddr,
+                pending: &mut VecDeque<Envelope>,
+                envelope: Envelope)
+{
+    if pending.len() >= OUTBOUND_QUEUE_SIZE {
+        warn!("outbound queue of peer {} is full, oldest message dropped", address);
+        pending.pop_front();
+    }
+    pending.push_back(envelope);
+}
+
+async fn notify(events: &Option<mpsc::Sender<ConnectionEvent>>, event: ConnectionEvent) {
+    if let Some(events) = events {
+        let _ = events.send(event).await;
+    }
+}
@@ -0,0 +1,267 @@
+use std::io::{Error, ErrorKind};
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, error};
+use errors::LedgerError;
+use crate::{deserialize_data, serialize_data};
+
+/// Version of the p2p protocol spoken by this node
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest version of the p2p protocol this node is able to talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Feature bits of `Handshake::features`
+pub mod features {
+    /// Node announces itself and exchanges peer lists
+    pub const PEER_EXCHANGE: u64 = 1;
+    /// Node announces blocks and transactions by hash and serves them on request
+    pub const INVENTORY: u64 = 2;
+    /// Node relays new blocks as compact blocks rebuilt from the transaction pool
+    pub const COMPACT_BLOCKS: u64 = 4;
+    /// Node serves blocks by height with `GetBlocks`
+    pub const BLOCK_RANGES: u64 = 8;
+    /// Node reads and writes frames with lz4 compressed payload (block format)
+    pub const COMPRESSION: u64 = 16;
+}
+
+/// Handshake is accepted with this byte, any other value is a `DisconnectReason`
+const ACCEPTED: u8 = 1;
+/// Handshake is small, anything bigger is a protocol violation
+const MAX_HANDSHAKE_SIZE: u32 = 1024;
+
+/// First message of every connection, sent by both sides
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Handshake {
+    pub version: u32,
+    pub chain_id: String,
+    pub genesis_hash: Vec<u8>,
+    /// Public key of the node, empty for clients
+    pub node_id: Vec<u8>,
+    pub best_height: u64,
+    pub best_hash: Vec<u8>,
+    pub features: u64,
+}
+
+/// Reason code sent to a peer that failed the handshake before the connection is closed
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DisconnectReason {
+    /// Connection was closed without a reason code
+    Closed = 0,
+    IncompatibleVersion = 2,
+    WrongChain = 3,
+    WrongGenesis = 4,
+    SelfConnection = 5,
+    MalformedHandshake = 6,
+}
+
+impl DisconnectReason {
+    pub fn from_value(value: u8) -> Self {
+        match value {
+            2 => DisconnectReason::IncompatibleVersion,
+            3 => DisconnectReason::WrongChain,
+            4 => DisconnectReason::WrongGenesis,
+            5 => DisconnectReason::SelfConnection,
+            6 => DisconnectReason::MalformedHandshake,
+            _ => DisconnectReason::Closed,
+        }
+    }
+}
+
+impl From<DisconnectReason> for LedgerError {
+    fn from(reason: DisconnectReason) -> Self {
+        match reason {
+            DisconnectReason::WrongChain => LedgerError::WrongChainId,
+            _ => LedgerError::HandshakeError,
+        }
+    }
+}
+
+impl Handshake {
+
+    /// Handshake of a client, it has neither node id nor blocks
+    pub fn client(chain_id: &str, genesis_hash: Vec<u8>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            chain_id: chain_id.to_string(),
+            genesis_hash: genesis_hash.clone(),
+            node_id: vec![],
+            best_height: 0,
+            best_hash: genesis_hash,
+            features: 0,
+        }
+    }
+
+    /// Checks handshake of the remote side against ours
+    pub fn validate(&self, remote: &Handshake) -> Result<(), DisconnectReason> {
+        if remote.version < MIN_PROTOCOL_VERSION {
+            return Err(DisconnectReason::IncompatibleVersion)
+        }
+        if remote.chain_id != self.chain_id {
+            return Err(DisconnectReason::WrongChain)
+        }
+        if remote.genesis_hash != self.genesis_hash {
+            return Err(DisconnectReason::WrongGenesis)
+        }
+        if !self.node_id.is_empty() && remote.node_id == self.node_id {
+            return Err(DisconnectReason::SelfConnection)
+        }
+        Ok(())
+    }
+
+    /// Features supported by both sides
+    pub fn common_features(&self, remote: &Handshake) -> u64 {
+        self.features & remote.features
+    }
+}
+
+/// Connection initiator: 4 bytes - len of handshake, then handshake.
+/// Responder replies with 1 byte: 1 - accepted (followed by its own handshake, framed the same way)
+/// or `DisconnectReason`. Initiator validates the answer and replies with 1 byte the same way
+pub(crate) async fn initiate<R, W>(reader: &mut R, writer: &mut W, local: &Handshake) -> Result<Handshake, Error>
+    where R: AsyncRead + Unpin,
+          W: AsyncWrite + Unpin
+{
+    write_handshake(writer, local).await?;
+    let reason = read_reason(reader).await?;
+    if reason != ACCEPTED {
+        let reason = DisconnectReason::from_value(reason);
+        error!("handshake rejected by peer: {}", reason);
+        return Err(Error::new(ErrorKind::ConnectionRefused, reason.to_string()))
+    }
+    let remote = match read_handshake(reader).await {
+        Ok(remote) => remote,
+        Err(reason) => {
+            disconnect(writer, reason).await;
+            return Err(Error::new(ErrorKind::InvalidData, reason.to_string()))
+        }
+    };
+    if let Err(reason) = local.validate(&remote) {
+        disconnect(writer, reason).await;
+        return Err(Error::new(ErrorKind::ConnectionRefused, reason.to_string()))
+    }
+    writer.write_all(&[ACCEPTED]).await?;
+    debug!("handshake with peer done, best height: {}", remote.best_height);
+    Ok(remote)
+}
+
+/// Responder side of `initiate`
+pub async fn accept<R, W>(reader: &mut R, writer: &mut W, local: &Handshake) -> Result<Handshake, LedgerError>
+    where R: AsyncRead + Unpin,
+          W: AsyncWrite + Unpin
+{
+    let remote = match read_handshake(reader).await {
+        Ok(remote) => remote,
+        Err(reason) => {
+            disconnect(writer, reason).await;
+            return Err(reason.into())
+        }
+    };
+    if let Err(reason) = local.validate(&remote) {
+        disconnect(writer, reason).await;
+        return Err(reason.into())
+    }
+    writer.write_all(&[ACCEPTED]).await.map_err(|_| LedgerError::NetworkError)?;
+    write_handshake(writer, local).await.map_err(|_| LedgerError::NetworkError)?;
+    let reason = read_reason(reader).await.map_err(|_| LedgerError::NetworkError)?;
+    if reason != ACCEPTED {
+        let reason = DisconnectReason::from_value(reason);
+        error!("handshake rejected by peer: {}", reason);
+        return Err(reason.into())
+    }
+    debug!("handshake with peer done, best height: {}", remote.best_height);
+    Ok(remote)
+}
+
+async fn write_handshake<W: AsyncWrite + Unpin>(writer: &mut W, handshake: &Handshake) -> Result<(), Error> {
+    let data = serialize_data(handshake);
+    writer.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    writer.write_all(&data).await
+}
+
+async fn read_handshake<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Handshake, DisconnectReason> {
+    let mut len_buf = [0u8; 4];
+    if reader.read_exact(&mut len_buf).await.is_err() {
+        return Err(DisconnectReason::Closed)
+    }
+    let len = u32::from_be_bytes(len_buf);
+    if len == 0 || len > MAX_HANDSHAKE_SIZE {
+        error!("invalid handshake length: {}", len);
+        return Err(DisconnectReason::MalformedHandshake)
+    }
+    let mut data_buf = vec![0; len as usize];
+    if reader.read_exact(&mut data_buf).await.is_err() {
+        return Err(DisconnectReason::Closed)
+    }
+    deserialize_data(&data_buf).map_err(|_| DisconnectReason::MalformedHandshake)
+}
+
+/// Connection closed by the peer is `DisconnectReason::Closed`
+async fn read_reason<R: AsyncRead + Unpin>(reader: &mut R) -> Result<u8, Error> {
+    let mut buf = [0u8];
+    match reader.read_exact(&mut buf).await {
+        Ok(_) => Ok(buf[0]),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(DisconnectReason::Closed as u8),
+        Err(e) => Err(e),
+    }
+}
+
+async fn disconnect<W: AsyncWrite + Unpin>(writer: &mut W, reason: DisconnectReason) {
+    error!("peer failed the handshake: {}", reason);
+    let _ = writer.write_all(&[reason as u8]).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handshake::{DisconnectReason, Handshake, PROTOCOL_VERSION};
+
+    #[test]
+    fn same_chain_handshake_accepted() {
+        let local = node_handshake(1);
+        assert_eq!(local.validate(&node_handshake(2)), Ok(()));
+        assert_eq!(local.validate(&Handshake::client("test", vec![1; 32])), Ok(()));
+        let mut newer = node_handshake(2);
+        newer.version = PROTOCOL_VERSION + 1;
+        assert_eq!(local.validate(&newer), Ok(()));
+    }
+
+    #[test]
+    fn invalid_handshake_rejected() {
+        let local = node_handshake(1);
+        let mut remote = node_handshake(2);
+        remote.version = 0;
+        assert_eq!(local.validate(&remote), Err(DisconnectReason::IncompatibleVersion));
+        let mut remote = node_handshake(2);
+        remote.chain_id = "other".to_string();
+        assert_eq!(local.validate(&remote), Err(DisconnectReason::WrongChain));
+        let mut remote = node_handshake(2);
+        remote.genesis_hash = vec![2; 32];
+        assert_eq!(local.validate(&remote), Err(DisconnectReason::WrongGenesis));
+        assert_eq!(local.validate(&node_handshake(1)), Err(DisconnectReason::SelfConnection));
+    }
+
+    #[test]
+    fn reason_codes_round_trip() {
+        for reason in [
+            DisconnectReason::IncompatibleVersion,
+            DisconnectReason::WrongChain,
+            DisconnectReason::WrongGenesis,
+            DisconnectReason::SelfConnection,
+            DisconnectReason::MalformedHandshake,
+        ] {
+            assert_eq!(DisconnectReason::from_value(reason as u8), reason);
+        }
+        assert_eq!(DisconnectReason::from_value(1), DisconnectReason::Closed);
+    }
+
+    fn node_handshake(node: u8) -> Handshake {
+        Handshake {
+            node_id: vec![node; 32],
+            best_height: 5,
+            best_hash: vec![5; 32],
+            features: crate::handshake::features::PEER_EXCHANGE,
+            ..Handshake::client("test", vec![1; 32])
+        }
+    }
+}
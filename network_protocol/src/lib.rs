@@ -1,113 +1,38 @@
-#![feature(let_chains)]
 #![feature(io_error_more)]
 
 pub mod p2p;
+pub mod codec;
+pub mod message;
+pub mod handshake;
+pub mod connection;
+pub mod transport;
 pub mod client2node;
+pub mod compact;
+pub mod blocks;
 
-use std::collections::HashMap;
-use std::fmt::{Display, Formatter};
-use std::io::ErrorKind;
 use bincode::{DefaultOptions, Options};
-use tokio::io;
-use tokio::net::TcpStream;
 use tracing::error;
 use serde::{Deserialize, Serialize};
 use errors::LedgerError;
 use errors::LedgerError::DeserializationError;
-use state::{Block, Transaction};
-
-const DATA_LENGTH: [u8; 4] = [0, 0, 0, 0];
-
-pub(crate) async fn read_exact_async(s: &TcpStream, buf: &mut [u8]) -> io::Result<()> {
-    let mut red = 0;
-    while red < buf.len() {
-        s.readable().await?;
-        match s.try_read(&mut buf[red..]) {
-            Ok(0) => break,
-            Ok(n) => {
-                red += n;
-            }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => { continue; }
-            Err(e) => return Err(e),
-        }
-    }
-    Ok(())
-}
 
-pub(crate) async fn write_all_async(stream: &TcpStream, buf: &[u8]) -> io::Result<()> {
-    let mut written = 0;
-    while written < buf.len() {
-        stream.writable().await?;
-        match stream.try_write(&buf[written..]) {
-            Ok(0) => break,
-            Ok(n) => {
-                written += n;
-            }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => { continue; }
-            Err(e) => return Err(e),
-        }
-    }
-    Ok(())
-}
+pub use message::{Envelope, Message, RequestId};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[repr(u8)]
-pub enum Data {
-    Block(Block) = 1,
-    Transaction(Transaction) = 2,
-    Peer(String) = 3,
-    Peers(HashMap<String, String>) = 4,
-    Blockchain(Vec<Block>) = 5,
-    NodeResponse(HashMap<String, String>) = 6,
+/// Object announced to peers by its hash: block hash or transaction id
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum InventoryItem {
+    Block(Vec<u8>),
+    Transaction(Vec<u8>),
 }
 
-impl Display for Data {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Data::Block(ref b) => {
-                write!(f, "data (block) : {}", b)
-            }
-            Data::Transaction(ref t) => {
-                write!(f, "data (transaction) : {}", t)
-            }
-            Data::Peer(ref p) => {
-                write!(f, "data (node) : {}", p)
-            }
-            Data::Peers(ref p) => {
-                write!(f, "data (peers) : {}",
-                       p.iter()
-                           .map(|p| p.0.clone() + " " + p.1.as_str())
-                           .reduce(|acc, s| acc + ", " + s.as_str())
-                           .unwrap())
-            }
-            Data::Blockchain(ref b) => {
-                write!(f, "data (blockchain) : {}",
-                       b.iter()
-                           .map(Block::to_string)
-                           .reduce(|acc, s| acc + ", " + s.as_str())
-                           .unwrap())
-            }
-            Data::NodeResponse(ref hashmap) => {
-                // write!(f, "data (node response to client) : {}",
-                //        hashmap.iter()
-                //            .reduce(|acc, (k,v)|  acc + "\nkey: " + k + "\nvalue: " + v)
-                //            .unwrap())
-                todo!()
-            }
-        }
-    }
-}
-
-impl Data {
+impl InventoryItem {
 
-    pub fn data_type(&self) -> u8 {
-        match self {
-            Data::Block(_) => 1,
-            Data::Transaction(_) => 2,
-            Data::Peer(_) => 3,
-            Data::Peers(_) => 4,
-            Data::Blockchain(_) => 5,
-            Data::NodeResponse(_) => 6
+    /// Inventory item of a block or a transaction
+    pub fn of(message: &Message) -> Option<Self> {
+        match message {
+            Message::Block(block) => Some(InventoryItem::Block(block.hash.clone())),
+            Message::Transaction(transaction) => Some(InventoryItem::Transaction(transaction.id())),
+            _ => None,
         }
     }
 }
@@ -118,12 +43,14 @@ pub fn serialize_data<DATA: serde::ser::Serialize>(data: DATA) -> Vec<u8> {
         .serialize(&data).unwrap()
 }
 
+/// Lengths of collections in `bytes` are trusted up to the size of `bytes` only
 pub fn deserialize_data<'a, DATA: serde::de::Deserialize<'a>>(bytes:  &'a [u8])
     -> Result<DATA, LedgerError>
 {
     let data = DefaultOptions::new()
         .with_varint_encoding()
-        .deserialize::<DATA>(&bytes[..]);
+        .with_limit(bytes.len() as u64)
+        .deserialize::<DATA>(bytes);
     if let Ok(data) = data {
         Ok(data)
     } else {
@@ -136,17 +63,242 @@ pub fn deserialize_data<'a, DATA: serde::de::Deserialize<'a>>(bytes:  &'a [u8])
 #[cfg(test)]
 mod tests {
 
-    use tokio::net::{TcpListener, TcpStream};
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, watch};
+    use errors::LedgerError;
     use state::{Block, Command, Transaction};
-    use crate::p2p::send_data;
-    use crate::p2p::SendEvent::SendBlock;
-    use crate::serialize_data;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+    use crate::{Envelope, Message};
+    use crate::blocks::BlockPage;
+    use crate::codec::{MessageCodec, WIRE_VERSION};
+    use crate::connection::{accept, connect, ConnectionEvent, PeerConnection, PING_INTERVAL};
+    use crate::handshake::Handshake;
+    use crate::transport::Transport;
+    use crate::p2p::{read_envelope, read_message, write_envelope, write_message, MAX_BLOCK_SIZE, MAX_PEER_SIZE};
+    use crate::{deserialize_data, serialize_data};
 
     #[tokio::test]
     async fn transfer_block() {
         let block = generate_block();
-        let mut sender = TcpStream::connect("127.0.0.1:1234").await.unwrap();
-        let _ = send_data(&mut sender, serialize_data::<&Block>(&block).as_slice(), SendBlock).await;
+        let (_, _, mut writer) = connect(utils::socket_addr("1234"), &Transport::Plain, &handshake("test")).await.unwrap();
+        let _ = write_message(&mut writer, Message::Block(block)).await;
+    }
+
+    #[tokio::test]
+    async fn connection_carries_messages_in_both_directions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (_, mut reader, mut writer) = accept(socket, &Transport::Plain, &handshake("test")).await.unwrap();
+            let mut blocks = Vec::new();
+            while let Some(Message::Block(block)) = read_message(&mut reader).await.unwrap() {
+                blocks.push(block.id);
+                if blocks.len() == 3 {
+                    break
+                }
+            }
+            write_message(&mut writer, Message::Peers(peers())).await.unwrap();
+            blocks
+        });
+        let (_, mut reader, mut writer) = connect(addr, &Transport::Plain, &handshake("test")).await.unwrap();
+        for _ in 0..3 {
+            write_message(&mut writer, Message::Block(generate_block())).await.unwrap();
+        }
+        assert!(matches!(read_message(&mut reader).await, Ok(Some(Message::Peers(p))) if p == peers()));
+        assert_eq!(receiver.await.unwrap(), vec![1, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn peers_of_different_chains_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            accept(socket, &Transport::Plain, &handshake("production")).await.map(|_| ())
+        });
+        assert!(connect(addr, &Transport::Plain, &handshake("test")).await.is_err());
+        assert_eq!(receiver.await.unwrap().err(), Some(LedgerError::WrongChainId));
+    }
+
+    #[tokio::test]
+    async fn peers_with_different_genesis_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            accept(socket, &Transport::Plain, &handshake("test")).await.map(|_| ())
+        });
+        let other_genesis = Handshake::client("test", vec![2; 32]);
+        assert!(connect(addr, &Transport::Plain, &other_genesis).await.is_err());
+        assert_eq!(receiver.await.unwrap().err(), Some(LedgerError::HandshakeError));
+    }
+
+    #[tokio::test]
+    async fn peer_connection_reconnects_and_keeps_queue() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_handshake_tx, handshake_rx) = watch::channel(handshake("test"));
+        let (events_tx, mut events_rx) = mpsc::channel(10);
+        let connection = PeerConnection::open(addr, Transport::Plain, handshake_rx, Some(events_tx), PING_INTERVAL);
+        connection.send(Message::Block(generate_block())).unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let (_, mut reader, writer) = accept(socket, &Transport::Plain, &handshake("test")).await.unwrap();
+        assert!(matches!(events_rx.recv().await, Some(ConnectionEvent::Connected(a, _)) if a == addr));
+        assert!(matches!(read_message(&mut reader).await, Ok(Some(Message::Block(_)))));
+        // peer goes away, message is kept until the connection is re-established
+        drop((reader, writer));
+        assert!(matches!(events_rx.recv().await, Some(ConnectionEvent::Failed(a)) if a == addr));
+        connection.send(Message::Block(generate_block())).unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let (_, mut reader, mut writer) = accept(socket, &Transport::Plain, &handshake("test")).await.unwrap();
+        assert!(matches!(read_message(&mut reader).await, Ok(Some(Message::Block(_)))));
+        write_message(&mut writer, Message::Peers(peers())).await.unwrap();
+        loop {
+            match events_rx.recv().await {
+                Some(ConnectionEvent::Received(a, Message::Peers(p))) => {
+                    assert_eq!((a, p), (addr, peers()));
+                    break
+                }
+                Some(ConnectionEvent::Connected(..)) => continue,
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_matched_to_requests_in_flight() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_handshake_tx, handshake_rx) = watch::channel(handshake("test"));
+        let connection = PeerConnection::open(addr, Transport::Plain, handshake_rx, None, PING_INTERVAL);
+        let peer = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (_, mut reader, mut writer) = accept(socket, &Transport::Plain, &handshake("test")).await.unwrap();
+            let first = read_envelope(&mut reader).await.unwrap().unwrap();
+            let second = read_envelope(&mut reader).await.unwrap().unwrap();
+            // replies come in reverse order, unsolicited messages in between are not replies
+            write_message(&mut writer, Message::Peers(peers())).await.unwrap();
+            let error = Message::Error(LedgerError::BadSignature.code());
+            write_envelope(&mut writer, Envelope { id: second.id, message: error }).await.unwrap();
+            write_envelope(&mut writer, Envelope { id: first.id, message: Message::Ack(()) }).await.unwrap();
+            (reader, writer)
+        });
+        let (first, second) = tokio::join!(
+            connection.request(Message::Block(generate_block())),
+            async {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                connection.request(Message::GetBans(())).await
+            });
+        assert!(matches!(first, Ok(Message::Ack(()))));
+        assert_eq!(second.err(), Some(LedgerError::BadSignature));
+        let _ = peer.await;
+    }
+
+    #[tokio::test]
+    async fn streamed_replies_delivered_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_handshake_tx, handshake_rx) = watch::channel(handshake("test"));
+        let (events_tx, mut events_rx) = mpsc::channel(10);
+        let connection = PeerConnection::open(addr, Transport::Plain, handshake_rx, Some(events_tx), PING_INTERVAL);
+        let mut replies = connection.request_stream(Message::GetBans(())).unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let (_, mut reader, mut writer) = accept(socket, &Transport::Plain, &handshake("test")).await.unwrap();
+        let request = read_envelope(&mut reader).await.unwrap().unwrap();
+        for id in 1..=3 {
+            let page = BlockPage { blocks: vec![Block { id, ..Default::default() }], next: Some(id + 1), more: id < 3 };
+            write_envelope(&mut writer, Envelope { id: request.id, message: Message::Blocks(page) }).await.unwrap();
+            write_message(&mut writer, Message::Peers(peers())).await.unwrap();
+        }
+        for id in 1..=3 {
+            let Ok(Message::Blocks(page)) = replies.next().await else { panic!("page {} is missing", id) };
+            assert_eq!((page.blocks[0].id, page.more), (id, id < 3));
+        }
+        // other messages are not replies
+        assert!(matches!(events_rx.recv().await, Some(ConnectionEvent::Connected(..))));
+        assert!(matches!(events_rx.recv().await, Some(ConnectionEvent::Received(_, Message::Peers(_)))));
+    }
+
+    #[tokio::test]
+    async fn stream_not_taken_does_not_block_reading() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_handshake_tx, handshake_rx) = watch::channel(handshake("test"));
+        let (events_tx, mut events_rx) = mpsc::channel(10);
+        let connection = PeerConnection::open(addr, Transport::Plain, handshake_rx, Some(events_tx), PING_INTERVAL);
+        let mut replies = connection.request_stream(Message::GetBans(())).unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let (_, mut reader, mut writer) = accept(socket, &Transport::Plain, &handshake("test")).await.unwrap();
+        let request = read_envelope(&mut reader).await.unwrap().unwrap();
+        for id in 1..=40 {
+            let page = BlockPage { blocks: vec![Block { id, ..Default::default() }], next: Some(id + 1), more: true };
+            write_envelope(&mut writer, Envelope { id: request.id, message: Message::Blocks(page) }).await.unwrap();
+        }
+        write_message(&mut writer, Message::Peers(peers())).await.unwrap();
+        assert!(matches!(events_rx.recv().await, Some(ConnectionEvent::Connected(..))));
+        loop {
+            match events_rx.recv().await {
+                Some(ConnectionEvent::Received(_, Message::Peers(_))) => break,
+                Some(ConnectionEvent::Received(_, Message::Blocks(_))) => continue,
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
+        // replies buffered before the stream failed are delivered, then the stream ends
+        let mut delivered = 0;
+        while let Ok(Message::Blocks(_)) = replies.next().await {
+            delivered += 1;
+        }
+        assert!(delivered < 40);
+    }
+
+    #[tokio::test]
+    async fn unanswered_pings_close_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_handshake_tx, handshake_rx) = watch::channel(handshake("test"));
+        let (events_tx, mut events_rx) = mpsc::channel(10);
+        let ping_interval = std::time::Duration::from_millis(200);
+        let _connection = PeerConnection::open(addr, Transport::Plain, handshake_rx, Some(events_tx), ping_interval);
+        let (socket, _) = listener.accept().await.unwrap();
+        let (_, mut reader, mut writer) = accept(socket, &Transport::Plain, &handshake("test")).await.unwrap();
+        assert!(matches!(events_rx.recv().await, Some(ConnectionEvent::Connected(..))));
+        let Ok(Some(Message::Ping(nonce))) = read_message(&mut reader).await else { panic!("peer is not pinged") };
+        write_message(&mut writer, Message::Pong(nonce)).await.unwrap();
+        assert!(matches!(events_rx.recv().await, Some(ConnectionEvent::Latency(a, _)) if a == addr));
+        // the next ping is left unanswered
+        assert!(matches!(read_message(&mut reader).await, Ok(Some(Message::Ping(_)))));
+        assert!(matches!(events_rx.recv().await, Some(ConnectionEvent::Failed(a)) if a == addr));
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_rejected() {
+        let mut frame = vec![WIRE_VERSION, Message::Block(Block::default()).tag()];
+        frame.extend_from_slice(&0u32.to_be_bytes());
+        frame.extend_from_slice(&(MAX_BLOCK_SIZE + 1).to_be_bytes());
+        let mut reader = FramedRead::new(frame.as_slice(), MessageCodec::default());
+        assert_eq!(read_message(&mut reader).await.err(), Some(LedgerError::FrameSizeError));
+        let mut writer = FramedWrite::new(Vec::new(), MessageCodec::default());
+        let oversized = "1".repeat(MAX_PEER_SIZE as usize + 1);
+        assert!(write_message(&mut writer, Message::Peer(oversized)).await.is_err());
+        assert!(writer.get_ref().is_empty());
+    }
+
+    #[test]
+    fn deserialization_limited_by_input_size() {
+        // varint length of a 4 GiB vector followed by nothing
+        let bytes = serialize_data(u32::MAX as u64);
+        assert_eq!(deserialize_data::<Vec<u8>>(&bytes).err(), Some(LedgerError::DeserializationError));
+        let bytes = serialize_data(peers());
+        assert_eq!(deserialize_data::<std::collections::HashMap<String, String>>(&bytes).unwrap(), peers());
+    }
+
+    fn handshake(chain_id: &str) -> Handshake {
+        Handshake::client(chain_id, vec![1; 32])
+    }
+
+    fn peers() -> std::collections::HashMap<String, String> {
+        [("127.0.0.1:1235".to_string(), "10".to_string())].into_iter().collect()
     }
 
     fn generate_block() -> Block {
@@ -154,11 +306,16 @@ mod tests {
             id: 1,
             timestamp: 0,
             transactions: vec![Transaction {
+                chain_id: "test".to_string(),
+                signer: vec![],
+                nonce: 0,
                 fee: 555,
                 commands: vec![Command::CreateAccount {
                     public_key: "12345".to_string(),
                 }],
+                signature: vec![],
             }],
+            producer: vec![],
             signature: vec![1, 2, 3, 4, 5],
             hash: vec![],
             previous_block_hash: None,
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+use errors::LedgerError;
+use state::{Block, Transaction};
+use crate::{deserialize_data, serialize_data, InventoryItem};
+use crate::blocks::{BlockPage, BlockRange};
+use crate::compact::{BlockTransactions, BlockTransactionsRequest, CompactBlock};
+use crate::p2p::{
+    MAX_BLOCK_PAGE_SIZE, MAX_BLOCK_RANGE_SIZE, MAX_BLOCK_SIZE, MAX_COMPACT_BLOCK_SIZE, MAX_INVENTORY_SIZE,
+    MAX_NODE_RESPONSE_SIZE, MAX_PEERS_SIZE, MAX_PEER_SIZE, MAX_REQUEST_SIZE, MAX_TRANSACTION_SIZE
+};
+
+/// Declares `Message` with the wire tag and the payload size limit of every message,
+/// so that adding a message is a change of one line
+macro_rules! messages {
+    ($($(#[$doc:meta])* $name:ident($payload:ty) = $tag:literal, $max_size:expr;)*) => {
+        #[derive(Debug, Clone, Serialize, Deserialize)]
+        pub enum Message {
+            $($(#[$doc])* $name($payload),)*
+        }
+
+        impl Message {
+
+            /// Tag of the message on the wire
+            pub fn tag(&self) -> u8 {
+                match self {
+                    $(Message::$name(_) => $tag,)*
+                }
+            }
+
+            /// Maximum size of payload of the message with `tag`, `None` for unknown tags
+            pub fn max_size(tag: u8) -> Option<u32> {
+                match tag {
+                    $($tag => Some($max_size),)*
+                    _ => None,
+                }
+            }
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Message::$name(_) => stringify!($name),)*
+                }
+            }
+
+            pub(crate) fn encode_payload(&self) -> Vec<u8> {
+                match self {
+                    $(Message::$name(payload) => serialize_data(payload),)*
+                }
+            }
+
+            pub(crate) fn decode_payload(tag: u8, bytes: &[u8]) -> Result<Self, LedgerError> {
+                match tag {
+                    $($tag => Ok(Message::$name(deserialize_data(bytes)?)),)*
+                    _ => Err(LedgerError::WrongCommandError),
+                }
+            }
+        }
+    };
+}
+
+messages! {
+    Block(Block) = 1, MAX_BLOCK_SIZE;
+    Transaction(Transaction) = 2, MAX_TRANSACTION_SIZE;
+    /// Address of a node announcing itself
+    Peer(String) = 3, MAX_PEER_SIZE;
+    /// Peer table: address -> unix time the peer was last seen
+    Peers(HashMap<String, String>) = 4, MAX_PEERS_SIZE;
+    NodeResponse(HashMap<String, String>) = 6, MAX_NODE_RESPONSE_SIZE;
+    /// Hashes of objects the node has
+    Inventory(Vec<InventoryItem>) = 7, MAX_INVENTORY_SIZE;
+    /// Request of announced objects the node is missing
+    GetData(Vec<InventoryItem>) = 8, MAX_INVENTORY_SIZE;
+    /// Block header and short ids of its transactions
+    CompactBlock(CompactBlock) = 9, MAX_COMPACT_BLOCK_SIZE;
+    /// Request of transactions of a compact block missing in the pool
+    GetBlockTransactions(BlockTransactionsRequest) = 10, MAX_INVENTORY_SIZE;
+    BlockTransactions(BlockTransactions) = 11, MAX_BLOCK_SIZE;
+    /// Client request of peers banned by the node, answered with `NodeResponse`
+    GetBans(()) = 13, MAX_REQUEST_SIZE;
+    /// Reply to a request that has succeeded without a payload
+    Ack(()) = 14, MAX_REQUEST_SIZE;
+    /// Reply to a request that has failed: code of the `LedgerError`, see `LedgerError::code`
+    Error(u16) = 15, MAX_REQUEST_SIZE;
+    /// Keepalive with a random nonce, answered with `Pong` of the same nonce
+    Ping(u64) = 16, MAX_REQUEST_SIZE;
+    Pong(u64) = 17, MAX_REQUEST_SIZE;
+    /// Request of blocks by height, answered with a sequence of `Blocks` pages
+    GetBlocks(BlockRange) = 18, MAX_BLOCK_RANGE_SIZE;
+    Blocks(BlockPage) = 19, MAX_BLOCK_PAGE_SIZE;
+}
+
+/// Id of a request, replies to it carry the same id. Messages nobody waits a reply to have id 0
+pub type RequestId = u32;
+
+/// Message with the id of the request it is or replies to
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub id: RequestId,
+    pub message: Message,
+}
+
+impl From<Message> for Envelope {
+    fn from(message: Message) -> Self {
+        Self { id: 0, message }
+    }
+}
+
+impl Envelope {
+
+    /// Payload of a reply, an `Error` reply is turned into its `LedgerError`
+    pub fn into_reply(self) -> Result<Message, LedgerError> {
+        match self.message {
+            Message::Error(code) => Err(LedgerError::from_code(code)),
+            message => Ok(message),
+        }
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Message::Block(ref b) => {
+                write!(f, "data (block) : {}", b)
+            }
+            Message::Transaction(ref t) => {
+                write!(f, "data (transaction) : {}", t)
+            }
+            Message::Peer(ref p) => {
+                write!(f, "data (node) : {}", p)
+            }
+            Message::Peers(ref p) => {
+                write!(f, "data (peers) : {}",
+                       p.iter()
+                           .map(|p| p.0.clone() + " " + p.1.as_str())
+                           .reduce(|acc, s| acc + ", " + s.as_str())
+                           .unwrap_or_default())
+            }
+            Message::Blocks(ref page) => {
+                write!(f, "data (blocks) : {}, next: {:?}",
+                       page.blocks.iter()
+                           .map(Block::to_string)
+                           .reduce(|acc, s| acc + ", " + s.as_str())
+                           .unwrap_or_default(),
+                       page.next)
+            }
+            Message::NodeResponse(ref hashmap) => {
+                write!(f, "data (node response to client) : {}",
+                       hashmap.iter()
+                           .map(|(k, v)| k.clone() + " " + v.as_str())
+                           .reduce(|acc, s| acc + ", " + s.as_str())
+                           .unwrap_or_default())
+            }
+            Message::Inventory(ref items) | Message::GetData(ref items) => {
+                write!(f, "data ({}) : {} items", self.name(), items.len())
+            }
+            Message::CompactBlock(ref c) => {
+                write!(f, "data (compact block) : {}, {} transactions", c.header, c.short_ids.len())
+            }
+            Message::Error(code) => {
+                write!(f, "data (error) : {}", LedgerError::from_code(*code))
+            }
+            _ => write!(f, "data ({})", self.name()),
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use serde::{Deserialize, Serialize};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tracing::{debug, error};
+use errors::LedgerError;
+use crate::{deserialize_data, serialize_data};
+
+/// Noise handshake pattern and primitives of encrypted connections
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+/// Noise static keys are generated per connection and signed by ed25519 node keys with this prefix
+const STATIC_KEY_CONTEXT: &[u8] = b"ledger-noise-static-key:";
+const MAX_MESSAGE_SIZE: usize = 65535;
+const TAG_SIZE: usize = 16;
+/// Plaintext is split into chunks of this size, every chunk is a Noise message
+const MAX_CHUNK_SIZE: usize = MAX_MESSAGE_SIZE - TAG_SIZE;
+
+pub type FrameReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type FrameWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// How bytes of a connection go over TCP, both sides must use the same transport
+#[derive(Debug, Clone, Default)]
+pub enum Transport {
+    #[default]
+    Plain,
+    /// Noise XX handshake authenticated by ed25519 keys, then every message is encrypted
+    Noise(Arc<NoiseConfig>),
+}
+
+/// ed25519 identity of the local side and keys of peers it talks to
+pub struct NoiseConfig {
+    public_key: Vec<u8>,
+    private_key: Vec<u8>,
+    /// Pinned ed25519 keys of peers, any peer is accepted if empty
+    trusted_keys: HashSet<Vec<u8>>,
+}
+
+impl Debug for NoiseConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseConfig")
+            .field("public_key", &self.public_key)
+            .field("trusted_keys", &self.trusted_keys.len())
+            .finish()
+    }
+}
+
+impl NoiseConfig {
+
+    pub fn new(public_key: Vec<u8>, private_key: Vec<u8>, trusted_keys: Vec<Vec<u8>>) -> Self {
+        Self { public_key, private_key, trusted_keys: trusted_keys.into_iter().collect() }
+    }
+
+    fn is_trusted(&self, public_key: &[u8]) -> bool {
+        self.trusted_keys.is_empty() || self.trusted_keys.contains(public_key)
+    }
+}
+
+/// Proof of ownership of the Noise static key, payload of the handshake
+#[derive(Debug, Serialize, Deserialize)]
+struct Identity {
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl Transport {
+
+    /// Performs the Noise handshake on `socket` if needed. Returns authenticated ed25519 key
+    /// of the remote side (`None` for plain transport) and halves carrying plaintext frames
+    pub(crate) async fn upgrade(&self, mut socket: TcpStream, initiator: bool)
+        -> Result<(Option<Vec<u8>>, FrameReader, FrameWriter), LedgerError>
+    {
+        match self {
+            Transport::Plain => {
+                let (reader, writer) = socket.into_split();
+                Ok((None, Box::new(reader), Box::new(writer)))
+            }
+            Transport::Noise(config) => {
+                let (remote_key, state) = handshake(&mut socket, config, initiator).await?;
+                let (reader, writer) = encrypt(socket, Arc::new(state));
+                Ok((Some(remote_key), reader, writer))
+            }
+        }
+    }
+
+    /// Secures a connection of the client API, `initiator` is the client. No protocol handshake follows
+    pub async fn secure(&self, socket: TcpStream, initiator: bool) -> Result<SecureStream, LedgerError> {
+        let (_, reader, writer) = self.upgrade(socket, initiator).await?;
+        Ok(SecureStream { reader, writer })
+    }
+}
+
+/// Plaintext halves of a connection as one stream
+pub struct SecureStream {
+    reader: FrameReader,
+    writer: FrameWriter,
+}
+
+impl AsyncRead for SecureStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SecureStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+/// XX pattern: -> e; <- e, ee, s, es + identity; -> s, se + identity
+async fn handshake(socket: &mut TcpStream, config: &NoiseConfig, initiator: bool)
+    -> Result<(Vec<u8>, StatelessTransportState), LedgerError>
+{
+    let builder = Builder::new(NOISE_PARAMS.parse().map_err(noise_error)?);
+    let keypair = builder.generate_keypair().map_err(noise_error)?;
+    let builder = builder.local_private_key(&keypair.private);
+    let mut noise = if initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    }.map_err(noise_error)?;
+    let signature = crypto::sign(&[STATIC_KEY_CONTEXT, &keypair.public].concat(), &config.private_key)
+        .ok_or(LedgerError::KeyError)?;
+    let identity = serialize_data(Identity { public_key: config.public_key.clone(), signature });
+    let remote_identity = if initiator {
+        write_handshake_message(socket, &mut noise, &[]).await?;
+        let remote_identity = read_handshake_message(socket, &mut noise).await?;
+        write_handshake_message(socket, &mut noise, &identity).await?;
+        remote_identity
+    } else {
+        read_handshake_message(socket, &mut noise).await?;
+        write_handshake_message(socket, &mut noise, &identity).await?;
+        read_handshake_message(socket, &mut noise).await?
+    };
+    let remote_static = noise.get_remote_static().ok_or(LedgerError::HandshakeError)?;
+    let remote_identity: Identity = deserialize_data(&remote_identity)?;
+    let signed = [STATIC_KEY_CONTEXT, remote_static].concat();
+    if !crypto::verify_signature(&signed, &remote_identity.signature, &remote_identity.public_key) {
+        error!("peer static key is not signed by its node key");
+        return Err(LedgerError::BadSignature)
+    }
+    if !config.is_trusted(&remote_identity.public_key) {
+        error!("peer key is not trusted: {}", hex(&remote_identity.public_key));
+        return Err(LedgerError::HandshakeError)
+    }
+    debug!("encrypted connection with {} established", hex(&remote_identity.public_key));
+    Ok((remote_identity.public_key, noise.into_stateless_transport_mode().map_err(noise_error)?))
+}
+
+async fn write_handshake_message(socket: &mut TcpStream, noise: &mut HandshakeState, payload: &[u8])
+    -> Result<(), LedgerError>
+{
+    let mut message = vec![0; MAX_MESSAGE_SIZE];
+    let len = noise.write_message(payload, &mut message).map_err(noise_error)?;
+    write_message(socket, &message[..len]).await.map_err(|_| LedgerError::NetworkError)
+}
+
+async fn read_handshake_message(socket: &mut TcpStream, noise: &mut HandshakeState)
+    -> Result<Vec<u8>, LedgerError>
+{
+    let message = read_message(socket).await.map_err(|_| LedgerError::NetworkError)?;
+    let mut payload = vec![0; MAX_MESSAGE_SIZE];
+    let len = noise.read_message(&message, &mut payload).map_err(noise_error)?;
+    payload.truncate(len);
+    Ok(payload)
+}
+
+/// Plaintext halves of an encrypted connection. Pump tasks encrypt what is written to the writer
+/// and decrypt what comes from the peer, they stop when both halves are dropped or the peer
+/// closes the connection
+fn encrypt(socket: TcpStream, state: Arc<StatelessTransportState>) -> (FrameReader, FrameWriter) {
+    let (mut socket_reader, mut socket_writer) = socket.into_split();
+    let (local, remote) = tokio::io::duplex(MAX_CHUNK_SIZE);
+    let (mut plain_reader, mut plain_writer) = tokio::io::split(remote);
+    let decrypt_state = state.clone();
+    let decrypt = tokio::spawn(async move {
+        let mut payload = vec![0; MAX_MESSAGE_SIZE];
+        for nonce in 0.. {
+            let Ok(message) = read_message(&mut socket_reader).await else { break };
+            let len = match decrypt_state.read_message(nonce, &message, &mut payload) {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("could not decrypt message from peer: {}", e);
+                    break
+                }
+            };
+            if plain_writer.write_all(&payload[..len]).await.is_err() {
+                break
+            }
+        }
+        // reader of the connection sees the end of stream
+        let _ = plain_writer.shutdown().await;
+    });
+    tokio::spawn(async move {
+        let mut chunk = vec![0; MAX_CHUNK_SIZE];
+        let mut message = vec![0; MAX_MESSAGE_SIZE];
+        for nonce in 0.. {
+            let len = match plain_reader.read(&mut chunk).await {
+                Ok(0) | Err(_) => break,
+                Ok(len) => len,
+            };
+            let Ok(len) = state.write_message(nonce, &chunk[..len], &mut message) else { break };
+            if write_message(&mut socket_writer, &message[..len]).await.is_err() {
+                break
+            }
+        }
+        let _ = socket_writer.shutdown().await;
+        decrypt.abort();
+    });
+    let (reader, writer) = tokio::io::split(local);
+    (Box::new(reader), Box::new(writer))
+}
+
+/// Noise message on the wire: 2 bytes - len of message, then message
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&(message.len() as u16).to_be_bytes()).await?;
+    writer.write_all(message).await
+}
+
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 2];
+    reader.read_exact(&mut len_buf).await?;
+    let mut message = vec![0; u16::from_be_bytes(len_buf) as usize];
+    reader.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+fn noise_error(e: snow::Error) -> LedgerError {
+    error!("noise handshake failed: {}", e);
+    LedgerError::HandshakeError
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use errors::LedgerError;
+    use state::Block;
+    use crate::Message;
+    use crate::connection::{accept, connect};
+    use crate::handshake::Handshake;
+    use crate::p2p::{read_message, write_message};
+    use crate::transport::{NoiseConfig, Transport, MAX_MESSAGE_SIZE};
+
+    #[tokio::test]
+    async fn encrypted_connection_carries_frames_bigger_than_noise_message() {
+        let (node_key, node_transport) = noise(vec![]);
+        let (client_key, client_transport) = noise(vec![node_key.clone()]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let block = Block { id: 7, signature: vec![1; MAX_MESSAGE_SIZE * 3], ..Default::default() };
+        let node = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (remote, mut reader, mut writer) = accept(socket, &node_transport, &handshake(vec![])).await.unwrap();
+            let data = read_message(&mut reader).await.unwrap();
+            write_message(&mut writer, Message::Block(block)).await.unwrap();
+            (remote, data)
+        });
+        let (remote, mut reader, mut writer) = connect(addr, &client_transport, &handshake(client_key.clone()))
+            .await.unwrap();
+        assert_eq!(remote.node_id, Vec::<u8>::new());
+        write_message(&mut writer, Message::Peer("127.0.0.1:1235".to_string())).await.unwrap();
+        assert!(matches!(read_message(&mut reader).await,
+            Ok(Some(Message::Block(b))) if b.id == 7 && b.signature.len() == MAX_MESSAGE_SIZE * 3));
+        let (remote, data) = node.await.unwrap();
+        assert_eq!(remote.node_id, client_key);
+        assert!(matches!(data, Some(Message::Peer(_))));
+    }
+
+    #[tokio::test]
+    async fn untrusted_peer_rejected() {
+        let (_, node_transport) = noise(vec![vec![7; 32]]);
+        let (client_key, client_transport) = noise(vec![]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let node = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            accept(socket, &node_transport, &handshake(vec![])).await.map(|_| ())
+        });
+        assert!(connect(addr, &client_transport, &handshake(client_key)).await.is_err());
+        assert_eq!(node.await.unwrap().err(), Some(LedgerError::HandshakeError));
+    }
+
+    #[tokio::test]
+    async fn node_id_must_match_transport_key() {
+        let (_, node_transport) = noise(vec![]);
+        let (_, client_transport) = noise(vec![]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let node = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            accept(socket, &node_transport, &handshake(vec![])).await.map(|_| ())
+        });
+        let _ = connect(addr, &client_transport, &handshake(vec![9; 32])).await;
+        assert_eq!(node.await.unwrap().err(), Some(LedgerError::HandshakeError));
+    }
+
+    #[tokio::test]
+    async fn plain_peer_cannot_talk_to_encrypted_one() {
+        let (_, node_transport) = noise(vec![]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let node = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            accept(socket, &node_transport, &handshake(vec![])).await.map(|_| ())
+        });
+        assert!(connect(addr, &Transport::Plain, &handshake(vec![])).await.is_err());
+        assert!(node.await.unwrap().is_err());
+    }
+
+    fn noise(trusted_keys: Vec<Vec<u8>>) -> (Vec<u8>, Transport) {
+        let (public_key, private_key) = crypto::generate_keypair();
+        let config = NoiseConfig::new(public_key.clone(), private_key, trusted_keys);
+        (public_key, Transport::Noise(Arc::new(config)))
+    }
+
+    fn handshake(node_id: Vec<u8>) -> Handshake {
+        Handshake { node_id, ..Handshake::client("test", vec![1; 32]) }
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use tracing::{error, info, warn};
+use errors::LedgerError;
+use network::Message;
+use state::chain_spec::ChainSpec;
+use crate::storage::Storage;
+
+/// Bans of the node are saved to this file inside of the data directory
+pub(crate) const BANS_FILE: &str = "bans.json";
+/// One point of a misbehaviour score is forgiven every interval, so only frequent misbehaviour gets a peer banned
+const SCORE_DECAY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Misbehaviour {
+    /// Block that is invalid regardless of the state of the chain, or rejected when added on top of its parent
+    InvalidBlock,
+    /// Transaction failing verification with the error
+    InvalidTransaction(LedgerError),
+    /// Undecodable or oversized frame, unknown command
+    ProtocolViolation,
+}
+
+impl Misbehaviour {
+    fn penalty(&self) -> u32 {
+        match self {
+            Misbehaviour::InvalidBlock => 50,
+            Misbehaviour::InvalidTransaction(_) => 20,
+            Misbehaviour::ProtocolViolation => 34,
+        }
+    }
+
+    /// Error replied to a request that misbehaved
+    pub fn error(&self) -> LedgerError {
+        match self {
+            Misbehaviour::InvalidBlock => LedgerError::BlockError,
+            Misbehaviour::InvalidTransaction(error) => error.clone(),
+            Misbehaviour::ProtocolViolation => LedgerError::WrongCommandError,
+        }
+    }
+}
+
+/// Misbehaviour of data received from a peer, if any
+pub(crate) fn inspect(chain_spec: &ChainSpec, data: &Message) -> Option<Misbehaviour> {
+    match data {
+        Message::Block(block) if !Storage::check_block(chain_spec, block) => {
+            Some(Misbehaviour::InvalidBlock)
+        }
+        Message::Transaction(transaction) => {
+            transaction.verify(&chain_spec.chain_id).err().map(Misbehaviour::InvalidTransaction)
+        }
+        Message::CompactBlock(compact) if compact.short_ids.len() > chain_spec.limits.max_transactions => {
+            Some(Misbehaviour::InvalidBlock)
+        }
+        _ => None,
+    }
+}
+
+/// Misbehaviour scores of peers by IP address. A peer is banned for `duration`
+/// once its score reaches `threshold`, bans are saved to the data directory
+#[derive(Debug)]
+pub(crate) struct BanManager {
+    /// IP address -> score and time it was last updated, scores decay over time
+    scores: HashMap<IpAddr, (u32, Instant)>,
+    /// IP address -> unix time the ban expires
+    bans: HashMap<IpAddr, i64>,
+    threshold: u32,
+    duration: Duration,
+    path: PathBuf,
+}
+
+impl BanManager {
+
+    pub fn new(threshold: u32, duration: Duration, data_dir: &Path) -> Self {
+        let mut ban_manager = Self {
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+            threshold,
+            duration,
+            path: data_dir.join(BANS_FILE),
+        };
+        ban_manager.bans = ban_manager.load();
+        let now = Utc::now().timestamp();
+        ban_manager.bans.retain(|_, until| *until > now);
+        if !ban_manager.bans.is_empty() {
+            info!("{} banned peers restored", ban_manager.bans.len());
+        }
+        ban_manager
+    }
+
+    /// Adds penalty of `misbehaviour` to the score of `ip`, returns true if `ip` is banned
+    pub fn misbehaved(&mut self, ip: IpAddr, misbehaviour: Misbehaviour) -> bool {
+        self.misbehaved_at(ip, misbehaviour, Instant::now())
+    }
+
+    /// Score of `ip` decays by a point per `SCORE_DECAY_INTERVAL` passed until `now`, then the penalty is added
+    fn misbehaved_at(&mut self, ip: IpAddr, misbehaviour: Misbehaviour, now: Instant) -> bool {
+        if self.is_banned(&ip) {
+            return true
+        }
+        let (score, updated) = self.scores.entry(ip).or_insert((0, now));
+        let forgiven = now.saturating_duration_since(*updated).as_secs() / SCORE_DECAY_INTERVAL.as_secs();
+        *score = score.saturating_sub(u32::try_from(forgiven).unwrap_or(u32::MAX)) + misbehaviour.penalty();
+        *updated = now;
+        warn!("peer {} misbehaved: {:?}, score: {}", ip, misbehaviour, score);
+        if *score < self.threshold {
+            return false
+        }
+        self.scores.remove(&ip);
+        let until = Utc::now().timestamp() + self.duration.as_secs() as i64;
+        self.bans.insert(ip, until);
+        warn!("peer {} banned for {} seconds", ip, self.duration.as_secs());
+        let _ = self.persist();
+        true
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        matches!(self.bans.get(ip), Some(until) if *until > Utc::now().timestamp())
+    }
+
+    /// Active bans: IP address -> unix time the ban expires
+    pub fn bans(&self) -> HashMap<String, String> {
+        let now = Utc::now().timestamp();
+        self.bans.iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(ip, until)| (ip.to_string(), until.to_string()))
+            .collect()
+    }
+
+    fn persist(&self) -> Result<(), LedgerError> {
+        let content = serde_json::to_string_pretty(&self.bans).map_err(|_| LedgerError::SerializeError)?;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|_| LedgerError::PersistenceError)?;
+        }
+        fs::write(&self.path, content).map_err(|e| {
+            error!("could not save bans to {}: {}", self.path.display(), e);
+            LedgerError::PersistenceError
+        })
+    }
+
+    fn load(&self) -> HashMap<IpAddr, i64> {
+        let Ok(content) = fs::read_to_string(&self.path) else {
+            return HashMap::new()
+        };
+        serde_json::from_str(&content).unwrap_or_else(|e| {
+            error!("invalid bans file {}: {}", self.path.display(), e);
+            HashMap::new()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+    use tempfile::tempdir;
+    use errors::LedgerError;
+    use network::Message;
+    use state::{Block, Transaction};
+    use state::chain_spec::ChainSpec;
+    use crate::bans::{inspect, BanManager, Misbehaviour, SCORE_DECAY_INTERVAL};
+
+    #[test]
+    fn peer_banned_at_threshold() {
+        let dir = tempdir().unwrap();
+        let mut bans = BanManager::new(100, Duration::from_secs(60), dir.path());
+        assert!(!bans.misbehaved(ip(1), Misbehaviour::InvalidBlock));
+        assert!(!bans.misbehaved(ip(2), Misbehaviour::InvalidBlock));
+        assert!(!bans.is_banned(&ip(1)));
+        assert!(bans.misbehaved(ip(1), Misbehaviour::InvalidBlock));
+        assert!(bans.is_banned(&ip(1)));
+        assert!(!bans.is_banned(&ip(2)));
+        assert_eq!(bans.bans().len(), 1);
+    }
+
+    #[test]
+    fn scores_decay_over_time() {
+        let dir = tempdir().unwrap();
+        let mut bans = BanManager::new(100, Duration::from_secs(60), dir.path());
+        let start = Instant::now();
+        assert!(!bans.misbehaved_at(ip(1), Misbehaviour::InvalidBlock, start));
+        // 30 points of the first penalty are forgiven after half an hour
+        let later = start + SCORE_DECAY_INTERVAL * 30;
+        assert!(!bans.misbehaved_at(ip(1), Misbehaviour::InvalidBlock, later));
+        assert_eq!(bans.scores[&ip(1)].0, 70);
+        assert!(bans.misbehaved_at(ip(1), Misbehaviour::InvalidBlock, later));
+        assert!(bans.is_banned(&ip(1)));
+        // score never goes below zero
+        assert!(!bans.misbehaved_at(ip(2), Misbehaviour::InvalidBlock, start));
+        assert!(!bans.misbehaved_at(ip(2), Misbehaviour::InvalidBlock, start + SCORE_DECAY_INTERVAL * 1000));
+        assert_eq!(bans.scores[&ip(2)].0, 50);
+    }
+
+    #[test]
+    fn bans_persist_until_expired() {
+        let dir = tempdir().unwrap();
+        let mut bans = BanManager::new(10, Duration::from_secs(60), dir.path());
+        bans.misbehaved(ip(1), Misbehaviour::ProtocolViolation);
+        let mut restored = BanManager::new(10, Duration::from_secs(0), dir.path());
+        assert!(restored.is_banned(&ip(1)));
+        restored.misbehaved(ip(2), Misbehaviour::ProtocolViolation);
+        // ban of zero seconds has already expired
+        assert!(!restored.is_banned(&ip(2)));
+        let restored = BanManager::new(10, Duration::from_secs(60), dir.path());
+        assert!(restored.is_banned(&ip(1)));
+        assert!(!restored.is_banned(&ip(2)));
+    }
+
+    #[test]
+    fn invalid_data_inspected() {
+        let spec = ChainSpec::from_toml("chain_id = \"test\"").unwrap();
+        let forged_block = Block { id: 1, hash: vec![0; 32], ..Default::default() };
+        assert_eq!(inspect(&spec, &Message::Block(forged_block)), Some(Misbehaviour::InvalidBlock));
+        let (public_key, private_key) = crypto::generate_keypair();
+        let signed = Transaction::new_signed("test", 0, 1, vec![], &public_key, &private_key).unwrap();
+        let forged = Transaction { fee: 2, ..signed.clone() };
+        assert_eq!(inspect(&spec, &Message::Transaction(forged)), Some(Misbehaviour::InvalidTransaction(LedgerError::BadSignature)));
+        assert_eq!(inspect(&spec, &Message::Transaction(signed)), None);
+        let foreign = Transaction::new_signed("other", 0, 1, vec![], &public_key, &private_key).unwrap();
+        assert_eq!(inspect(&spec, &Message::Transaction(foreign)),
+                   Some(Misbehaviour::InvalidTransaction(LedgerError::WrongChainId)));
+    }
+
+    fn ip(n: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, n])
+    }
+}
@@ -0,0 +1,397 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use clap::{Args, Parser, Subcommand};
+use serde::Deserialize;
+use tracing::Level;
+use errors::LedgerError;
+
+/// Ledger node
+#[derive(Debug, Parser)]
+#[command(version)]
+pub(crate) struct Cli {
+    /// TOML config file, command line flags override its values
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Save existing hex-encoded ed25519 private key as the node key in data directory,
+    /// the key is read from standard input unless `--key-file` is set
+    ImportKey {
+        /// File with the hex-encoded private key
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Default, Args)]
+pub(crate) struct ConfigOverrides {
+    /// Address for p2p connections
+    #[arg(long)]
+    pub listen_address: Option<SocketAddr>,
+    /// Address for client API requests
+    #[arg(long)]
+    pub api_address: Option<SocketAddr>,
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+    #[arg(long)]
+    pub chain_spec: Option<PathBuf>,
+    /// Bootstrap peer address, may be repeated
+    #[arg(long = "bootstrap-peer")]
+    pub bootstrap_peers: Vec<SocketAddr>,
+    /// Count of peers kept in the peer table
+    #[arg(long)]
+    pub target_peers: Option<usize>,
+    /// Seconds between peer list exchanges
+    #[arg(long)]
+    pub peer_exchange_interval: Option<u64>,
+    /// Maximum count of concurrent inbound connections
+    #[arg(long)]
+    pub max_inbound_connections: Option<usize>,
+    /// Maximum count of concurrent inbound connections from the same IP address
+    #[arg(long)]
+    pub max_connections_per_ip: Option<usize>,
+    /// Seconds to receive a handshake or a started message
+    #[arg(long)]
+    pub read_timeout: Option<u64>,
+    /// Seconds an inbound connection may stay silent before it is closed
+    #[arg(long)]
+    pub idle_timeout: Option<u64>,
+    /// Seconds between pings of connected peers
+    #[arg(long)]
+    pub ping_interval: Option<u64>,
+    /// Misbehaviour score at which a peer is banned
+    #[arg(long)]
+    pub ban_threshold: Option<u32>,
+    /// Seconds a misbehaving peer stays banned
+    #[arg(long)]
+    pub ban_duration: Option<u64>,
+    /// Encrypt p2p connections with Noise, authenticated by node keys
+    #[arg(long)]
+    pub encryption: Option<bool>,
+    /// Hex-encoded public key of a peer allowed to connect over encrypted transport, may be repeated
+    #[arg(long = "trusted-peer")]
+    pub trusted_peers: Vec<String>,
+    /// Maximum count of pending transactions
+    #[arg(long)]
+    pub mempool_max_transactions: Option<usize>,
+    /// Maximum total size of pending transactions in bytes
+    #[arg(long)]
+    pub mempool_max_bytes: Option<usize>,
+    /// Seconds a transaction may stay pending
+    #[arg(long)]
+    pub mempool_ttl: Option<u64>,
+    /// Maximum count of pending transactions of one signer
+    #[arg(long)]
+    pub mempool_max_per_sender: Option<usize>,
+    /// Minimum fee increase of a transaction replacing a pending one with the same signer and nonce
+    #[arg(long)]
+    pub mempool_replacement_fee_bump: Option<u32>,
+    #[arg(long)]
+    pub mining: Option<bool>,
+    /// Allow a chain spec without `genesis_hash`
+    #[arg(long)]
+    pub dev: Option<bool>,
+    /// Count of runtime worker threads
+    #[arg(long)]
+    pub threads: Option<usize>,
+    /// trace, debug, info, warn or error
+    #[arg(long)]
+    pub log_level: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct NodeConfig {
+    pub listen_address: SocketAddr,
+    pub api_address: SocketAddr,
+    pub data_dir: PathBuf,
+    pub chain_spec: PathBuf,
+    /// Seed addresses, the peer table is also restored from `peers.json` in data directory
+    pub bootstrap_peers: Vec<SocketAddr>,
+    pub target_peers: usize,
+    /// Seconds between peer list exchanges
+    pub peer_exchange_interval: u64,
+    pub max_inbound_connections: usize,
+    pub max_connections_per_ip: usize,
+    /// Seconds to receive a handshake or a started message
+    pub read_timeout: u64,
+    /// Seconds an inbound connection may stay silent before it is closed
+    pub idle_timeout: u64,
+    /// Seconds between pings of connected peers, a peer not answering until the next ping is disconnected.
+    /// Must be below `idle_timeout` of peers to keep quiet connections open
+    pub ping_interval: u64,
+    /// Misbehaviour score at which a peer is banned, bans are saved to `bans.json` in data directory
+    pub ban_threshold: u32,
+    /// Seconds a misbehaving peer stays banned
+    pub ban_duration: u64,
+    /// Encrypt p2p connections with Noise, every peer of the network must enable it
+    pub encryption: bool,
+    /// Hex-encoded public keys of peers allowed to connect when encryption is on, any peer if empty
+    pub trusted_peers: Vec<String>,
+    /// Pending transactions kept, the ones paying the lowest fee are evicted for better paying ones
+    pub mempool_max_transactions: usize,
+    /// Total size of pending transactions in bytes
+    pub mempool_max_bytes: usize,
+    /// Seconds a transaction may stay pending before it is dropped
+    pub mempool_ttl: u64,
+    /// Pending transactions of one signer
+    pub mempool_max_per_sender: usize,
+    /// Fee a transaction must pay above a pending one with the same signer and nonce to replace it
+    pub mempool_replacement_fee_bump: u32,
+    pub mining: bool,
+    /// Development mode, a chain spec without `genesis_hash` is accepted instead of refused
+    pub dev: bool,
+    /// Runtime worker threads, count of CPU cores if not set
+    pub threads: Option<usize>,
+    pub log_level: String,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            listen_address: utils::socket_addr("1234"),
+            api_address: utils::socket_addr("1244"),
+            data_dir: PathBuf::from("data"),
+            chain_spec: PathBuf::from("chain_spec.toml"),
+            bootstrap_peers: vec![],
+            target_peers: 8,
+            peer_exchange_interval: 30,
+            max_inbound_connections: 64,
+            max_connections_per_ip: 4,
+            read_timeout: 10,
+            idle_timeout: 300,
+            ping_interval: 60,
+            ban_threshold: 100,
+            ban_duration: 86400,
+            encryption: false,
+            trusted_peers: vec![],
+            mempool_max_transactions: 10_000,
+            mempool_max_bytes: 32 * 1024 * 1024,
+            mempool_ttl: 3 * 3600,
+            mempool_max_per_sender: 64,
+            mempool_replacement_fee_bump: 1,
+            mining: true,
+            dev: false,
+            threads: None,
+            log_level: String::from("info"),
+        }
+    }
+}
+
+impl NodeConfig {
+
+    /// Defaults, overridden by config file (if any), overridden by command line flags
+    pub fn from_cli(cli: &Cli) -> Result<Self, LedgerError> {
+        let config = match cli.config.as_ref() {
+            Some(path) => Self::load(path)?,
+            None => Self::default(),
+        };
+        config.with_overrides(&cli.overrides).validated()
+    }
+
+    pub fn load(path: &Path) -> Result<Self, LedgerError> {
+        let content = fs::read_to_string(path).map_err(|_| LedgerError::ConfigError)?;
+        toml::from_str(&content).map_err(|_| LedgerError::ConfigError)
+    }
+
+    fn with_overrides(mut self, overrides: &ConfigOverrides) -> Self {
+        if let Some(listen_address) = overrides.listen_address {
+            self.listen_address = listen_address;
+        }
+        if let Some(api_address) = overrides.api_address {
+            self.api_address = api_address;
+        }
+        if let Some(data_dir) = overrides.data_dir.as_ref() {
+            self.data_dir = data_dir.clone();
+        }
+        if let Some(chain_spec) = overrides.chain_spec.as_ref() {
+            self.chain_spec = chain_spec.clone();
+        }
+        if !overrides.bootstrap_peers.is_empty() {
+            self.bootstrap_peers = overrides.bootstrap_peers.clone();
+        }
+        if let Some(target_peers) = overrides.target_peers {
+            self.target_peers = target_peers;
+        }
+        if let Some(peer_exchange_interval) = overrides.peer_exchange_interval {
+            self.peer_exchange_interval = peer_exchange_interval;
+        }
+        if let Some(max_inbound_connections) = overrides.max_inbound_connections {
+            self.max_inbound_connections = max_inbound_connections;
+        }
+        if let Some(max_connections_per_ip) = overrides.max_connections_per_ip {
+            self.max_connections_per_ip = max_connections_per_ip;
+        }
+        if let Some(read_timeout) = overrides.read_timeout {
+            self.read_timeout = read_timeout;
+        }
+        if let Some(idle_timeout) = overrides.idle_timeout {
+            self.idle_timeout = idle_timeout;
+        }
+        if let Some(ping_interval) = overrides.ping_interval {
+            self.ping_interval = ping_interval;
+        }
+        if let Some(ban_threshold) = overrides.ban_threshold {
+            self.ban_threshold = ban_threshold;
+        }
+        if let Some(ban_duration) = overrides.ban_duration {
+            self.ban_duration = ban_duration;
+        }
+        if let Some(encryption) = overrides.encryption {
+            self.encryption = encryption;
+        }
+        if !overrides.trusted_peers.is_empty() {
+            self.trusted_peers = overrides.trusted_peers.clone();
+        }
+        if let Some(mempool_max_transactions) = overrides.mempool_max_transactions {
+            self.mempool_max_transactions = mempool_max_transactions;
+        }
+        if let Some(mempool_max_bytes) = overrides.mempool_max_bytes {
+            self.mempool_max_bytes = mempool_max_bytes;
+        }
+        if let Some(mempool_ttl) = overrides.mempool_ttl {
+            self.mempool_ttl = mempool_ttl;
+        }
+        if let Some(mempool_max_per_sender) = overrides.mempool_max_per_sender {
+            self.mempool_max_per_sender = mempool_max_per_sender;
+        }
+        if let Some(mempool_replacement_fee_bump) = overrides.mempool_replacement_fee_bump {
+            self.mempool_replacement_fee_bump = mempool_replacement_fee_bump;
+        }
+        if let Some(mining) = overrides.mining {
+            self.mining = mining;
+        }
+        if let Some(dev) = overrides.dev {
+            self.dev = dev;
+        }
+        if overrides.threads.is_some() {
+            self.threads = overrides.threads;
+        }
+        if let Some(log_level) = overrides.log_level.as_ref() {
+            self.log_level = log_level.clone();
+        }
+        self
+    }
+
+    fn validated(self) -> Result<Self, LedgerError> {
+        self.log_level()?;
+        self.trusted_keys()?;
+        if self.threads == Some(0)
+            || self.listen_address == self.api_address
+            || self.peer_exchange_interval == 0
+            || self.max_inbound_connections == 0
+            || self.max_connections_per_ip == 0
+            || self.read_timeout == 0
+            || self.idle_timeout < self.read_timeout
+            || self.ping_interval == 0
+            || self.ping_interval >= self.idle_timeout
+            || self.ban_threshold == 0
+            || self.ban_duration == 0
+            || self.mempool_max_transactions == 0
+            || self.mempool_max_bytes == 0
+            || self.mempool_ttl == 0
+            || self.mempool_max_per_sender == 0
+            || self.mempool_replacement_fee_bump == 0
+        {
+            return Err(LedgerError::ConfigError)
+        }
+        Ok(self)
+    }
+
+    pub fn log_level(&self) -> Result<Level, LedgerError> {
+        self.log_level.parse::<Level>().map_err(|_| LedgerError::ConfigError)
+    }
+
+    pub fn trusted_keys(&self) -> Result<Vec<Vec<u8>>, LedgerError> {
+        self.trusted_peers.iter()
+            .map(|key| hex::decode(key).map_err(|_| LedgerError::ConfigError))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use clap::Parser;
+    use tempfile::tempdir;
+    use errors::LedgerError;
+    use crate::config::{Cli, Command, NodeConfig};
+
+    #[test]
+    fn defaults_without_config_file() {
+        let cli = Cli::parse_from(["peer"]);
+        assert_eq!(NodeConfig::from_cli(&cli).unwrap(), NodeConfig::default());
+    }
+
+    #[test]
+    fn flags_override_config_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("node.toml");
+        fs::write(&path, r#"
+            listen_address = "0.0.0.0:2000"
+            api_address = "0.0.0.0:2010"
+            bootstrap_peers = ["10.0.0.1:2000", "10.0.0.2:2000"]
+            mining = false
+            threads = 2
+        "#).unwrap();
+        let cli = Cli::parse_from([
+            "peer",
+            "--config", path.to_str().unwrap(),
+            "--api-address", "0.0.0.0:3010",
+            "--data-dir", "/var/lib/ledger",
+            "--log-level", "debug",
+            "--dev", "true"]);
+        let config = NodeConfig::from_cli(&cli).unwrap();
+        assert_eq!(config.listen_address, "0.0.0.0:2000".parse().unwrap());
+        assert_eq!(config.api_address, "0.0.0.0:3010".parse().unwrap());
+        assert_eq!(config.data_dir.to_str(), Some("/var/lib/ledger"));
+        assert_eq!(config.bootstrap_peers.len(), 2);
+        assert!(!config.mining);
+        assert_eq!(config.threads, Some(2));
+        assert_eq!(config.log_level, "debug");
+        assert!(config.dev);
+    }
+
+    #[test]
+    fn invalid_config_rejected() {
+        let cli = Cli::parse_from(["peer", "--log-level", "loud"]);
+        assert_eq!(NodeConfig::from_cli(&cli).err(), Some(LedgerError::ConfigError));
+        let cli = Cli::parse_from(["peer", "--threads", "0"]);
+        assert_eq!(NodeConfig::from_cli(&cli).err(), Some(LedgerError::ConfigError));
+        let cli = Cli::parse_from(["peer", "--peer-exchange-interval", "0"]);
+        assert_eq!(NodeConfig::from_cli(&cli).err(), Some(LedgerError::ConfigError));
+        let cli = Cli::parse_from(["peer", "--read-timeout", "20", "--idle-timeout", "10"]);
+        assert_eq!(NodeConfig::from_cli(&cli).err(), Some(LedgerError::ConfigError));
+        let cli = Cli::parse_from(["peer", "--ping-interval", "300"]);
+        assert_eq!(NodeConfig::from_cli(&cli).err(), Some(LedgerError::ConfigError));
+        let cli = Cli::parse_from(["peer", "--ban-threshold", "0"]);
+        assert_eq!(NodeConfig::from_cli(&cli).err(), Some(LedgerError::ConfigError));
+        let cli = Cli::parse_from(["peer", "--mempool-ttl", "0"]);
+        assert_eq!(NodeConfig::from_cli(&cli).err(), Some(LedgerError::ConfigError));
+        let cli = Cli::parse_from(["peer", "--mempool-replacement-fee-bump", "0"]);
+        assert_eq!(NodeConfig::from_cli(&cli).err(), Some(LedgerError::ConfigError));
+        let cli = Cli::parse_from(["peer", "--encryption", "true", "--trusted-peer", "not hex"]);
+        assert_eq!(NodeConfig::from_cli(&cli).err(), Some(LedgerError::ConfigError));
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("node.toml");
+        fs::write(&path, "unknown = 1").unwrap();
+        let cli = Cli::parse_from(["peer", "--config", path.to_str().unwrap()]);
+        assert_eq!(NodeConfig::from_cli(&cli).err(), Some(LedgerError::ConfigError));
+    }
+
+    #[test]
+    fn import_key_command() {
+        let cli = Cli::parse_from(["peer", "--data-dir", "keys", "import-key", "--key-file", "node.hex"]);
+        assert!(matches!(cli.command,
+            Some(Command::ImportKey { key_file: Some(ref key_file) }) if key_file.to_str() == Some("node.hex")));
+        assert!(Cli::try_parse_from(["peer", "import-key", "abcd"]).is_err());
+        assert_eq!(NodeConfig::from_cli(&cli).unwrap().data_dir.to_str(), Some("keys"));
+    }
+}
@@ -1,22 +1,44 @@
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use network::Data;
+use tokio::sync::{oneshot, Mutex};
+use errors::LedgerError;
+use network::Message;
 use tokio::sync::mpsc::{
-    channel,
     Receiver as Rx,
     Sender as Tx
 };
 use async_trait::async_trait;
-use tracing::{error, info, span, trace, Level};
-use crate::sender::Sender;
+use tracing::error;
+
+/// Data received from the network on its way to the miner or the sender
+#[derive(Debug)]
+pub(crate) struct Inbound {
+    pub data: Message,
+    /// IP address of the peer the data came from, it is reported if the chain rejects its block
+    pub origin: Option<IpAddr>,
+    /// Submitter of a transaction waiting for it to be admitted to the pool or rejected
+    pub admitted: Option<oneshot::Sender<Result<(), LedgerError>>>,
+}
+
+impl Inbound {
+    pub fn from_peer(data: Message, origin: IpAddr) -> Self {
+        Self { data, origin: Some(origin), admitted: None }
+    }
+}
+
+impl From<Message> for Inbound {
+    fn from(data: Message) -> Self {
+        Self { data, origin: None, admitted: None }
+    }
+}
 
 /// structure for connecting modules together
 #[derive(Debug)]
 pub struct Connector {
-    pub(crate) receiver_rx: Arc<Mutex<Option<Rx<Data>>>>,
-    pub(crate) sender_tx: Arc<Mutex<Option<Tx<Data>>>>,
-    pub(crate) miner_rx: Arc<Mutex<Option<Rx<Data>>>>,
-    pub(crate) miner_tx: Arc<Mutex<Option<Tx<Data>>>>,
+    pub(crate) receiver_rx: Arc<Mutex<Option<Rx<Inbound>>>>,
+    pub(crate) sender_tx: Arc<Mutex<Option<Tx<Message>>>>,
+    pub(crate) miner_rx: Arc<Mutex<Option<Rx<Message>>>>,
+    pub(crate) miner_tx: Arc<Mutex<Option<Tx<Inbound>>>>,
 }
 
 impl Connector {
@@ -46,10 +68,10 @@ impl Connector {
     }
 
     async fn process_incoming(
-        receiver_rx: Arc<Mutex<Option<Rx<Data>>>>,
-        sender_tx: Arc<Mutex<Option<Tx<Data>>>>,
-        miner_rx: Arc<Mutex<Option<Rx<Data>>>>,
-        miner_tx: Arc<Mutex<Option<Tx<Data>>>>,
+        receiver_rx: Arc<Mutex<Option<Rx<Inbound>>>>,
+        sender_tx: Arc<Mutex<Option<Tx<Message>>>>,
+        miner_rx: Arc<Mutex<Option<Rx<Message>>>>,
+        miner_tx: Arc<Mutex<Option<Tx<Inbound>>>>,
     )
     {
         let sender_tx = sender_tx.clone();
@@ -60,9 +82,9 @@ impl Connector {
                 let miner_rx = miner_rx.as_mut();
                 if let Some(miner_rx) = miner_rx {
                     while let Some(data) = miner_rx.recv().await {
-                        match data.data_type() {
-                            1 => {
-                                //trace!("get block from miner: {}", &data);
+                        match data {
+                            Message::Block(_) | Message::Transaction(_) => {
+                                //trace!("get block or relayed transaction from miner: {}", &data);
                                 let sender_tx = sender_tx.clone();
                                 Self::send_data(sender_tx, data).await;
                             }
@@ -78,18 +100,18 @@ impl Connector {
                 let mut receiver_rx = receiver_rx.lock().await;
                 let receiver_rx = receiver_rx.as_mut();
                 if let Some(receiver_rx) = receiver_rx {
-                    while let Some(data) = receiver_rx.recv().await {
-                        //trace!("get data from receiver: {}", &data);
-                        match data.data_type() {
-                            1 | 2 => {
+                    while let Some(inbound) = receiver_rx.recv().await {
+                        //trace!("get data from receiver: {}", &inbound.data);
+                        match inbound.data {
+                            Message::Block(_) | Message::Transaction(_) => {
                                 let miner_tx = miner_tx.clone();
-                                Self::send_data(miner_tx, data).await
+                                Self::send_data(miner_tx, inbound).await
                             }
-                            3 | 4 => {
+                            Message::Peer(_) | Message::Peers(_) => {
                                 let sender_tx = sender_tx1.clone();
-                                Self::send_data(sender_tx, data).await;
+                                Self::send_data(sender_tx, inbound.data).await;
                             }
-                            _ => { unreachable!() }
+                            _ => { error!("received wrong data type: {}", inbound.data) }
                         }
                     }
                 }
@@ -99,7 +121,7 @@ impl Connector {
     }
 
     // TODO retry
-    async fn send_data(tx: Arc<Mutex<Option<Tx<Data>>>>, data: Data) {
+    async fn send_data<T>(tx: Arc<Mutex<Option<Tx<T>>>>, data: T) {
         let mut tx = tx.lock().await;
         let tx = tx.as_mut();
         if let Some(tx) = tx {
@@ -119,13 +141,12 @@ pub trait Connect {
 
 #[cfg(test)]
 mod tests {
-    use std::net::SocketAddr;
-    use std::str::FromStr;
     use std::sync::Arc;
     use tokio::sync::Mutex;
-    use network::serialize_data;
+    use ursa::signatures::ed25519::Ed25519Sha512;
+    use ursa::signatures::SignatureScheme;
     use state::Transaction;
-    use utils::LOCAL_HOST;
+    use state::chain_spec::ChainSpec;
     use client::Client;
     use crate::connector::{Connect, Connector};
 
@@ -133,8 +154,20 @@ mod tests {
     #[tokio::test]
     async fn test_channel() {
         let address =  utils::socket_addr("1234");
-        let mut receiver = crate::receiver::Receiver::new(address).await;
-        let mut miner = crate::miner::Miner::new(1);
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+        let mut storage = crate::storage::Storage::new(1, test_spec()).unwrap();
+        let handshake = storage.watch_handshake(&public_key.0);
+        let mut miner = crate::miner::Miner::new(1, public_key, private_key, storage);
+        let bans = crate::bans::BanManager::new(100, std::time::Duration::from_secs(60), &tempfile::tempdir().unwrap().keep());
+        let mut receiver = crate::receiver::Receiver::new(
+            address,
+            network::transport::Transport::Plain,
+            handshake,
+            crate::receiver::InboundLimits::from(&crate::config::NodeConfig::default()),
+            test_spec(),
+            Arc::new(std::sync::Mutex::new(bans)),
+            Arc::new(crate::gossip::Gossip::new(Default::default())),
+            miner.storage.clone()).await;
         //miner.run().await;
         let connector = Arc::new(Mutex::new(Connector::new()));
         let connector1 = connector.clone();
@@ -150,16 +183,25 @@ mod tests {
 
     async fn send_transaction_to_receiver() {
         let transaction = create_account_transaction();
-        let mut client = Client::new();
-        client.send_transaction_to_network(serialize_data(&transaction)).await;
+        let mut client = Client::new(&test_spec(), vec![utils::socket_addr("1234")]).unwrap();
+        client.send_transaction_to_network(transaction).await;
+    }
+
+    fn test_spec() -> ChainSpec {
+        ChainSpec::from_toml("chain_id = \"test\"").unwrap()
     }
 
     fn create_account_transaction() -> Transaction {
-        Transaction {
-            fee: 333,
-            commands: vec![state::Command::CreateAccount {
+        let (public_key, private_key) = crypto::generate_keypair();
+        Transaction::new_signed(
+            "test",
+            0,
+            333,
+            vec![state::Command::CreateAccount {
                 public_key: "12345".to_string(),
             }],
-        }
+            &public_key,
+            &private_key)
+            .unwrap()
     }
 }
@@ -0,0 +1,387 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use network::{InventoryItem, Message};
+use errors::LedgerError;
+use network::compact::{BlockTransactions, BlockTransactionsRequest, PartialBlock};
+use state::Block;
+use state::chain_spec::ChainSpec;
+use crate::bans::{inspect, Misbehaviour};
+use crate::miner::TransactionPool;
+
+/// Count of transaction ids remembered by the node
+pub(crate) const KNOWN_TRANSACTIONS: usize = 10_000;
+/// Count of inventory items remembered per peer
+const KNOWN_BY_PEER: usize = 10_000;
+/// Count of peers whose inventory is remembered, inventory of further peers is not tracked
+const MAX_TRACKED_PEERS: usize = 256;
+/// Count of recently relayed objects kept to serve requests of peers
+const MAX_OBJECTS: usize = 512;
+/// Item requested from a peer that has not sent it is requested again after this time
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Count of items in one inventory or get data message
+pub(crate) const MAX_INVENTORY_ITEMS: usize = 1000;
+/// Count of compact blocks waiting for their missing transactions
+const MAX_PENDING_BLOCKS: usize = 16;
+
+/// Recently seen values, e.g. transaction ids: a transaction is relayed to peers only the first time
+/// it is seen. The oldest value is forgotten when the capacity is reached
+#[derive(Debug)]
+pub(crate) struct KnownSet<T> {
+    values: HashSet<T>,
+    order: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T: Clone + Eq + Hash> KnownSet<T> {
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            values: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Returns false if `value` is already known
+    pub fn insert(&mut self, value: T) -> bool {
+        if self.values.contains(&value) {
+            return false
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.values.remove(&oldest);
+            }
+        }
+        self.values.insert(value.clone());
+        self.order.push_back(value);
+        true
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.values.contains(value)
+    }
+}
+
+/// Objects relayed by the node and inventory known to its peers by node id,
+/// shared by sender (announcing objects) and receiver (requesting missing ones)
+#[derive(Debug)]
+pub(crate) struct Inventory {
+    objects: HashMap<InventoryItem, Message>,
+    order: VecDeque<InventoryItem>,
+    peers: HashMap<Vec<u8>, KnownSet<InventoryItem>>,
+    requested: HashMap<InventoryItem, Instant>,
+    /// compact blocks by hash waiting for transactions missing in the pool
+    pending: HashMap<Vec<u8>, (PartialBlock, Instant)>,
+}
+
+impl Inventory {
+
+    pub fn new() -> Self {
+        Self {
+            objects: HashMap::new(),
+            order: VecDeque::new(),
+            peers: HashMap::new(),
+            requested: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Keeps a block or a transaction to serve it to peers, returns its inventory item
+    pub fn add(&mut self, data: &Message) -> Option<InventoryItem> {
+        let item = InventoryItem::of(data)?;
+        self.requested.remove(&item);
+        if self.objects.insert(item.clone(), data.clone()).is_none() {
+            self.order.push_back(item.clone());
+            if self.order.len() > MAX_OBJECTS {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.objects.remove(&oldest);
+                }
+            }
+        }
+        Some(item)
+    }
+
+    /// Marks `item` as known to peer `node_id`, returns false if the peer already knows it.
+    /// Clients (empty node id) and peers over `MAX_TRACKED_PEERS` are not tracked
+    pub fn known_by(&mut self, node_id: &[u8], item: InventoryItem) -> bool {
+        if node_id.is_empty() || (!self.peers.contains_key(node_id) && self.peers.len() >= MAX_TRACKED_PEERS) {
+            return true
+        }
+        self.peers.entry(node_id.to_vec())
+            .or_insert_with(|| KnownSet::new(KNOWN_BY_PEER))
+            .insert(item)
+    }
+
+    /// Forgets inventory known to peer `node_id`, called when a connection to the peer is closed
+    pub fn disconnected(&mut self, node_id: &[u8]) {
+        self.peers.remove(node_id);
+    }
+
+    /// Requested objects the node has, they are known to peer `node_id` from now on
+    pub fn serve(&mut self, node_id: &[u8], items: Vec<InventoryItem>) -> Vec<Message> {
+        let mut objects = Vec::new();
+        for item in items.into_iter().take(MAX_INVENTORY_ITEMS) {
+            let Some(object) = self.objects.get(&item).cloned() else {
+                continue
+            };
+            self.known_by(node_id, item);
+            objects.push(object);
+        }
+        objects
+    }
+
+    /// Items announced by peer `node_id` which the node neither has nor has recently requested,
+    /// they are considered requested from now on
+    pub fn missing(&mut self, node_id: &[u8], items: Vec<InventoryItem>) -> Vec<InventoryItem> {
+        let now = Instant::now();
+        self.requested.retain(|_, requested| now.duration_since(*requested) < REQUEST_TIMEOUT);
+        let mut missing = Vec::new();
+        for item in items.into_iter().take(MAX_INVENTORY_ITEMS) {
+            self.known_by(node_id, item.clone());
+            if self.objects.contains_key(&item) || self.requested.contains_key(&item) {
+                continue
+            }
+            self.requested.insert(item.clone(), now);
+            missing.push(item);
+        }
+        missing
+    }
+
+    /// Transactions of a block the node has, requested by indexes
+    pub fn block_transactions(&self, request: BlockTransactionsRequest) -> Vec<Message> {
+        let Some(Message::Block(block)) = self.objects.get(&InventoryItem::Block(request.hash.clone())) else {
+            return Vec::new()
+        };
+        let transactions = request.indexes.iter()
+            .filter_map(|i| block.transactions.get(*i as usize).cloned())
+            .collect();
+        let response = BlockTransactions { hash: request.hash, transactions };
+        vec![Message::BlockTransactions(response)]
+    }
+
+    /// False if the block is already known to the node or is waiting for its transactions
+    fn is_new_block(&mut self, hash: &[u8]) -> bool {
+        let now = Instant::now();
+        self.pending.retain(|_, (_, since)| now.duration_since(*since) < REQUEST_TIMEOUT);
+        !self.objects.contains_key(&InventoryItem::Block(hash.to_vec())) && !self.pending.contains_key(hash)
+    }
+
+    /// Keeps `partial` until its missing transactions arrive, returns their request.
+    /// The whole block is requested when too many blocks are waiting
+    fn await_transactions(&mut self, partial: PartialBlock) -> Vec<Message> {
+        if self.pending.len() >= MAX_PENDING_BLOCKS {
+            return vec![Message::GetData(vec![InventoryItem::Block(partial.hash().to_vec())])]
+        }
+        let request = BlockTransactionsRequest { hash: partial.hash().to_vec(), indexes: partial.missing() };
+        self.pending.insert(request.hash.clone(), (partial, Instant::now()));
+        vec![Message::GetBlockTransactions(request)]
+    }
+}
+
+/// Gossip state shared by sender and receiver
+#[derive(Debug)]
+pub(crate) struct Gossip {
+    pub inventory: Mutex<Inventory>,
+    /// compact blocks are rebuilt from transactions of the pool
+    pool: TransactionPool,
+}
+
+impl Gossip {
+
+    pub fn new(pool: TransactionPool) -> Self {
+        Self { inventory: Mutex::new(Inventory::new()), pool }
+    }
+
+    /// Handles data received from peer `node_id`: answers requests of the peer, requests objects
+    /// missing to the node and rebuilds compact blocks. Returns messages to send back to the peer
+    /// and data to pass to connector
+    pub async fn receive(&self, chain_spec: &ChainSpec, node_id: &[u8], data: Message)
+        -> Result<(Vec<Message>, Option<Message>), Misbehaviour>
+    {
+        if let Some(misbehaviour) = inspect(chain_spec, &data) {
+            return Err(misbehaviour)
+        }
+        match data {
+            Message::Ping(nonce) => Ok((vec![Message::Pong(nonce)], None)),
+            Message::Pong(_) => Ok((Vec::new(), None)),
+            // blocks are served by height on inbound connections, where replies carry the request id
+            Message::GetBlocks(_) => Ok((Vec::new(), None)),
+            Message::Inventory(items) => {
+                let missing = self.inventory.lock().unwrap().missing(node_id, items);
+                if missing.is_empty() {
+                    return Ok((Vec::new(), None))
+                }
+                Ok((vec![Message::GetData(missing)], None))
+            }
+            Message::GetData(items) => Ok((self.inventory.lock().unwrap().serve(node_id, items), None)),
+            Message::GetBlockTransactions(request) => {
+                Ok((self.inventory.lock().unwrap().block_transactions(request), None))
+            }
+            Message::CompactBlock(compact) => {
+                let hash = compact.header.hash.clone();
+                {
+                    let mut inventory = self.inventory.lock().unwrap();
+                    inventory.known_by(node_id, InventoryItem::Block(hash.clone()));
+                    if !inventory.is_new_block(&hash) {
+                        return Ok((Vec::new(), None))
+                    }
+                }
+                let partial = compact.reconstruct(self.pool.lock().await.iter());
+                if !partial.missing().is_empty() {
+                    return Ok((self.inventory.lock().unwrap().await_transactions(partial), None))
+                }
+                Self::rebuilt(chain_spec, hash, partial.fill(Vec::new()))
+            }
+            Message::BlockTransactions(transactions) => {
+                let pending = self.inventory.lock().unwrap().pending.remove(&transactions.hash);
+                let Some((partial, _)) = pending else {
+                    return Ok((Vec::new(), None))
+                };
+                Self::rebuilt(chain_spec, transactions.hash, partial.fill(transactions.transactions))
+            }
+            data => {
+                if let Some(item) = InventoryItem::of(&data) {
+                    self.inventory.lock().unwrap().known_by(node_id, item);
+                }
+                Ok((Vec::new(), Some(data)))
+            }
+        }
+    }
+
+    /// Rebuilt block is checked as any received one. Short ids may collide,
+    /// a block that could not be rebuilt is requested whole
+    fn rebuilt(chain_spec: &ChainSpec, hash: Vec<u8>, block: Result<Block, LedgerError>)
+        -> Result<(Vec<Message>, Option<Message>), Misbehaviour>
+    {
+        let Ok(block) = block else {
+            return Ok((vec![Message::GetData(vec![InventoryItem::Block(hash)])], None))
+        };
+        let data = Message::Block(block);
+        if let Some(misbehaviour) = inspect(chain_spec, &data) {
+            return Err(misbehaviour)
+        }
+        Ok((Vec::new(), Some(data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use network::{InventoryItem, Message};
+    use network::compact::CompactBlock;
+    use state::{Block, Transaction};
+    use state::chain_spec::ChainSpec;
+    use crate::gossip::{Gossip, Inventory, KnownSet, MAX_TRACKED_PEERS};
+    use crate::mempool::Mempool;
+
+    #[test]
+    fn value_is_new_only_once() {
+        let mut known = KnownSet::new(2);
+        assert!(known.insert(vec![1]));
+        assert!(!known.insert(vec![1]));
+        assert!(known.insert(vec![2]));
+        assert!(known.insert(vec![3]));
+        // the oldest value is forgotten
+        assert!(known.insert(vec![1]));
+        assert!(!known.insert(vec![3]));
+    }
+
+    #[test]
+    fn item_announced_once_per_peer() {
+        let mut inventory = Inventory::new();
+        let item = inventory.add(&block(1)).unwrap();
+        assert_eq!(item, InventoryItem::Block(vec![1; 32]));
+        assert!(inventory.known_by(&[1], item.clone()));
+        assert!(!inventory.known_by(&[1], item.clone()));
+        assert!(inventory.known_by(&[2], item));
+    }
+
+    #[test]
+    fn peers_forgotten_on_disconnect_and_limited() {
+        let mut inventory = Inventory::new();
+        let item = inventory.add(&block(1)).unwrap();
+        assert!(inventory.known_by(&[1], item.clone()));
+        inventory.disconnected(&[1]);
+        assert!(inventory.peers.is_empty());
+        assert!(inventory.known_by(&[1], item.clone()));
+        for peer in 2..=MAX_TRACKED_PEERS as u32 {
+            inventory.known_by(&peer.to_be_bytes(), item.clone());
+        }
+        assert_eq!(inventory.peers.len(), MAX_TRACKED_PEERS);
+        // peer over the limit is announced everything
+        assert!(inventory.known_by(&[0], item.clone()));
+        assert!(inventory.known_by(&[0], item));
+        assert_eq!(inventory.peers.len(), MAX_TRACKED_PEERS);
+    }
+
+    #[test]
+    fn only_missing_items_requested() {
+        let mut inventory = Inventory::new();
+        let known = inventory.add(&block(1)).unwrap();
+        let new = InventoryItem::Block(vec![2; 32]);
+        assert_eq!(inventory.missing(&[1], vec![known.clone(), new.clone()]), vec![new.clone()]);
+        // requested item is not requested from another peer at the same time
+        assert!(inventory.missing(&[2], vec![new.clone()]).is_empty());
+        // announcing peer knows the items, they are not announced back to it
+        assert!(!inventory.known_by(&[1], new.clone()));
+        assert!(!inventory.known_by(&[1], known));
+        inventory.add(&block(2));
+        assert!(inventory.missing(&[3], vec![new]).is_empty());
+    }
+
+    #[test]
+    fn requested_objects_served() {
+        let mut inventory = Inventory::new();
+        let item = inventory.add(&block(1)).unwrap();
+        let frames = inventory.serve(&[1], vec![item.clone(), InventoryItem::Block(vec![2; 32])]);
+        assert_eq!(frames.len(), 1);
+        assert!(matches!(frames[0], Message::Block(_)));
+        assert!(!inventory.known_by(&[1], item));
+    }
+
+    #[tokio::test]
+    async fn compact_block_rebuilt_from_pool() {
+        let spec = ChainSpec::from_toml("chain_id = \"test\"\n[consensus]\ndifficulty = 0\nblock_reward = 1").unwrap();
+        let transactions = (1..=3).map(transaction).collect::<Vec<_>>();
+        let block = signed_block(transactions.clone());
+        let mut pool = Mempool::default();
+        for transaction in transactions[..2].iter() {
+            pool.add(transaction.clone()).unwrap();
+        }
+        let gossip = Gossip::new(Arc::new(Mutex::new(pool)));
+        let compact = Message::CompactBlock(CompactBlock::new(&block));
+        let (frames, data) = gossip.receive(&spec, &[1], compact.clone()).await.unwrap();
+        assert!(data.is_none());
+        let Message::GetBlockTransactions(request) = frames[0].clone() else { panic!("transactions are not requested") };
+        assert_eq!(request.indexes, vec![2]);
+        // block waiting for its transactions is not requested again
+        assert!(gossip.receive(&spec, &[2], compact).await.unwrap().0.is_empty());
+        // peer that relayed the block serves the missing transactions
+        let peer = Gossip::new(Default::default());
+        peer.inventory.lock().unwrap().add(&Message::Block(block.clone()));
+        let (frames, _) = peer.receive(&spec, &[3], Message::GetBlockTransactions(request)).await.unwrap();
+        let Message::BlockTransactions(response) = frames[0].clone() else { panic!("transactions are not served") };
+        let (_, data) = gossip.receive(&spec, &[1], Message::BlockTransactions(response)).await.unwrap();
+        assert!(matches!(data, Some(Message::Block(b)) if b.hash == block.hash && b.transactions.len() == 3));
+    }
+
+    fn signed_block(transactions: Vec<Transaction>) -> Block {
+        let (public_key, private_key) = crypto::generate_keypair();
+        let mut block = Block { id: 1, producer: public_key, transactions, ..Default::default() };
+        block.hash = block.compute_hash();
+        block.signature = crypto::sign(&block.hash, &private_key).unwrap();
+        block
+    }
+
+    fn transaction(fee: u32) -> Transaction {
+        let (public_key, private_key) = crypto::generate_keypair();
+        Transaction::new_signed("test", 0, fee, vec![], &public_key, &private_key).unwrap()
+    }
+
+    fn block(n: u8) -> Message {
+        Message::Block(Block { hash: vec![n; 32], ..Default::default() })
+    }
+}
@@ -0,0 +1,281 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use argon2::Argon2;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead};
+use rand::{RngCore, thread_rng};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use ursa::keys::{PrivateKey, PublicKey};
+use ursa::signatures::ed25519::Ed25519Sha512;
+use ursa::signatures::SignatureScheme;
+use errors::LedgerError;
+
+/// Name of the node key file inside of the data directory
+pub(crate) const KEY_FILE: &str = "node.key";
+/// Environment variable with the passphrase of the node key file
+pub(crate) const PASSPHRASE_ENV: &str = "LEDGER_KEY_PASSPHRASE";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Content of the key file, all binary values are hex-encoded
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyFile {
+    public_key: String,
+    secret: Secret,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Secret {
+    Plain {
+        private_key: String,
+    },
+    /// private key encrypted by ChaCha20Poly1305 with a key derived from passphrase by Argon2
+    Encrypted {
+        salt: String,
+        nonce: String,
+        ciphertext: String,
+    },
+}
+
+/// Loads node keys from `data_dir`, generating and saving them on the first run
+pub(crate) fn load_or_create(data_dir: &Path, passphrase: Option<&str>)
+    -> Result<(PublicKey, PrivateKey), LedgerError>
+{
+    let path = data_dir.join(KEY_FILE);
+    if path.exists() {
+        return load(&path, passphrase)
+    }
+    let (public_key, private_key) = Ed25519Sha512::new()
+        .keypair(None)
+        .map_err(|_| LedgerError::KeyError)?;
+    save(data_dir, &public_key, &private_key, passphrase)?;
+    info!("new node key {} has been saved to {}", &public_key, path.display());
+    Ok((public_key, private_key))
+}
+
+/// Hex-encoded private key to import, read from `key_file` or from the first line of standard input,
+/// so that it does not show up in the command line of the process
+pub(crate) fn read_private_key(key_file: Option<&Path>) -> Result<String, LedgerError> {
+    let private_key_hex = match key_file {
+        Some(path) => fs::read_to_string(path).map_err(|e| {
+            error!("could not read private key file {}: {}", path.display(), e);
+            LedgerError::KeyError
+        })?,
+        None => {
+            let mut line = String::new();
+            io::stdin().read_line(&mut line).map_err(|_| LedgerError::KeyError)?;
+            line
+        }
+    };
+    Ok(private_key_hex.trim().to_string())
+}
+
+/// Saves existing hex-encoded ed25519 private key as the node key.
+/// Refuses to overwrite a key file that is already present in `data_dir`
+pub(crate) fn import(data_dir: &Path, private_key_hex: &str, passphrase: Option<&str>)
+    -> Result<PublicKey, LedgerError>
+{
+    let path = data_dir.join(KEY_FILE);
+    if path.exists() {
+        error!("key file already exists: {}", path.display());
+        return Err(LedgerError::KeyError)
+    }
+    let private_key = decode_hex(private_key_hex.trim())?;
+    let (public_key, private_key) = restore_keypair(private_key)?;
+    save(data_dir, &public_key, &private_key, passphrase)?;
+    info!("node key {} has been imported to {}", &public_key, path.display());
+    Ok(public_key)
+}
+
+fn load(path: &Path, passphrase: Option<&str>) -> Result<(PublicKey, PrivateKey), LedgerError> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        error!("could not read key file {}: {}", path.display(), e);
+        LedgerError::PersistenceError
+    })?;
+    let key_file: KeyFile = serde_json::from_str(&content).map_err(|e| {
+        error!("invalid key file {}: {}", path.display(), e);
+        LedgerError::KeyError
+    })?;
+    let private_key = match key_file.secret {
+        Secret::Plain { private_key } => decode_hex(&private_key)?,
+        Secret::Encrypted { salt, nonce, ciphertext } => {
+            let Some(passphrase) = passphrase else {
+                error!("key file {} is encrypted, passphrase required", path.display());
+                return Err(LedgerError::KeyError)
+            };
+            let cipher = cipher(passphrase, &decode_hex(&salt)?)?;
+            let nonce = decode_hex(&nonce)?;
+            if nonce.len() != NONCE_LEN {
+                return Err(LedgerError::KeyError)
+            }
+            cipher.decrypt(Nonce::from_slice(&nonce), decode_hex(&ciphertext)?.as_slice())
+                .map_err(|_| {
+                    error!("could not decrypt key file {}, wrong passphrase?", path.display());
+                    LedgerError::KeyError
+                })?
+        }
+    };
+    let (public_key, private_key) = restore_keypair(private_key)?;
+    if hex::encode(&public_key.0) != key_file.public_key {
+        error!("public key does not match private key in {}", path.display());
+        return Err(LedgerError::KeyError)
+    }
+    Ok((public_key, private_key))
+}
+
+fn save(data_dir: &Path,
+        public_key: &PublicKey,
+        private_key: &PrivateKey,
+        passphrase: Option<&str>)
+    -> Result<(), LedgerError>
+{
+    let secret = match passphrase {
+        None => Secret::Plain { private_key: hex::encode(&private_key.0) },
+        Some(passphrase) => {
+            let mut salt = [0u8; SALT_LEN];
+            let mut nonce = [0u8; NONCE_LEN];
+            thread_rng().fill_bytes(&mut salt);
+            thread_rng().fill_bytes(&mut nonce);
+            let ciphertext = cipher(passphrase, &salt)?
+                .encrypt(Nonce::from_slice(&nonce), private_key.0.as_slice())
+                .map_err(|_| LedgerError::KeyError)?;
+            Secret::Encrypted {
+                salt: hex::encode(salt),
+                nonce: hex::encode(nonce),
+                ciphertext: hex::encode(ciphertext),
+            }
+        }
+    };
+    let key_file = KeyFile { public_key: hex::encode(&public_key.0), secret };
+    let content = serde_json::to_string_pretty(&key_file).map_err(|_| LedgerError::SerializeError)?;
+    fs::create_dir_all(data_dir)
+        .and_then(|_| write_private(&data_dir.join(KEY_FILE), content.as_bytes()))
+        .map_err(|e| {
+            error!("could not save key file to {}: {}", data_dir.display(), e);
+            LedgerError::PersistenceError
+        })
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(content)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    fs::write(path, content)
+}
+
+/// Accepts both 32-byte ed25519 secret and 64-byte (secret + public) ursa private key.
+/// The public key is always derived from the secret, a 64-byte key with another public half is rejected
+fn restore_keypair(private_key: Vec<u8>) -> Result<(PublicKey, PrivateKey), LedgerError> {
+    if private_key.len() != 32 && private_key.len() != 64 {
+        error!("invalid private key length: {}", private_key.len());
+        return Err(LedgerError::KeyError)
+    }
+    let (public_key, restored) = Ed25519Sha512::expand_keypair(&private_key[..32])
+        .map_err(|_| LedgerError::KeyError)?;
+    if private_key.len() == 64 && private_key[32..] != public_key.0[..] {
+        error!("public half of the private key does not match its secret");
+        return Err(LedgerError::KeyError)
+    }
+    Ok((public_key, restored))
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<ChaCha20Poly1305, LedgerError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| LedgerError::KeyError)?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+fn decode_hex(string: &str) -> Result<Vec<u8>, LedgerError> {
+    hex::decode(string).map_err(|_| LedgerError::KeyError)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use tempfile::tempdir;
+    use errors::LedgerError;
+    use crate::keys::{import, KEY_FILE, load_or_create, read_private_key};
+
+    #[test]
+    fn key_is_created_once_and_loaded_afterwards() {
+        let dir = tempdir().unwrap();
+        let (public_key, private_key) = load_or_create(dir.path(), None).unwrap();
+        assert!(dir.path().join(KEY_FILE).exists());
+        let (loaded_public_key, loaded_private_key) = load_or_create(dir.path(), None).unwrap();
+        assert_eq!(public_key, loaded_public_key);
+        assert_eq!(private_key, loaded_private_key);
+    }
+
+    #[test]
+    fn encrypted_key_requires_right_passphrase() {
+        let dir = tempdir().unwrap();
+        let (public_key, _) = load_or_create(dir.path(), Some("secret")).unwrap();
+        let content = fs::read_to_string(dir.path().join(KEY_FILE)).unwrap();
+        assert!(content.contains("encrypted"));
+        assert_eq!(load_or_create(dir.path(), None).err(), Some(LedgerError::KeyError));
+        assert_eq!(load_or_create(dir.path(), Some("wrong")).err(), Some(LedgerError::KeyError));
+        let (loaded_public_key, _) = load_or_create(dir.path(), Some("secret")).unwrap();
+        assert_eq!(public_key, loaded_public_key);
+    }
+
+    #[test]
+    fn imported_key_is_used_as_node_key() {
+        let source = tempdir().unwrap();
+        let (public_key, private_key) = load_or_create(source.path(), None).unwrap();
+        let dir = tempdir().unwrap();
+        let key_file = source.path().join("private_key");
+        fs::write(&key_file, format!("{}\n", hex::encode(&private_key.0))).unwrap();
+        let private_key_hex = read_private_key(Some(&key_file)).unwrap();
+        let imported = import(dir.path(), &private_key_hex, Some("secret")).unwrap();
+        assert_eq!(public_key, imported);
+        let (loaded_public_key, _) = load_or_create(dir.path(), Some("secret")).unwrap();
+        assert_eq!(public_key, loaded_public_key);
+        assert_eq!(import(dir.path(), &private_key_hex, None).err(), Some(LedgerError::KeyError));
+    }
+
+    #[test]
+    fn secret_and_expanded_keys_imported() {
+        let (public_key, private_key) = load_or_create(tempdir().unwrap().path(), None).unwrap();
+        let secret = tempdir().unwrap();
+        assert_eq!(import(secret.path(), &hex::encode(&private_key.0[..32]), None).unwrap(), public_key);
+        let (_, loaded_private_key) = load_or_create(secret.path(), None).unwrap();
+        assert_eq!(loaded_private_key, private_key);
+        let expanded = tempdir().unwrap();
+        assert_eq!(import(expanded.path(), &hex::encode(&private_key.0), None).unwrap(), public_key);
+    }
+
+    #[test]
+    fn key_with_foreign_public_half_rejected() {
+        let (_, private_key) = load_or_create(tempdir().unwrap().path(), None).unwrap();
+        let (other_public_key, _) = load_or_create(tempdir().unwrap().path(), None).unwrap();
+        let forged = [&private_key.0[..32], &other_public_key.0[..]].concat();
+        let dir = tempdir().unwrap();
+        assert_eq!(import(dir.path(), &hex::encode(forged), None).err(), Some(LedgerError::KeyError));
+        assert!(!dir.path().join(KEY_FILE).exists());
+    }
+
+    #[test]
+    fn import_rejects_malformed_key() {
+        let dir = tempdir().unwrap();
+        assert_eq!(import(dir.path(), "not hex", None).err(), Some(LedgerError::KeyError));
+        assert_eq!(import(dir.path(), "abcd", None).err(), Some(LedgerError::KeyError));
+        assert!(!dir.path().join(KEY_FILE).exists());
+        assert_eq!(read_private_key(Some(&dir.path().join("missing"))).err(), Some(LedgerError::KeyError));
+    }
+}
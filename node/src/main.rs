@@ -1,8 +1,12 @@
-#![feature(async_closure)]
 #![feature(slice_pattern)]
-#![feature(let_chains)]
 extern crate core;
 
+mod config;
+mod keys;
+mod peers;
+mod bans;
+mod gossip;
+mod mempool;
 mod storage;
 mod sender;
 mod receiver;
@@ -10,50 +14,94 @@ mod miner;
 mod node;
 mod connector;
 
-use tracing_subscriber;
-use std::time::Duration;
+use std::path::Path;
+use std::process::ExitCode;
+use clap::Parser;
+use tracing::error;
+use state::chain_spec::ChainSpec;
+use crate::config::{Cli, Command, NodeConfig};
 use crate::node::Node;
 
-
-fn main() {
-
-    tracing_subscriber::fmt::init();
-    let runtime = tokio::runtime::Runtime::new().unwrap();
+/// `peer [--config <file>] [flags]` - start node,
+/// `peer [--data-dir <dir>] import-key [--key-file <file>]` - save existing key (from file or stdin) as node key
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let config = match NodeConfig::from_cli(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE
+        }
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(config.log_level().unwrap())
+        .init();
+    if let Some(Command::ImportKey { key_file }) = cli.command.as_ref() {
+        return import_key(&config, key_file.as_deref());
+    }
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = config.threads {
+        runtime.worker_threads(threads);
+    }
+    let runtime = runtime.enable_all().build().unwrap();
 
     runtime.block_on( async {
-        let node1 = Node::new(1, "1234").await;
-        let node2 = Node::new(2,"1235").await;
-        let node3 = Node::new(3,"1236").await;
-
-        tokio::spawn(async move { node1.start().await });
-        tokio::spawn(async move { node2.start().await });
-        tokio::spawn(async move { node3.start().await });
-        loop {
-            tokio::time::sleep(Duration::MAX).await;
+        let chain_spec = match ChainSpec::load(&config.chain_spec) {
+            Ok(chain_spec) => chain_spec,
+            Err(e) => {
+                error!("could not load chain spec {}: {}", config.chain_spec.display(), e);
+                return ExitCode::FAILURE
+            }
+        };
+        match Node::new(&config, chain_spec).await {
+            Ok(node) => {
+                node.start().await;
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                error!("could not start node: {}", e);
+                ExitCode::FAILURE
+            }
         }
-    });
+    })
+}
+
+fn import_key(config: &NodeConfig, key_file: Option<&Path>) -> ExitCode {
+    let passphrase = std::env::var(keys::PASSPHRASE_ENV).ok();
+    let imported = keys::read_private_key(key_file)
+        .and_then(|private_key| keys::import(&config.data_dir, &private_key, passphrase.as_deref()));
+    if let Err(e) = imported {
+        error!("could not import key: {}", e);
+        return ExitCode::FAILURE
+    }
+    ExitCode::SUCCESS
 }
 
 
 #[cfg(test)]
 mod tests {
-    use std::thread;
     use std::time::Duration;
     use rand::{Rng, thread_rng};
     use ursa::signatures::ed25519::Ed25519Sha512;
     use ursa::signatures::SignatureScheme;
     use client::Client;
-    use network::serialize_data;
     use state::{Command, Transaction};
+    use state::chain_spec::ChainSpec;
 
 
     /// Assuming that nodes have started before this test performs
     #[tokio::test]
     async fn send_transactions_to_network() {
 
-        let mut client1 = Client::new();
-        let mut client2 = Client::new();
-        let mut client3 = Client::new();
+        let peers = vec![
+            utils::socket_addr("1234"),
+            utils::socket_addr("1235"),
+            utils::socket_addr("1236")
+        ];
+        let chain_spec = ChainSpec::from_toml(include_str!("../../chain_spec.toml")).unwrap();
+        let mut client1 = Client::new(&chain_spec, peers.clone()).unwrap();
+        let mut client2 = Client::new(&chain_spec, peers.clone()).unwrap();
+        let mut client3 = Client::new(&chain_spec, peers).unwrap();
 
         let mut transactions1 = Vec::with_capacity(10);
         for _ in 0..transactions1.capacity() {
@@ -70,39 +118,30 @@ mod tests {
             transactions3.push(generate_transaction());
         };
 
-        let mut count = 0;
         tokio::spawn(async move {
             for i in 0..transactions1.len() {
                 let transaction = transactions1.get(i).unwrap();
-                client1.send_transaction_to_network(
-                    serialize_data(transaction))
+                client1.send_transaction_to_network(transaction.clone())
                     .await;
                 tokio::time::sleep(Duration::from_secs(2)).await;
-                count += 1;
             }
-        }).await.expect("TODO: panic message 1");;
+        }).await.expect("TODO: panic message 1");
 
-        let mut count = 0;
         tokio::spawn(async move {
             for i in 0..transactions2.len() {
                 let transaction = transactions2.get(i).unwrap();
-                client2.send_transaction_to_network(
-                    serialize_data(transaction))
+                client2.send_transaction_to_network(transaction.clone())
                     .await;
                 tokio::time::sleep(Duration::from_secs(2)).await;
-                count += 1;
             }
-        }).await.expect("TODO: panic message 2");;
+        }).await.expect("TODO: panic message 2");
 
-        let mut count = 0;
         tokio::spawn(async move {
             for i in 0..transactions3.len() {
                 let transaction = transactions3.get(i).unwrap();
-                client3.send_transaction_to_network(
-                    serialize_data(transaction))
+                client3.send_transaction_to_network(transaction.clone())
                     .await;
                 tokio::time::sleep(Duration::from_secs(2)).await;
-                count += 1;
             }
         }).await.expect("TODO: panic message 3");
     }
@@ -142,10 +181,8 @@ mod tests {
             n2 -= 1;
         }
 
-        Transaction {
-            fee: 111,
-            commands,
-        }
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+        Transaction::new_signed("ledger-local", 0, 111, commands, &public_key.0, &private_key.0).unwrap()
     }
 }
 
@@ -0,0 +1,484 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+use crypto::Hash;
+use errors::LedgerError;
+use network::serialize_data;
+use state::{Accounts, Assets, Block, Transaction, Undo};
+use utils::print_bytes;
+use crate::config::NodeConfig;
+use crate::gossip::{KnownSet, KNOWN_TRANSACTIONS};
+
+/// Limits of pending transactions kept by the node
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct MempoolLimits {
+    pub max_transactions: usize,
+    /// total size of encoded transactions
+    pub max_bytes: usize,
+    /// time a transaction may stay pending before it is dropped
+    pub ttl: Duration,
+    /// count of pending transactions of one signer
+    pub max_per_sender: usize,
+    /// fee a replacement must pay above the pending transaction with the same signer and nonce
+    pub replacement_fee_bump: u32,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        Self::from(&NodeConfig::default())
+    }
+}
+
+impl From<&NodeConfig> for MempoolLimits {
+    fn from(config: &NodeConfig) -> Self {
+        Self {
+            max_transactions: config.mempool_max_transactions,
+            max_bytes: config.mempool_max_bytes,
+            ttl: Duration::from_secs(config.mempool_ttl),
+            max_per_sender: config.mempool_max_per_sender,
+            replacement_fee_bump: config.mempool_replacement_fee_bump,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    transaction: Transaction,
+    size: usize,
+    added: Instant,
+    /// order of arrival, transactions with the same fee are mined first come first served
+    seq: u64,
+}
+
+/// Pending transactions of the node, highest fee first. A full pool evicts transactions
+/// with the lowest fee for a better paying one, a pending transaction is replaced by one
+/// with the same signer and nonce paying at least `replacement_fee_bump` more
+#[derive(Debug)]
+pub(crate) struct Mempool {
+    limits: MempoolLimits,
+    entries: HashMap<Hash, Entry>,
+    /// (fee, arrival) -> id, the best transaction is the last one
+    by_priority: BTreeSet<(u32, Reverse<u64>, Hash)>,
+    /// (time added, arrival) -> id, the oldest transaction is the first one
+    by_arrival: BTreeMap<(Instant, u64), Hash>,
+    per_sender: HashMap<Vec<u8>, usize>,
+    /// (signer, nonce) -> id of the pending transaction
+    by_slot: HashMap<(Vec<u8>, u64), Hash>,
+    bytes: usize,
+    next_seq: u64,
+    /// ids of transactions recently added to the chain, they are never pending again
+    included: KnownSet<Hash>,
+    /// time transactions taken for a block were added, kept until the block is added or fails
+    taken: HashMap<Hash, Instant>,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(MempoolLimits::default())
+    }
+}
+
+impl Mempool {
+
+    pub fn new(limits: MempoolLimits) -> Self {
+        Self {
+            limits,
+            entries: HashMap::new(),
+            by_priority: BTreeSet::new(),
+            by_arrival: BTreeMap::new(),
+            per_sender: HashMap::new(),
+            by_slot: HashMap::new(),
+            bytes: 0,
+            next_seq: 0,
+            included: KnownSet::new(KNOWN_TRANSACTIONS),
+            taken: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Pending transactions in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.entries.values().map(|entry| &entry.transaction)
+    }
+
+    /// Adds a verified transaction. It is rejected when it is already pending or included, its signer has
+    /// `max_per_sender` pending transactions, or the pool is full of transactions paying the same or more.
+    /// A pending transaction with the same signer and nonce is replaced if the fee is high enough
+    pub fn add(&mut self, transaction: Transaction) -> Result<(), LedgerError> {
+        self.add_at(transaction, Instant::now())
+    }
+
+    /// Adds a verified transaction received from the network, it must be executable on `accounts` and `assets`,
+    /// the state of the best block. Returns the error the transaction has been rejected with
+    pub fn admit(&mut self, transaction: Transaction, mut accounts: Accounts, mut assets: Assets)
+        -> Result<(), LedgerError>
+    {
+        if self.is_known(&transaction.id()) {
+            return Err(LedgerError::DuplicateTransaction)
+        }
+        execute(&transaction, &mut accounts, &mut assets)?;
+        self.add(transaction)
+    }
+
+    /// Adds a transaction pending since `added`, it expires `ttl` after that
+    fn add_at(&mut self, transaction: Transaction, added: Instant) -> Result<(), LedgerError> {
+        let id = transaction.id();
+        if self.is_known(&id) {
+            return Err(LedgerError::DuplicateTransaction)
+        }
+        let slot = (transaction.signer.clone(), transaction.nonce);
+        let replaced = self.by_slot.get(&slot).cloned();
+        if let Some(replaced) = replaced.as_ref() {
+            let fee = self.entries[replaced].transaction.fee;
+            if transaction.fee < fee.saturating_add(self.limits.replacement_fee_bump) {
+                return Err(LedgerError::ReplacementUnderpriced)
+            }
+        } else if self.per_sender.get(&transaction.signer).copied().unwrap_or_default() >= self.limits.max_per_sender {
+            return Err(LedgerError::TooManyPending)
+        }
+        let size = serialize_data(&transaction).len();
+        let evicted = self.to_evict(transaction.fee, size, replaced.as_ref())?;
+        if let Some(replaced) = replaced {
+            debug!("transaction {} replaced by {}", print_bytes(&replaced), print_bytes(&id));
+            self.remove(&replaced);
+        }
+        for id in evicted {
+            debug!("transaction evicted from the pool: {}", print_bytes(&id));
+            self.remove(&id);
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.by_priority.insert((transaction.fee, Reverse(seq), id.clone()));
+        self.by_arrival.insert((added, seq), id.clone());
+        *self.per_sender.entry(transaction.signer.clone()).or_default() += 1;
+        self.by_slot.insert(slot, id.clone());
+        self.bytes += size;
+        self.entries.insert(id, Entry { transaction, size, added, seq });
+        Ok(())
+    }
+
+    /// Removes up to `count` transactions with the highest fee, best first
+    pub fn pop_best(&mut self, count: usize) -> Vec<Transaction> {
+        let best = self.by_priority.iter().rev()
+            .take(count)
+            .map(|(_, _, id)| (id.clone(), self.entries[id].added))
+            .collect::<Vec<_>>();
+        self.taken = best.iter().cloned().collect();
+        best.iter().filter_map(|(id, _)| self.remove(id)).collect()
+    }
+
+    /// Returns transactions taken for a block that has not been added to the chain, the ones included
+    /// in the chain meanwhile stay out. Returned transactions keep the time they were first added.
+    /// Pending transactions are revalidated against `accounts` and `assets`, the state of the best block.
+    /// Returns count of dropped pending transactions
+    pub fn restore(&mut self, transactions: Vec<Transaction>, accounts: Accounts, assets: Assets) -> usize {
+        let mut taken = std::mem::take(&mut self.taken);
+        for transaction in transactions {
+            let id = transaction.id();
+            let added = taken.remove(&id).unwrap_or_else(Instant::now);
+            if let Err(e) = self.add_at(transaction, added) {
+                debug!("transaction {} is not returned to the pool: {}", print_bytes(&id), e);
+            }
+        }
+        self.revalidate(accounts, assets)
+    }
+
+    /// Removes transactions of a block added to the chain and the ones conflicting with them,
+    /// `accounts` and `assets` are the state after the block. Returns count of removed transactions
+    pub fn block_connected(&mut self, block: &Block, accounts: Accounts, assets: Assets) -> usize {
+        let mut removed = 0;
+        for transaction in block.transactions.iter() {
+            let id = transaction.id();
+            self.taken.remove(&id);
+            // the included transaction itself, its replacement or the one it has replaced
+            let pending = self.by_slot.get(&(transaction.signer.clone(), transaction.nonce)).cloned();
+            if pending.is_some_and(|pending| self.remove(&pending).is_some()) {
+                removed += 1;
+            }
+            self.included.insert(id);
+        }
+        removed + self.revalidate(accounts, assets)
+    }
+
+    /// Drops transactions pending for `ttl` or longer at `now`, returns their count
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let expired = self.by_arrival.values()
+            .take_while(|id| now.saturating_duration_since(self.entries[*id].added) >= self.limits.ttl)
+            .cloned()
+            .collect::<Vec<_>>();
+        for id in expired.iter() {
+            debug!("pending transaction expired: {}", print_bytes(id));
+            self.remove(id);
+        }
+        expired.len()
+    }
+
+    /// Drops transactions that can no longer be executed on the state of the best block,
+    /// they are checked in order they would be mined. Returns count of dropped transactions
+    fn revalidate(&mut self, mut accounts: Accounts, mut assets: Assets) -> usize {
+        let invalid = self.by_priority.iter().rev()
+            .filter(|(_, _, id)| execute(&self.entries[id].transaction, &mut accounts, &mut assets).is_err())
+            .map(|(_, _, id)| id.clone())
+            .collect::<Vec<_>>();
+        for id in invalid.iter() {
+            warn!("pending transaction is no longer valid: {}", print_bytes(id));
+            self.remove(id);
+        }
+        invalid.len()
+    }
+
+    /// Transactions to evict to make room for a transaction of `size` paying `fee` in place of `replaced`,
+    /// cheapest and latest first. Only transactions paying less than `fee` are evicted
+    fn to_evict(&self, fee: u32, size: usize, replaced: Option<&Hash>) -> Result<Vec<Hash>, LedgerError> {
+        if size > self.limits.max_bytes {
+            return Err(LedgerError::MempoolFull)
+        }
+        let mut count = self.entries.len() + 1;
+        let mut bytes = self.bytes + size;
+        if let Some(replaced) = replaced {
+            count -= 1;
+            bytes -= self.entries[replaced].size;
+        }
+        let mut evicted = Vec::new();
+        for (evicted_fee, _, id) in self.by_priority.iter().filter(|(_, _, id)| Some(id) != replaced) {
+            if count <= self.limits.max_transactions && bytes <= self.limits.max_bytes {
+                break
+            }
+            if *evicted_fee >= fee {
+                return Err(LedgerError::MempoolFull)
+            }
+            count -= 1;
+            bytes -= self.entries[id].size;
+            evicted.push(id.clone());
+        }
+        if count > self.limits.max_transactions {
+            return Err(LedgerError::MempoolFull)
+        }
+        Ok(evicted)
+    }
+
+    /// Transaction is pending or has been included in the chain recently
+    fn is_known(&self, id: &Hash) -> bool {
+        self.entries.contains_key(id) || self.included.contains(id)
+    }
+
+    fn remove(&mut self, id: &Hash) -> Option<Transaction> {
+        let entry = self.entries.remove(id)?;
+        self.by_priority.remove(&(entry.transaction.fee, Reverse(entry.seq), id.clone()));
+        self.by_arrival.remove(&(entry.added, entry.seq));
+        self.by_slot.remove(&(entry.transaction.signer.clone(), entry.transaction.nonce));
+        if let Some(count) = self.per_sender.get_mut(&entry.transaction.signer) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_sender.remove(&entry.transaction.signer);
+            }
+        }
+        self.bytes -= entry.size;
+        Some(entry.transaction)
+    }
+}
+
+/// Executes commands of `transaction`, a failed transaction leaves `accounts` and `assets` untouched
+fn execute(transaction: &Transaction, accounts: &mut Accounts, assets: &mut Assets) -> Result<(), LedgerError> {
+    let mut undo = Undo::default();
+    let executed = transaction.commands.iter()
+        .try_for_each(|command| command.execute_with_undo(accounts, assets, &mut undo));
+    if executed.is_err() {
+        undo.rollback(accounts, assets);
+    }
+    executed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use errors::LedgerError;
+    use state::{Accounts, Assets, Asset, Block, Command, Transaction};
+    use crate::mempool::{Mempool, MempoolLimits};
+
+    #[test]
+    fn duplicates_rejected_and_best_fee_popped_first() {
+        let mut pool = Mempool::default();
+        for (signer, fee) in [(1, 5), (2, 9), (3, 5), (4, 7)] {
+            pool.add(transaction(signer, fee)).unwrap();
+        }
+        assert_eq!(pool.add(transaction(2, 9)).err(), Some(LedgerError::DuplicateTransaction));
+        let fees = pool.pop_best(3).iter().map(|t| (t.signer[0], t.fee)).collect::<Vec<_>>();
+        // transactions with the same fee go in order of arrival
+        assert_eq!(fees, vec![(2, 9), (4, 7), (1, 5)]);
+        assert_eq!(pool.len(), 1);
+        assert!(pending(&pool, &transaction(3, 5)));
+    }
+
+    #[test]
+    fn lowest_fee_evicted_when_full() {
+        let mut pool = Mempool::new(MempoolLimits { max_transactions: 2, ..Default::default() });
+        pool.add(transaction(1, 5)).unwrap();
+        pool.add(transaction(2, 3)).unwrap();
+        assert_eq!(pool.add(transaction(3, 3)).err(), Some(LedgerError::MempoolFull));
+        pool.add(transaction(3, 4)).unwrap();
+        assert!(!pending(&pool, &transaction(2, 3)));
+        assert_eq!(pool.len(), 2);
+        // size limit evicts as many transactions as needed
+        let size = network::serialize_data(transaction(1, 5)).len();
+        let mut pool = Mempool::new(MempoolLimits { max_bytes: 3 * size, ..Default::default() });
+        for signer in 1..=3 {
+            pool.add(transaction(signer, 1)).unwrap();
+        }
+        let mut big = transaction(4, 2);
+        big.signature = vec![0; size];
+        pool.add(big.clone()).unwrap();
+        assert_eq!(pool.len(), 2);
+        big.nonce += 1;
+        big.signature = vec![0; 3 * size];
+        assert_eq!(pool.add(big).err(), Some(LedgerError::MempoolFull));
+    }
+
+    #[test]
+    fn pending_transactions_of_sender_limited() {
+        let mut pool = Mempool::new(MempoolLimits { max_per_sender: 2, ..Default::default() });
+        pool.add(transaction(1, 1)).unwrap();
+        pool.add(transaction(1, 2)).unwrap();
+        assert_eq!(pool.add(transaction(1, 3)).err(), Some(LedgerError::TooManyPending));
+        pool.add(transaction(2, 3)).unwrap();
+        pool.pop_best(1);
+        pool.pop_best(1);
+        pool.add(transaction(1, 3)).unwrap();
+    }
+
+    #[test]
+    fn pending_transaction_replaced_by_higher_fee() {
+        let mut pool = Mempool::new(MempoolLimits { replacement_fee_bump: 2, max_per_sender: 1, ..Default::default() });
+        let original = transaction(1, 5);
+        pool.add(original.clone()).unwrap();
+        // the replacement takes the place of the original, the signer limit does not apply
+        let replacement = Transaction { nonce: original.nonce, ..transaction(1, 7) };
+        pool.add(replacement.clone()).unwrap();
+        assert_eq!(pool.len(), 1);
+        assert!(!pending(&pool, &original) && pending(&pool, &replacement));
+        // the original is underpriced against its replacement
+        assert_eq!(pool.add(original).err(), Some(LedgerError::ReplacementUnderpriced));
+        assert_eq!(pool.pop_best(1).iter().map(|t| t.fee).collect::<Vec<_>>(), vec![7]);
+    }
+
+    #[test]
+    fn replacement_with_insufficient_bump_rejected() {
+        let mut pool = Mempool::new(MempoolLimits { replacement_fee_bump: 2, ..Default::default() });
+        let original = transaction(1, 5);
+        pool.add(original.clone()).unwrap();
+        let replacement = Transaction { nonce: original.nonce, ..transaction(1, 6) };
+        assert_eq!(pool.add(replacement.clone()).err(), Some(LedgerError::ReplacementUnderpriced));
+        assert!(pending(&pool, &original) && !pending(&pool, &replacement));
+        // a block including the original takes the other transaction of its slot out too
+        let replacement = Transaction { nonce: original.nonce, ..transaction(1, 8) };
+        pool.add(replacement).unwrap();
+        let block = Block { transactions: vec![original], ..Default::default() };
+        assert_eq!(pool.block_connected(&block, Accounts::new(), Assets::new()), 1);
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn old_transactions_expire() {
+        let ttl = Duration::from_secs(60);
+        let mut pool = Mempool::new(MempoolLimits { ttl, ..Default::default() });
+        pool.add(transaction(1, 1)).unwrap();
+        pool.add(transaction(2, 1)).unwrap();
+        assert_eq!(pool.expire(Instant::now()), 0);
+        assert_eq!(pool.expire(Instant::now() + ttl), 2);
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn transactions_revalidated_against_state() {
+        let transfer = |signer, fee, value| Transaction {
+            commands: vec![Command::TransferFunds { account_from_id: 1, account_to_id: 2, value, asset_id: "TEST".to_string() }],
+            ..transaction(signer, fee)
+        };
+        let mut pool = Mempool::default();
+        pool.add(transfer(1, 3, 10)).unwrap();
+        pool.add(transfer(2, 2, 10)).unwrap();
+        pool.add(transfer(3, 1, 100)).unwrap();
+        pool.add(transaction(4, 1)).unwrap();
+        let mut assets = Assets::new();
+        assets.insert((1, "TEST".to_string()), Asset::new_with_value(50));
+        // the first transfer spends the asset, the one after it has nothing to transfer
+        assert_eq!(pool.revalidate(Accounts::new(), assets), 2);
+        assert!(pending(&pool, &transfer(1, 3, 10)));
+        assert!(pending(&pool, &transaction(4, 1)));
+    }
+
+    #[test]
+    fn only_executable_transactions_admitted() {
+        let transfer = Transaction {
+            commands: vec![Command::TransferFunds { account_from_id: 1, account_to_id: 2, value: 60, asset_id: "TEST".to_string() }],
+            ..transaction(1, 1)
+        };
+        let mut assets = Assets::new();
+        assets.insert((1, "TEST".to_string()), Asset::new_with_value(50));
+        let mut pool = Mempool::default();
+        assert_eq!(pool.admit(transfer, Accounts::new(), assets.clone()).err(), Some(LedgerError::InsufficientFunds));
+        pool.admit(transaction(2, 1), Accounts::new(), assets.clone()).unwrap();
+        assert_eq!(pool.admit(transaction(2, 1), Accounts::new(), assets).err(), Some(LedgerError::DuplicateTransaction));
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn block_transactions_leave_the_pool() {
+        let spend = |signer, fee| Transaction {
+            commands: vec![Command::TransferFunds { account_from_id: 1, account_to_id: 2, value: 10, asset_id: "TEST".to_string() }],
+            ..transaction(signer, fee)
+        };
+        let mut pool = Mempool::default();
+        for transaction in [transaction(1, 1), transaction(2, 1), spend(3, 1)] {
+            pool.add(transaction).unwrap();
+        }
+        // a block of another node spends the asset the pending transfer spends
+        let block = Block { transactions: vec![transaction(1, 1), spend(4, 1)], ..Default::default() };
+        assert_eq!(pool.block_connected(&block, Accounts::new(), Assets::new()), 2);
+        assert_eq!(pool.len(), 1);
+        assert!(pending(&pool, &transaction(2, 1)));
+        // included transactions are not pending again
+        assert_eq!(pool.add(transaction(1, 1)).err(), Some(LedgerError::DuplicateTransaction));
+        // transactions of a block that was not added go back, except included and invalid ones
+        let taken = pool.pop_best(1);
+        let restored = [taken, vec![transaction(1, 1), transaction(5, 1), spend(6, 1)]].concat();
+        assert_eq!(pool.restore(restored, Accounts::new(), Assets::new()), 1);
+        assert_eq!(pool.len(), 2);
+        assert!(pending(&pool, &transaction(2, 1)) && pending(&pool, &transaction(5, 1)));
+    }
+
+    #[test]
+    fn restored_transaction_keeps_its_deadline() {
+        let ttl = Duration::from_secs(60);
+        let mut pool = Mempool::new(MempoolLimits { ttl, ..Default::default() });
+        let added = Instant::now().checked_sub(Duration::from_secs(30)).unwrap();
+        pool.add_at(transaction(1, 1), added).unwrap();
+        pool.add(transaction(2, 1)).unwrap();
+        let taken = pool.pop_best(2);
+        assert_eq!(pool.restore(taken, Accounts::new(), Assets::new()), 0);
+        assert_eq!(pool.len(), 2);
+        // the restored transaction expires at its original deadline, not `ttl` after the restore
+        assert_eq!(pool.expire(added + ttl), 1);
+        assert!(!pending(&pool, &transaction(1, 1)));
+        assert!(pending(&pool, &transaction(2, 1)));
+    }
+
+    fn pending(pool: &Mempool, transaction: &Transaction) -> bool {
+        pool.entries.contains_key(&transaction.id())
+    }
+
+    fn transaction(signer: u8, fee: u32) -> Transaction {
+        Transaction {
+            chain_id: "test".to_string(),
+            signer: vec![signer; 32],
+            nonce: fee.into(),
+            fee,
+            commands: vec![Command::CreateAccount { public_key: "12345".to_string() }],
+            signature: vec![signer; 64],
+        }
+    }
+}
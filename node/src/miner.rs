@@ -1,119 +1,160 @@
-use std::collections::BinaryHeap;
 use std::sync::{Arc};
 use tokio::sync::mpsc::{
     channel,
     Receiver as Rx,
     Sender as Tx
 };
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::{Mutex};
 use chrono::{Utc};
 use ursa::keys::{PublicKey, PrivateKey};
 use ursa::signatures::ed25519::Ed25519Sha512;
 use ursa::signatures::SignatureScheme;
 use crypto::Hash;
-use network::Data;
+use errors::LedgerError;
+use network::Message;
 use state::{Block, Transaction};
 use utils::print_bytes;
 use async_trait::async_trait;
-use blake2::Digest;
 use tracing::{debug, error, info, trace, warn};
-use crate::connector::{Connect, Connector};
+use crate::bans::{BanManager, Misbehaviour};
+use crate::connector::{Connect, Connector, Inbound};
+use crate::gossip::{KnownSet, KNOWN_TRANSACTIONS};
+use crate::mempool::{Mempool, MempoolLimits};
 use crate::storage::Storage;
 
+/// Pending transactions of the node, highest fee first
+pub(crate) type TransactionPool = Arc<Mutex<Mempool>>;
+
 #[derive(Debug)]
 pub(crate) struct Miner {
     id: u64,
     public_key: PublicKey,
     private_key: PrivateKey,
-    transaction_pool: Arc<Mutex<BinaryHeap<Transaction>>>,
+    pub(crate) transaction_pool: TransactionPool,
     pub(crate) storage: Arc<Mutex<Storage>>,
-    pub(crate) connector_rx: Arc<Mutex<Option<Rx<Data>>>>,
-    pub(crate) connector_tx: Arc<Mutex<Option<Tx<Data>>>>,
+    /// peers sending blocks rejected by the chain are reported here
+    bans: Option<Arc<std::sync::Mutex<BanManager>>>,
+    pub(crate) connector_rx: Arc<Mutex<Option<Rx<Inbound>>>>,
+    pub(crate) connector_tx: Arc<Mutex<Option<Tx<Message>>>>,
 }
 
 impl Miner {
 
-    pub fn new(id: u64) -> Self {
-        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+    pub fn new(id: u64, public_key: PublicKey, private_key: PrivateKey, storage: Storage) -> Self {
         Self {
             id,
             public_key,
             private_key,
-            transaction_pool: Arc::new(Mutex::new(BinaryHeap::new())),
-            storage: Arc::new(Mutex::new(Storage::new(id))),
+            transaction_pool: Arc::new(Mutex::new(Mempool::default())),
+            storage: Arc::new(Mutex::new(storage)),
+            bans: None,
             connector_rx: Arc::new(Mutex::new(None)),
             connector_tx: Arc::new(Mutex::new(None)),
         }
     }
 
-    pub async fn run(&self) {
+    pub fn with_mempool_limits(mut self, limits: MempoolLimits) -> Self {
+        self.transaction_pool = Arc::new(Mutex::new(Mempool::new(limits)));
+        self
+    }
+
+    pub fn with_bans(mut self, bans: Arc<std::sync::Mutex<BanManager>>) -> Self {
+        self.bans = Some(bans);
+        self
+    }
+
+    /// Validates incoming blocks and collects transactions, mines blocks if `mining` is set
+    pub async fn run(&self, mining: bool) {
         let connector_tx = self.connector_tx.clone();
         let connector_rx = self.connector_rx.clone();
         let storage1 = self.storage.clone();
         let storage2 = self.storage.clone();
         let transaction_pool_1 = self.transaction_pool.clone();
         let transaction_pool_2 = self.transaction_pool.clone();
+        let relay_tx = self.connector_tx.clone();
+        let bans = self.bans.clone();
         let id = self.id;
+        let public_key = self.public_key.clone();
         let private_key = self.private_key.clone();
         tokio::spawn(async move {
             Self::run_listening(
                 id,
                 connector_rx,
+                relay_tx,
                 storage1,
-                transaction_pool_1)
+                transaction_pool_1,
+                bans)
                 .await
         });
+        if !mining {
+            return
+        }
         tokio::spawn(async move {
             Self::run_mining(
                 id,
                 connector_tx,
                 storage2,
+                &public_key,
                 &private_key,
                 transaction_pool_2)
                 .await
         });
     }
 
+    /// Valid transactions seen for the first time are added to the pool, the ones accepted by the pool
+    /// are relayed to peers the same way as added blocks. A waiting submitter gets the result of the admission.
+    /// Transactions of every added block leave the pool, the peer a block rejected by the chain came from is reported
     async fn run_listening(
         id: u64,
-        connector_rx: Arc<Mutex<Option<Rx<Data>>>>,
+        connector_rx: Arc<Mutex<Option<Rx<Inbound>>>>,
+        relay_tx: Arc<Mutex<Option<Tx<Message>>>>,
         storage: Arc<Mutex<Storage>>,
-        transaction_pool: Arc<Mutex<BinaryHeap<Transaction>>>)
+        transaction_pool: TransactionPool,
+        bans: Option<Arc<std::sync::Mutex<BanManager>>>)
     {
+        let chain_id = storage.lock().await.chain_spec().chain_id.clone();
+        let mut known_transactions = KnownSet::new(KNOWN_TRANSACTIONS);
         loop {
             let connector_rx = connector_rx.clone();
             let mut connector_rx = connector_rx.lock().await;
             let connector_rx = connector_rx.as_mut().unwrap();
-            while let Some(data) = connector_rx.recv().await {
+            while let Some(Inbound { data, origin, admitted }) = connector_rx.recv().await {
                 match data {
                     // receive block from other node
-                    Data::Block(block) => {
+                    Message::Block(block) => {
                         debug!("miner id: {}", id);
                         info!("block has been received from another node, \
                         block id: {}, block hash: {}", &block.id, print_bytes(&block.hash));
                         let storage = storage.clone();
                         let mut storage = storage.lock().await;
-                        let added_block = storage.try_add_block(block);
-                        if added_block.is_err() {
-                            println!("error while adding block: {}", added_block.err().unwrap())
-                        }
-                    }
-                    // receive transaction from client
-                    Data::Transaction(transaction) => {
-                        let mut transactions;
-                        loop {
-                            match transaction_pool.try_lock() {
-                                Ok(mutex_guard) => {
-                                    transactions = mutex_guard;
-                                    break;
-                                }
-                                Err(_) => {
-                                    tokio::time::sleep(Duration::from_secs(3)).await;
+                        let added_block = storage.try_add_block(block.clone());
+                        if let Err(e) = added_block {
+                            drop(storage);
+                            error!("error while adding block: {}", e);
+                            // a block of another branch may be valid, every other failure is the fault of its sender
+                            if let (Some(ip), Some(bans)) = (origin, bans.as_ref()) {
+                                if e != LedgerError::StaleBlock {
+                                    bans.lock().unwrap().misbehaved(ip, Misbehaviour::InvalidBlock);
                                 }
                             }
+                        } else {
+                            Self::block_connected(&storage, &transaction_pool, &block).await;
+                            drop(storage);
+                            // peers of this node may not know the block yet
+                            Self::relay(&relay_tx, Message::Block(block)).await;
+                        }
+                    }
+                    // receive transaction from client or other node
+                    Message::Transaction(transaction) => {
+                        let admission = Self::admit(
+                            &chain_id, &mut known_transactions, &storage, &transaction_pool, &transaction).await;
+                        if let Some(admitted) = admitted {
+                            let _ = admitted.send(admission.clone());
+                        }
+                        if admission.is_ok() {
+                            Self::relay(&relay_tx, Message::Transaction(transaction)).await;
                         }
-                        transactions.push(transaction);
                     }
                     _ => { error!("received wrong data type") }
                 }
@@ -121,12 +162,53 @@ impl Miner {
         }
     }
 
+    /// Verified transaction seen for the first time goes to the pool if it can be executed on the state
+    /// of the best block. A rejected transaction may be admitted later, e.g. once the pool has room
+    async fn admit(
+        chain_id: &str,
+        known_transactions: &mut KnownSet<Hash>,
+        storage: &Mutex<Storage>,
+        transaction_pool: &TransactionPool,
+        transaction: &Transaction)
+        -> Result<(), LedgerError>
+    {
+        if let Err(e) = transaction.verify(chain_id) {
+            error!("transaction rejected: {}", e);
+            return Err(e)
+        }
+        if known_transactions.contains(&transaction.id()) {
+            trace!("transaction is already known: {}", print_bytes(&transaction.id()));
+            return Err(LedgerError::DuplicateTransaction)
+        }
+        let (accounts, assets) = storage.lock().await.state();
+        let mut transactions;
+        loop {
+            match transaction_pool.try_lock() {
+                Ok(mutex_guard) => {
+                    transactions = mutex_guard;
+                    break;
+                }
+                Err(_) => {
+                    tokio::time::sleep(Duration::from_secs(3)).await;
+                }
+            }
+        }
+        transactions.expire(Instant::now());
+        if let Err(e) = transactions.admit(transaction.clone(), accounts, assets) {
+            warn!("transaction is not added to the pool: {}", e);
+            return Err(e)
+        }
+        known_transactions.insert(transaction.id());
+        Ok(())
+    }
+
     async fn run_mining(
         id: u64,
-        connector_tx: Arc<Mutex<Option<Tx<Data>>>>,
+        connector_tx: Arc<Mutex<Option<Tx<Message>>>>,
         storage: Arc<Mutex<Storage>>,
+        public_key: &PublicKey,
         private_key: &PrivateKey,
-        transaction_pool: Arc<Mutex<BinaryHeap<Transaction>>>
+        transaction_pool: TransactionPool
     ) {
         // mine block from received transactions
         loop {
@@ -150,26 +232,25 @@ impl Miner {
                     previous_block_hash = Some(p_b.hash.clone());
                 }
             };
+            let difficulty = storage_lock.chain_spec().consensus.difficulty;
+            let limits = storage_lock.chain_spec().limits.clone();
             drop(storage_lock);
+            let public_key = public_key.clone();
             let private_key = private_key.clone();
-            let transaction_pool = transaction_pool.clone();
+            let pending = transaction_pool.clone();
             let ready_to_mine = async move {
                 let mut transactions;
                 loop {
-                    match transaction_pool.try_lock() {
+                    match pending.try_lock() {
                         Ok(mutex_guard) => {
                             transactions = mutex_guard;
-                            if transactions.len() < 10 {
+                            transactions.expire(Instant::now());
+                            if transactions.len() < limits.min_transactions {
                                 drop(transactions);
                                 tokio::time::sleep(Duration::from_secs(5)).await;
                                 continue
                             };
-                            let mut ready_transactions = Vec::with_capacity(10);
-                            for _ in 0..10 {
-                                let transaction = transactions.pop().unwrap();
-                                ready_transactions.push(transaction);
-                            }
-                            return ready_transactions
+                            return transactions.pop_best(limits.max_transactions)
                         }
                         Err(_) => {
                             tokio::time::sleep(Duration::from_secs(2)).await;
@@ -181,8 +262,9 @@ impl Miner {
             debug!("mining block started, miner_id: {}", id);
             let block = tokio::task::spawn_blocking(move || {
                 Self::mine_block(
+                    public_key,
                     private_key,
-                    2,
+                    difficulty,
                     previous_block_hash,
                     previous_block_id,
                     ready_to_mine)
@@ -192,16 +274,25 @@ impl Miner {
             info!("miner_id: {}, block has been mined, block: \n {}", id, &block);
             let mut storage = storage.lock().await;
             let added_block = storage.try_add_block(block.clone());
+            match added_block.as_ref() {
+                Ok(()) => Self::block_connected(&storage, &transaction_pool, &block).await,
+                // a block of another node has been added meanwhile, the transactions may be mined again
+                Err(LedgerError::StaleBlock) => {
+                    warn!("miner_id: {id}, self-mined block is stale, its transactions go back to the pool");
+                    let (accounts, assets) = storage.state();
+                    transaction_pool.lock().await.restore(block.transactions.clone(), accounts, assets);
+                }
+                Err(err) => {
+                    error!("miner_id: {id}, failed to add self-mined block, its transactions are dropped: {}", err);
+                }
+            }
             drop(storage);
-            if added_block.is_err() {
-                let err = added_block.err().unwrap();
-                error!("miner_id: {id}, failed to add self-mined block: {}", err)
-            } else {
+            if added_block.is_ok() {
 
                 let connector_tx = connector_tx.clone();
                 let mut connector_tx = connector_tx.lock().await;
                 let connector_tx = connector_tx.as_mut().unwrap();
-                let data = Data::Block(block);
+                let data = Message::Block(block);
                 let sent_block = connector_tx.send(data).await;
                 if sent_block.is_err() {
                     error!("error while sending block to connector: {}", sent_block.err().unwrap())
@@ -210,15 +301,29 @@ impl Miner {
         }
     }
 
-    /// FOR TEST ONLY
-    async fn add_block_to_storage(&mut self, block: Block) {
-        let mut storage = self.storage.lock().await;
-        if let Err(_) = storage.try_add_block(block) {
-           error!("could not add block to storage")
-        };
+    /// Drops pending transactions included in `block` added to the chain, conflicting with it or expired
+    async fn block_connected(storage: &Storage, transaction_pool: &TransactionPool, block: &Block) {
+        let (accounts, assets) = storage.state();
+        let mut transactions = transaction_pool.lock().await;
+        let dropped = transactions.expire(Instant::now()) + transactions.block_connected(block, accounts, assets);
+        if dropped > 0 {
+            debug!("{} pending transactions dropped, {} left", dropped, transactions.len());
+        }
+    }
+
+    /// Relayed data is dropped rather than waited for when the connector is busy,
+    /// so that the miner never blocks the data flowing back to it
+    async fn relay(relay_tx: &Arc<Mutex<Option<Tx<Message>>>>, data: Message) {
+        let relay_tx = relay_tx.lock().await;
+        if let Some(relay_tx) = relay_tx.as_ref() {
+            if let Err(e) = relay_tx.try_send(data) {
+                warn!("data is not relayed to peers: {}", e);
+            }
+        }
     }
 
     fn mine_block(
+        public_key: PublicKey,
         private_key: PrivateKey,
         target_hash_zero_count: usize,
         previous_block_hash: Option<Hash>,
@@ -226,56 +331,39 @@ impl Miner {
         transactions: Vec<Transaction>)
         -> Block
     {
-        let mut id = 0;
-        if previous_block_id.is_some() {
-            id = previous_block_id.unwrap() + 1;
-        };
+        let id = previous_block_id.map_or(0, |id| id + 1);
         let timestamp = Utc::now().timestamp();
-        let signature = Ed25519Sha512::new()
-            .sign(format!("{:?}", &transactions).as_bytes(), &private_key)
-            .unwrap();
-        let mut nonce = 0;
-        let mut hash = vec![];
-        let mut block = Default::default();
-        let start = Utc::now();
-        while !is_hash_valid(&hash, target_hash_zero_count) {  // TODO concurrent calculation
-            let mut hasher = crypto::hasher();
-            block = Block {
-                id,
-                timestamp,
-                nonce,
-                signature: signature.clone(),
-                hash: vec![],
-                previous_block_hash: previous_block_hash.clone(),
-                transactions: transactions.clone()
-            };
-            let hash_data = bincode::serialize(&block).unwrap();
-            hasher.update(hash_data.as_slice());
-            let h = hasher.finalize();
-            hash.clear();
-            hash.extend_from_slice(&h);
-            nonce += 1;
+        let mut block = Block {
+            id,
+            timestamp,
+            nonce: 0,
+            producer: public_key.0.clone(),
+            signature: vec![],
+            hash: vec![],
+            previous_block_hash,
+            transactions
+        };
+        let mut hash = block.compute_hash();
+        while !crypto::is_hash_valid(&hash, target_hash_zero_count) {  // TODO concurrent calculation
+            block.nonce += 1;
+            hash = block.compute_hash();
         };
-        let finish = Utc::now();
-        //info!("valid block hash has been found, total time = {} sec", finish.second() - start.second());
-        info!("hash: {}, nonce: {}", print_bytes(&hash), &nonce);
-        block.nonce = nonce;
+        info!("hash: {}, nonce: {}", print_bytes(&hash), &block.nonce);
+        block.signature = Ed25519Sha512::new()
+            .sign(&hash, &private_key)
+            .unwrap();
         block.hash = hash;
         info!("block: {}", &block);
         block
     }
 }
 
-fn is_hash_valid(hash: &Hash, target_hash_zero_count: usize) -> bool {
-    hash.iter().take_while(|n| **n == 0u8).count() >= target_hash_zero_count
-}
-
 #[async_trait]
 impl Connect for Miner {
     async fn connect(&mut self, connector: Arc<Mutex<Connector>>) {
         let mut connector = connector.lock().await;
-        let (tx1, rx1): (Tx<Data>, Rx<Data>) = channel(10);
-        let (tx2, rx2): (Tx<Data>, Rx<Data>) = channel(10);
+        let (tx1, rx1): (Tx<Inbound>, Rx<Inbound>) = channel(10);
+        let (tx2, rx2): (Tx<Message>, Rx<Message>) = channel(10);
         self.connector_rx = Arc::new(Mutex::new(Some(rx1)));
         connector.miner_tx = Arc::new(Mutex::new(Some(tx1)));
         self.connector_tx = Arc::new(Mutex::new(Some(tx2)));
@@ -294,15 +382,23 @@ impl Connect for Miner {
 
 #[cfg(test)]
 mod tests {
+    use std::net::IpAddr;
     use std::sync::Arc;
+    use std::time::Duration;
     use rand::prelude::*;
     use chrono::Utc;
     use crypto::hash;
+    use network::Message;
     use state::{Block, Command, Transaction};
+    use state::chain_spec::ChainSpec;
     use ursa::signatures::ed25519::Ed25519Sha512;
     use ursa::signatures::SignatureScheme;
-    use utils::{LOCAL_HOST, print_bytes};
+    use utils::print_bytes;
+    use crate::bans::BanManager;
+    use crate::connector::Inbound;
+    use crate::mempool::MempoolLimits;
     use crate::miner::{ Miner};
+    use crate::storage::Storage;
     use tracing::info;
 
     #[test]
@@ -340,40 +436,176 @@ mod tests {
 
     #[tokio::test]
     async fn mine_block_succeed() {
-        let miner = Miner::new(1);
-        miner.run().await;
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+        let chain_spec = ChainSpec::from_toml("chain_id = \"test\"").unwrap();
+        let storage = Storage::new(1, chain_spec).unwrap();
+        let miner = Miner::new(1, public_key, private_key, storage);
+        miner.run(true).await;
         let previous_block_transactions = vec![generate_transaction()];
         let previous_block = generate_block(2, previous_block_transactions);
         let current_block_transactions = vec![generate_transaction()];
+        let public_key = miner.public_key.clone();
         let private_key = miner.private_key.clone();
         let block = Miner::mine_block(
+            public_key,
             private_key,
             2,
             Some(previous_block.hash),
             Some(previous_block.id),
             current_block_transactions);
-        assert!(&block.hash.starts_with(&[0, 0]))
+        assert!(&block.hash.starts_with(&[0, 0]));
+        assert_eq!(block.hash, block.compute_hash());
+        assert_eq!(block.producer, miner.public_key.0);
+        assert!(crypto::verify_signature(&block.hash, &block.signature, &block.producer))
     }
 
-    fn generate_block(nonce: u32, transactions: Vec<Transaction>) -> Block {
-        let (_, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+    #[tokio::test]
+    async fn new_valid_transactions_relayed_once() {
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+        let chain_spec = ChainSpec::from_toml("chain_id = \"test\"").unwrap();
+        let storage = Storage::new(1, chain_spec).unwrap();
+        let mut miner = Miner::new(1, public_key, private_key, storage);
+        let (incoming_tx, incoming_rx) = tokio::sync::mpsc::channel(10);
+        let (relay_tx, mut relay_rx) = tokio::sync::mpsc::channel(10);
+        miner.connector_rx = Arc::new(tokio::sync::Mutex::new(Some(incoming_rx)));
+        miner.connector_tx = Arc::new(tokio::sync::Mutex::new(Some(relay_tx)));
+        miner.run(false).await;
+        let transaction = generate_transaction();
+        let mut forged = generate_transaction();
+        forged.fee += 1;
+        for data in [transaction.clone(), forged, transaction.clone()] {
+            incoming_tx.send(Message::Transaction(data).into()).await.unwrap();
+        }
+        let relayed = tokio::time::timeout(Duration::from_secs(5), relay_rx.recv()).await.unwrap();
+        assert!(matches!(relayed, Some(Message::Transaction(t)) if t.id() == transaction.id()));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(relay_rx.try_recv().is_err());
+        assert_eq!(miner.transaction_pool.lock().await.len(), 1);
+    }
 
-        let signature = Ed25519Sha512::new()
-            .sign(format!("{:?}", &transactions).as_bytes(), &private_key)
-            .unwrap();
+    #[tokio::test]
+    async fn received_block_purges_pool() {
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+        let chain_spec = ChainSpec::from_toml("chain_id = \"test\"").unwrap();
+        let genesis = chain_spec.genesis_block().unwrap();
+        let storage = Storage::new(1, chain_spec).unwrap();
+        let mut miner = Miner::new(1, public_key.clone(), private_key.clone(), storage);
+        let (incoming_tx, incoming_rx) = tokio::sync::mpsc::channel(10);
+        let (relay_tx, mut relay_rx) = tokio::sync::mpsc::channel(10);
+        miner.connector_rx = Arc::new(tokio::sync::Mutex::new(Some(incoming_rx)));
+        miner.connector_tx = Arc::new(tokio::sync::Mutex::new(Some(relay_tx)));
+        miner.run(false).await;
+        let (included, pending) = (generate_transaction(), generate_transaction());
+        for transaction in [included.clone(), pending.clone()] {
+            incoming_tx.send(Message::Transaction(transaction).into()).await.unwrap();
+            relay_rx.recv().await.unwrap();
+        }
+        let block = Miner::mine_block(public_key, private_key, 2, Some(genesis.hash), Some(genesis.id), vec![included]);
+        incoming_tx.send(Message::Block(block).into()).await.unwrap();
+        assert!(matches!(relay_rx.recv().await, Some(Message::Block(_))));
+        let pool = miner.transaction_pool.lock().await;
+        assert_eq!(pool.iter().map(Transaction::id).collect::<Vec<_>>(), vec![pending.id()]);
+    }
+
+    #[tokio::test]
+    async fn transaction_rejected_by_pool_admitted_later() {
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+        let chain_spec = ChainSpec::from_toml("chain_id = \"test\"").unwrap();
+        let genesis = chain_spec.genesis_block().unwrap();
+        let storage = Storage::new(1, chain_spec).unwrap();
+        let mut miner = Miner::new(1, public_key.clone(), private_key.clone(), storage)
+            .with_mempool_limits(MempoolLimits { max_transactions: 1, ..Default::default() });
+        let (incoming_tx, incoming_rx) = tokio::sync::mpsc::channel(10);
+        let (relay_tx, mut relay_rx) = tokio::sync::mpsc::channel(10);
+        miner.connector_rx = Arc::new(tokio::sync::Mutex::new(Some(incoming_rx)));
+        miner.connector_tx = Arc::new(tokio::sync::Mutex::new(Some(relay_tx)));
+        miner.run(false).await;
+        let (included, rejected) = (generate_transaction(), generate_transaction());
+        for transaction in [included.clone(), rejected.clone()] {
+            incoming_tx.send(Message::Transaction(transaction).into()).await.unwrap();
+        }
+        assert!(matches!(relay_rx.recv().await, Some(Message::Transaction(t)) if t.id() == included.id()));
+        let block = Miner::mine_block(public_key, private_key, 2, Some(genesis.hash), Some(genesis.id), vec![included]);
+        incoming_tx.send(Message::Block(block).into()).await.unwrap();
+        assert!(matches!(relay_rx.recv().await, Some(Message::Block(_))));
+        incoming_tx.send(Message::Transaction(rejected.clone()).into()).await.unwrap();
+        let relayed = tokio::time::timeout(Duration::from_secs(5), relay_rx.recv()).await.unwrap();
+        assert!(matches!(relayed, Some(Message::Transaction(t)) if t.id() == rejected.id()));
+    }
+
+    #[tokio::test]
+    async fn replacement_transaction_relayed() {
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+        let chain_spec = ChainSpec::from_toml("chain_id = \"test\"").unwrap();
+        let storage = Storage::new(1, chain_spec).unwrap();
+        let mut miner = Miner::new(1, public_key, private_key, storage);
+        let (incoming_tx, incoming_rx) = tokio::sync::mpsc::channel(10);
+        let (relay_tx, mut relay_rx) = tokio::sync::mpsc::channel(10);
+        miner.connector_rx = Arc::new(tokio::sync::Mutex::new(Some(incoming_rx)));
+        miner.connector_tx = Arc::new(tokio::sync::Mutex::new(Some(relay_tx)));
+        miner.run(false).await;
+        let (signer, secret) = crypto::generate_keypair();
+        let create = |fee, key: &str| {
+            let command = Command::CreateAccount { public_key: key.to_string() };
+            Transaction::new_signed("test", 0, fee, vec![command], &signer, &secret).unwrap()
+        };
+        let (original, replacement, underpriced) = (create(1, "1"), create(2, "2"), create(2, "3"));
+        for transaction in [original, replacement.clone(), underpriced] {
+            incoming_tx.send(Message::Transaction(transaction).into()).await.unwrap();
+        }
+        relay_rx.recv().await.unwrap();
+        let relayed = tokio::time::timeout(Duration::from_secs(5), relay_rx.recv()).await.unwrap();
+        assert!(matches!(relayed, Some(Message::Transaction(t)) if t.id() == replacement.id()));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(relay_rx.try_recv().is_err());
+        let pool = miner.transaction_pool.lock().await;
+        assert_eq!(pool.iter().map(Transaction::id).collect::<Vec<_>>(), vec![replacement.id()]);
+    }
+
+    #[tokio::test]
+    async fn sender_of_rejected_block_reported() {
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+        let chain_spec = ChainSpec::from_toml("chain_id = \"test\"").unwrap();
+        let genesis = chain_spec.genesis_block().unwrap();
+        let storage = Storage::new(1, chain_spec).unwrap();
+        let bans = Arc::new(std::sync::Mutex::new(
+            BanManager::new(50, Duration::from_secs(60), &tempfile::tempdir().unwrap().keep())));
+        let mut miner = Miner::new(1, public_key.clone(), private_key.clone(), storage).with_bans(bans.clone());
+        let (incoming_tx, incoming_rx) = tokio::sync::mpsc::channel(10);
+        let (relay_tx, _relay_rx) = tokio::sync::mpsc::channel(10);
+        miner.connector_rx = Arc::new(tokio::sync::Mutex::new(Some(incoming_rx)));
+        miner.connector_tx = Arc::new(tokio::sync::Mutex::new(Some(relay_tx)));
+        miner.run(false).await;
+        let (stale_peer, invalid_peer) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        let stale = Miner::mine_block(public_key.clone(), private_key.clone(), 2, Some(vec![1; 32]), Some(0), vec![]);
+        incoming_tx.send(Inbound::from_peer(Message::Block(stale), stale_peer)).await.unwrap();
+        let (signer, secret) = crypto::generate_keypair();
+        let overdraft = Command::TransferFunds { account_from_id: 1, account_to_id: 2, value: 1, asset_id: "TEST".to_string() };
+        let transaction = Transaction::new_signed("test", 0, 1, vec![overdraft], &signer, &secret).unwrap();
+        let invalid = Miner::mine_block(public_key, private_key, 2, Some(genesis.hash), Some(genesis.id), vec![transaction]);
+        incoming_tx.send(Inbound::from_peer(Message::Block(invalid), invalid_peer)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let bans = bans.lock().unwrap();
+        assert!(bans.is_banned(&invalid_peer));
+        assert!(!bans.is_banned(&stale_peer));
+    }
+
+    fn generate_block(nonce: u32, transactions: Vec<Transaction>) -> Block {
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
 
         let mut block = Block {
             id: 7,
             timestamp: Utc::now().timestamp(),
             nonce,
-            signature,
+            producer: public_key.0.clone(),
+            signature: vec![],
             hash: vec![],
             previous_block_hash: Some(String::from("0004f4544324323323").as_bytes().to_vec()),
             transactions,
         };
-        let hash_data = bincode::serialize::<Block>(&block).unwrap();
-        let hash = hash(hash_data.as_slice());
+        let hash = block.compute_hash();
         println!("block hash : {}", print_bytes(&hash));
+        block.signature = Ed25519Sha512::new().sign(&hash, &private_key).unwrap();
         block.hash = hash;
         block
     }
@@ -411,10 +643,8 @@ mod tests {
             n2 -= 1;
         }
 
-        Transaction {
-            fee: 111,
-            commands,
-        }
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+        Transaction::new_signed("test", 0, 111, commands, &public_key.0, &private_key.0).unwrap()
     }
 }
 
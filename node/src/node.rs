@@ -1,47 +1,113 @@
 use std::net::{SocketAddr};
-use std::str::FromStr;
 use std::sync::{Arc};
 //use std::sync::Mutex;
 use std::time::Duration;
 
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
 use tokio::net::TcpListener;
 use tokio::sync::{Mutex};
-use tracing::{debug, error, event, info, Level, span};
+use tracing::{error, event, info, warn, Level};
 
-use network::{Data, serialize_data};
+use errors::LedgerError;
+use network::Message;
 use network::client2node::{RequestType, node_response};
-use state::Block;
+use network::transport::{NoiseConfig, Transport};
+use state::chain_spec::ChainSpec;
 
+use crate::bans::BanManager;
+use crate::config::NodeConfig;
 use crate::connector::{Connect, Connector};
+use crate::gossip::Gossip;
+use crate::keys;
+use crate::mempool::MempoolLimits;
 use crate::miner::Miner;
-use crate::receiver::Receiver;
+use crate::peers::PeerManager;
+use crate::receiver::{InboundLimits, Receiver};
 use crate::sender::Sender;
-
-const LOCAL_HOST: &str = "127.0.0.1:";
+use crate::storage::{block_pages, Storage};
 
 pub struct Node {
     node_id: u64,
     peer_address: SocketAddr,
+    api_address: SocketAddr,
+    /// API connection is closed if its request is not answered in this time
+    api_timeout: Duration,
+    api_transport: Transport,
+    mining: bool,
     receiver: Arc<Mutex<Receiver>>,
     sender: Arc<Mutex<Sender>>,
     miner: Arc<Mutex<Miner>>,
+    bans: Arc<std::sync::Mutex<BanManager>>,
 }
 
 impl Node {
 
-    pub async fn new(node_id: u64, local_port: &str) -> Self {
-        let addr = utils::socket_addr(local_port);
-        Self {
+    /// Node identity is read from the key file in `config.data_dir` (created on the first run),
+    /// encrypted key file requires passphrase in `LEDGER_KEY_PASSPHRASE` environment variable.
+    /// Fails if genesis block built from `chain_spec` does not match its `genesis_hash`,
+    /// or if `genesis_hash` is missing outside of development mode
+    pub async fn new(config: &NodeConfig, chain_spec: ChainSpec) -> Result<Self, LedgerError> {
+        if chain_spec.genesis_hash.is_none() {
+            if !config.dev {
+                error!("chain spec has no genesis_hash, the node could follow any chain with the same id; \
+                        set it or start with `--dev true`");
+                return Err(LedgerError::ChainSpecError)
+            }
+            warn!("chain spec has no genesis_hash, genesis block is not verified (development mode)");
+        }
+        let addr = config.listen_address;
+        let node_id = addr.port() as u64;
+        let passphrase = std::env::var(keys::PASSPHRASE_ENV).ok();
+        let (public_key, private_key) = keys::load_or_create(&config.data_dir, passphrase.as_deref())?;
+        info!("node {} public key: {}", node_id, &public_key);
+        let noise = Transport::Noise(Arc::new(
+            NoiseConfig::new(public_key.0.clone(), private_key.0.clone(), config.trusted_keys()?)));
+        let transport = if config.encryption { noise.clone() } else { Transport::Plain };
+        // API requests go in plaintext only in development mode without encryption
+        let api_transport = if config.encryption || !config.dev { noise } else { Transport::Plain };
+        let mut storage = Storage::new(node_id, chain_spec.clone())?;
+        let handshake = storage.watch_handshake(&public_key.0);
+        let bans = Arc::new(std::sync::Mutex::new(BanManager::new(
+            config.ban_threshold,
+            Duration::from_secs(config.ban_duration),
+            &config.data_dir)));
+        let miner = Miner::new(node_id, public_key, private_key, storage)
+            .with_mempool_limits(MempoolLimits::from(config))
+            .with_bans(bans.clone());
+        let gossip = Arc::new(Gossip::new(miner.transaction_pool.clone()));
+        let receiver = Receiver::new(
+            addr,
+            transport.clone(),
+            handshake.clone(),
+            InboundLimits::from(config),
+            chain_spec.clone(),
+            bans.clone(),
+            gossip.clone(),
+            miner.storage.clone()).await;
+        Ok(Self {
             node_id,
             peer_address: addr,
-            receiver: Arc::new(Mutex::new(Receiver::new(addr).await)),
-            sender: Arc::new(Mutex::new(Sender::new(addr))),
-            miner: Arc::new(Mutex::new(Miner::new(local_port.parse().unwrap()))),
-        }
+            api_address: config.api_address,
+            api_timeout: Duration::from_secs(config.read_timeout),
+            api_transport,
+            mining: config.mining,
+            receiver: Arc::new(Mutex::new(receiver)),
+            sender: Arc::new(Mutex::new(Sender::new(
+                transport,
+                handshake,
+                PeerManager::new(addr, &config.bootstrap_peers, config.target_peers, &config.data_dir),
+                Duration::from_secs(config.peer_exchange_interval),
+                chain_spec,
+                bans.clone(),
+                gossip)
+                .with_ping_interval(Duration::from_secs(config.ping_interval)))),
+            miner: Arc::new(Mutex::new(miner)),
+            bans,
+        })
     }
 
     pub async fn start(&self) {
-        let port = self.peer_address.port();
         let receiver = self.receiver.clone();
         let sender = self.sender.clone();
         let miner = self.miner.clone();
@@ -61,6 +127,7 @@ impl Node {
         miner1.lock().await.connect(c1).await;
         sender1.lock().await.connect(c2).await;
         receiver1.lock().await.connect(c3).await;
+        sender1.lock().await.inbound_tx = receiver1.lock().await.connector_tx.clone();
         connector.lock().await.start().await;
 
         tokio::spawn(async move {
@@ -71,87 +138,140 @@ impl Node {
             let mut sender = sender2.lock().await;
             sender.run().await
         });
+        let mining = self.mining;
         tokio::spawn(async move {
             let miner = miner2.lock().await;
-            miner.run().await;
+            miner.run(mining).await;
         });
 
-        event!(Level::INFO, "node started on 127.0.0.1:{}", port);
+        event!(Level::INFO, "node {} started on {}, mining: {}", self.node_id, self.peer_address, mining);
 
-        Self::listen_api_requests(&self, port).await;
+        Self::listen_api_requests(self).await;
     }
 
-    async fn listen_api_requests(&self, mut port: u16) {
-        port += 10;
-        let addr = String::from(LOCAL_HOST) + port.to_string().as_str();
-        let listener = TcpListener::bind(addr.as_str()).await.unwrap();
-        let miner = self.miner.clone();
+    async fn listen_api_requests(&self) {
+        let addr = self.api_address;
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let storage = self.miner.lock().await.storage.clone();
         info!("listen_api_requests started on {}", &addr);
-        loop {
-            if let Ok((mut socket, _)) = listener.accept().await {
-                if let Err(e) = node_response(&mut socket,
-                              miner.clone(),
-                              |m, r_t| blockchain_data(m, r_t))
-                    .await {
-                    error!("api request error: {}", e);
-                }
-                continue
-            }
-        }
+        serve_api(listener, self.api_transport.clone(), storage, self.bans.clone(), self.api_timeout).await
     }
 }
 
-/// This function requires Option<RequestType> = Some(request_type), otherwise - panic!
-async fn blockchain_data(miner: Arc<Mutex<Miner>>, request_type: Option<RequestType>) -> Vec<u8>
+/// Every API connection is served by its own task, so a slow client does not hold up the others
+async fn serve_api(listener: TcpListener,
+                   transport: Transport,
+                   storage: Arc<Mutex<Storage>>,
+                   bans: Arc<std::sync::Mutex<BanManager>>,
+                   timeout: Duration)
 {
-    let miner = miner.lock().await;
-    let storage = miner.storage.clone();
     loop {
-        match storage.try_lock() {
-            Ok(storage) => {
-                match request_type.unwrap() {
-                    RequestType::Blockchain { height } => {
-                        info!("requested height: {}", height);
-                        let blockchain = storage.get_blockchain_by_ref();
-                        let blockchain_of_required_length = blockchain.iter().rev()
-                            .take(height as usize)
-                            .map(|item| {item.to_owned()})
-                            .collect::<Vec<Block>>();
-                        let data = Data::Blockchain(blockchain_of_required_length);
-                        return serialize_data(data)
-                    }
-                    RequestType::Block { ref hash } => { todo!() }
-                    RequestType::Transaction { ref hash } => { todo!() }
-                }
-            }
-            Err(_) => {
-                debug!("storage is locked yet");
-                tokio::time::sleep(Duration::from_millis(300)).await
+        let Ok((socket, address)) = listener.accept().await else { continue };
+        let transport = transport.clone();
+        let storage = storage.clone();
+        let bans = bans.clone();
+        tokio::spawn(async move {
+            let response = async {
+                let mut socket = transport.secure(socket, false).await?;
+                node_response(&mut socket, storage, |s, r_t| api_response(s, bans.clone(), r_t)).await
+            };
+            match tokio::time::timeout(timeout, response).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("api request error: {}", e),
+                Err(_) => warn!("api connection of {} timed out", address),
             }
+        });
+    }
+}
+
+/// Response to an API request, requests the node cannot answer get `WrongCommandError`
+fn api_response(storage: Arc<Mutex<Storage>>,
+                bans: Arc<std::sync::Mutex<BanManager>>,
+                request_type: Option<RequestType>)
+    -> BoxStream<'static, Message>
+{
+    match request_type {
+        Some(RequestType::Blocks(range)) => {
+            info!("requested blocks from height {} to {}", range.from, range.to);
+            block_pages(storage, range).map(Message::Blocks).boxed()
         }
+        Some(RequestType::Bans) => {
+            let bans = bans.lock().unwrap().bans();
+            stream::iter([Message::NodeResponse(bans)]).boxed()
+        }
+        _ => stream::iter([Message::Error(LedgerError::WrongCommandError.code())]).boxed(),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use tracing::{error, info};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+    use tokio::sync::Mutex;
+    use tracing::info;
     use client::Client;
+    use network::blocks::BlockRange;
     use network::client2node::RequestType;
-    use network::Data;
+    use network::Message;
+    use network::transport::{NoiseConfig, Transport};
+    use errors::LedgerError;
+    use state::chain_spec::ChainSpec;
+    use crate::bans::BanManager;
+    use crate::config::NodeConfig;
+    use crate::node::{serve_api, Node};
+    use crate::storage::Storage;
+
+    #[tokio::test]
+    async fn chain_spec_without_genesis_hash_refused() {
+        let config = NodeConfig { data_dir: tempfile::tempdir().unwrap().keep(), ..Default::default() };
+        let chain_spec = ChainSpec::from_toml("chain_id = \"test\"").unwrap();
+        assert_eq!(Node::new(&config, chain_spec).await.err(), Some(LedgerError::ChainSpecError));
+    }
+
+    #[tokio::test]
+    async fn stalled_api_client_does_not_block_others() {
+        let address = start_api(Transport::Plain).await;
+        let mut stalled = TcpStream::connect(address).await.unwrap();
+        let client = Client::new(&test_spec(), vec![]).unwrap();
+        let response = tokio::time::timeout(Duration::from_secs(5),
+                                            client.client_request(address, RequestType::Bans)).await.unwrap();
+        assert!(matches!(response, Ok(Message::NodeResponse(_))));
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), stalled.read(&mut buf)).await.unwrap();
+        assert!(matches!(read, Ok(0)));
+    }
+
+    #[tokio::test]
+    async fn api_requests_go_over_node_transport() {
+        let noise = |(public_key, private_key): (Vec<u8>, Vec<u8>)|
+            Transport::Noise(Arc::new(NoiseConfig::new(public_key, private_key, vec![])));
+        let address = start_api(noise(crypto::generate_keypair())).await;
+        let client = Client::new(&test_spec(), vec![]).unwrap().with_transport(noise(crypto::generate_keypair()));
+        let response = tokio::time::timeout(Duration::from_secs(5),
+                                            client.client_request(address, RequestType::Bans)).await.unwrap();
+        assert!(matches!(response, Ok(Message::NodeResponse(_))));
+        let plain = Client::new(&test_spec(), vec![]).unwrap();
+        let response = tokio::time::timeout(Duration::from_secs(5),
+                                            plain.client_request(address, RequestType::Bans)).await.unwrap();
+        assert!(response.is_err());
+    }
 
     #[tokio::test]
     async fn receive_blockchain_request_and_response_ok() {
         tracing_subscriber::fmt::init();
 
-        let request_type = RequestType::Blockchain { height: 3};
+        let request_type = RequestType::Blocks(BlockRange { from: 0, to: 2, limit: 3 });
         let socket_addr = utils::socket_addr("1244");
+        let client = Client::new(&test_spec(), vec![]).unwrap();
         let blockchain_response =
-            Client::client_request(socket_addr, request_type).await;
+            client.client_request(socket_addr, request_type).await;
         if let Ok(blockchain_response) = blockchain_response {
             match blockchain_response {
-                Data::Blockchain(blocks) => {
-                    assert_eq!(blocks.len(), 3);
-                    for block in blocks {
+                Message::Blocks(page) => {
+                    assert_eq!(page.blocks.len(), 3);
+                    for block in page.blocks {
                         info!{"block: {}", block}
                     }
                 },
@@ -159,4 +279,21 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+
+    async fn start_api(transport: Transport) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let storage = Storage::new(1, test_spec()).unwrap();
+        let bans = BanManager::new(100, Duration::from_secs(60), &tempfile::tempdir().unwrap().keep());
+        tokio::spawn(serve_api(listener,
+                               transport,
+                               Arc::new(Mutex::new(storage)),
+                               Arc::new(std::sync::Mutex::new(bans)),
+                               Duration::from_secs(1)));
+        address
+    }
+
+    fn test_spec() -> ChainSpec {
+        ChainSpec::from_toml("chain_id = \"test\"").unwrap()
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use chrono::Utc;
+use tracing::{debug, error, info};
+use errors::LedgerError;
+
+/// Known peers of the node are saved to this file inside of the data directory
+pub(crate) const PEERS_FILE: &str = "peers.json";
+/// Peer is dropped from the table after this count of failed connections in a row
+const MAX_FAILURES: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+struct PeerInfo {
+    /// Unix time of the last successful interaction with the peer, as known to us
+    last_seen: i64,
+    failures: u32,
+    /// Round-trip time of the last ping
+    latency: Option<Duration>,
+}
+
+/// Table of peers, bootstrapped from seed addresses and peers saved by the previous run,
+/// extended by announcements and peer lists of other nodes
+#[derive(Debug)]
+pub(crate) struct PeerManager {
+    own_address: SocketAddr,
+    peers: HashMap<SocketAddr, PeerInfo>,
+    target_size: usize,
+    path: PathBuf,
+}
+
+impl PeerManager {
+
+    pub fn new(own_address: SocketAddr,
+               bootstrap_peers: &[SocketAddr],
+               target_size: usize,
+               data_dir: &Path)
+        -> Self
+    {
+        let mut peer_manager = Self {
+            own_address,
+            peers: HashMap::new(),
+            target_size,
+            path: data_dir.join(PEERS_FILE),
+        };
+        for address in bootstrap_peers.iter().chain(peer_manager.load().iter()) {
+            peer_manager.add(*address, 0);
+        }
+        info!("peer table initialized with {} peers", peer_manager.peers.len());
+        peer_manager
+    }
+
+    pub fn own_address(&self) -> SocketAddr {
+        self.own_address
+    }
+
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.peers.keys().cloned().collect()
+    }
+
+    /// Adds peer to the table. When the table is full, the worst peer is evicted
+    /// if it is worse than the new one, otherwise the new one is ignored
+    pub fn add(&mut self, address: SocketAddr, last_seen: i64) -> bool {
+        if address == self.own_address || self.target_size == 0 {
+            return false
+        }
+        let last_seen = last_seen.min(Utc::now().timestamp());
+        if let Some(peer) = self.peers.get_mut(&address) {
+            peer.last_seen = peer.last_seen.max(last_seen);
+            return false
+        }
+        if self.peers.len() >= self.target_size {
+            let (worst_address, worst_peer) = self.peers.iter()
+                .min_by_key(|(_, peer)| (u32::MAX - peer.failures, peer.last_seen))
+                .map(|(address, peer)| (*address, peer.clone()))
+                .unwrap();
+            if worst_peer.failures == 0 && worst_peer.last_seen >= last_seen {
+                return false
+            }
+            debug!("peer {} evicted from the peer table", worst_address);
+            self.peers.remove(&worst_address);
+        }
+        self.peers.insert(address, PeerInfo { last_seen, failures: 0, latency: None });
+        info!("new peer: {}", address);
+        true
+    }
+
+    /// Merges peer list received from another node: address -> unix time it was last seen
+    pub fn merge(&mut self, peers: &HashMap<String, String>) {
+        for (address, last_seen) in peers.iter() {
+            match (address.parse::<SocketAddr>(), last_seen.parse::<i64>()) {
+                (Ok(address), Ok(last_seen)) => { self.add(address, last_seen); }
+                _ => error!("invalid peer entry: {} {}", address, last_seen),
+            }
+        }
+    }
+
+    /// Peer list for other nodes: this node and peers that answered last time
+    pub fn to_data(&self) -> HashMap<String, String> {
+        let mut data = self.peers.iter()
+            .filter(|(_, peer)| peer.failures == 0)
+            .map(|(address, peer)| (address.to_string(), peer.last_seen.to_string()))
+            .collect::<HashMap<_, _>>();
+        data.insert(self.own_address.to_string(), Utc::now().timestamp().to_string());
+        data
+    }
+
+    pub fn record_success(&mut self, address: &SocketAddr) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            peer.last_seen = Utc::now().timestamp();
+            peer.failures = 0;
+        }
+    }
+
+    pub fn record_latency(&mut self, address: &SocketAddr, latency: Duration) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            peer.latency = Some(latency);
+        }
+    }
+
+    pub fn latency(&self, address: &SocketAddr) -> Option<Duration> {
+        self.peers.get(address).and_then(|peer| peer.latency)
+    }
+
+    /// Peers with known latency, fastest first
+    pub fn by_latency(&self) -> Vec<(SocketAddr, Duration)> {
+        let mut peers = self.peers.iter()
+            .filter_map(|(address, peer)| peer.latency.map(|latency| (*address, latency)))
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|(_, latency)| *latency);
+        peers
+    }
+
+    pub fn remove(&mut self, address: &SocketAddr) {
+        self.peers.remove(address);
+    }
+
+    pub fn record_failure(&mut self, address: &SocketAddr) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            peer.failures += 1;
+            if peer.failures >= MAX_FAILURES {
+                info!("peer {} removed after {} failed connections", address, peer.failures);
+                self.peers.remove(address);
+            }
+        }
+    }
+
+    /// Saves peers that answered last time, for the next start of the node
+    pub fn persist(&self) -> Result<(), LedgerError> {
+        let good_peers = self.peers.iter()
+            .filter(|(_, peer)| peer.failures == 0 && peer.last_seen > 0)
+            .map(|(address, _)| *address)
+            .collect::<Vec<_>>();
+        let content = serde_json::to_string_pretty(&good_peers).map_err(|_| LedgerError::SerializeError)?;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|_| LedgerError::PersistenceError)?;
+        }
+        fs::write(&self.path, content).map_err(|e| {
+            error!("could not save peers to {}: {}", self.path.display(), e);
+            LedgerError::PersistenceError
+        })
+    }
+
+    fn load(&self) -> Vec<SocketAddr> {
+        let Ok(content) = fs::read_to_string(&self.path) else {
+            return vec![]
+        };
+        serde_json::from_str(&content).unwrap_or_else(|e| {
+            error!("invalid peers file {}: {}", self.path.display(), e);
+            vec![]
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tempfile::tempdir;
+    use crate::peers::PeerManager;
+
+    #[test]
+    fn own_address_is_not_a_peer() {
+        let dir = tempdir().unwrap();
+        let mut peers = PeerManager::new(addr(1), &[addr(1), addr(2)], 8, dir.path());
+        assert_eq!(peers.addresses(), vec![addr(2)]);
+        assert!(!peers.add(addr(1), 10));
+    }
+
+    #[test]
+    fn table_is_kept_within_target_size() {
+        let dir = tempdir().unwrap();
+        let mut peers = PeerManager::new(addr(1), &[addr(2), addr(3)], 2, dir.path());
+        peers.record_success(&addr(3));
+        assert!(peers.add(addr(4), 10));
+        assert_eq!(peers.addresses().len(), 2);
+        assert!(!peers.addresses().contains(&addr(2)));
+        // every peer is fresher than the new one
+        peers.record_success(&addr(4));
+        assert!(!peers.add(addr(5), 10));
+        assert_eq!(peers.addresses().len(), 2);
+    }
+
+    #[test]
+    fn failing_peer_is_removed() {
+        let dir = tempdir().unwrap();
+        let mut peers = PeerManager::new(addr(1), &[addr(2)], 8, dir.path());
+        peers.record_failure(&addr(2));
+        peers.record_failure(&addr(2));
+        assert_eq!(peers.addresses(), vec![addr(2)]);
+        assert!(!peers.to_data().contains_key(&addr(2).to_string()));
+        peers.record_failure(&addr(2));
+        assert!(peers.addresses().is_empty());
+    }
+
+    #[test]
+    fn merge_peer_list() {
+        let dir = tempdir().unwrap();
+        let mut peers = PeerManager::new(addr(1), &[], 8, dir.path());
+        let mut data = HashMap::new();
+        data.insert(addr(2).to_string(), "10".to_string());
+        data.insert(addr(1).to_string(), "10".to_string());
+        data.insert("not an address".to_string(), "10".to_string());
+        data.insert(addr(3).to_string(), "yesterday".to_string());
+        peers.merge(&data);
+        assert_eq!(peers.addresses(), vec![addr(2)]);
+        let data = peers.to_data();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data.get(&addr(2).to_string()), Some(&"10".to_string()));
+        assert!(data.contains_key(&addr(1).to_string()));
+    }
+
+    #[test]
+    fn peers_ordered_by_latency() {
+        let dir = tempdir().unwrap();
+        let mut peers = PeerManager::new(addr(1), &[addr(2), addr(3), addr(4)], 8, dir.path());
+        peers.record_latency(&addr(2), Duration::from_millis(80));
+        peers.record_latency(&addr(3), Duration::from_millis(20));
+        peers.record_latency(&addr(5), Duration::from_millis(10));
+        assert_eq!(peers.by_latency(), vec![(addr(3), Duration::from_millis(20)), (addr(2), Duration::from_millis(80))]);
+    }
+
+    #[test]
+    fn good_peers_are_persisted() {
+        let dir = tempdir().unwrap();
+        let mut peers = PeerManager::new(addr(1), &[addr(2), addr(3), addr(4)], 8, dir.path());
+        peers.record_success(&addr(2));
+        peers.record_success(&addr(3));
+        peers.record_failure(&addr(3));
+        peers.persist().unwrap();
+        let peers = PeerManager::new(addr(1), &[], 8, dir.path());
+        assert_eq!(peers.addresses(), vec![addr(2)]);
+    }
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 1234))
+    }
+}
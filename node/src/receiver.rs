@@ -1,61 +1,261 @@
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{oneshot, watch, Mutex, OwnedSemaphorePermit, Semaphore};
 use tokio::sync::mpsc::{
     channel,
     Receiver as Rx,
     Sender as Tx
 };
 use async_trait::async_trait;
-use tracing::{error, trace};
+use futures::StreamExt;
+use tracing::{debug, error, info, trace, warn};
 use errors::LedgerError;
-use network::{p2p::process_incoming_data, Data};
-use crate::connector::{Connect, Connector};
+use network::connection::accept;
+use network::handshake::Handshake;
+use network::transport::Transport;
+use network::{p2p::{is_protocol_violation, read_envelope_timeout, write_envelope}, Envelope, Message};
+use state::chain_spec::ChainSpec;
+use crate::bans::{BanManager, Misbehaviour};
+use crate::config::NodeConfig;
+use crate::connector::{Connect, Connector, Inbound};
+use crate::gossip::Gossip;
+use crate::storage::{block_pages, Storage};
 
+/// Limits protecting the node from slow and greedy peers
+#[derive(Debug, Clone)]
+pub(crate) struct InboundLimits {
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    /// time to receive the handshake or the rest of a started message
+    pub read_timeout: Duration,
+    /// time a connection may stay silent between messages
+    pub idle_timeout: Duration,
+}
+
+impl From<&NodeConfig> for InboundLimits {
+    fn from(config: &NodeConfig) -> Self {
+        Self {
+            max_connections: config.max_inbound_connections,
+            max_connections_per_ip: config.max_connections_per_ip,
+            read_timeout: Duration::from_secs(config.read_timeout),
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct Receiver {
     address: SocketAddr,
+    handshake: watch::Receiver<Handshake>,
     listener: TcpListener,
-    pub(crate) connector_tx: Option<Tx<Data>>
+    transport: Transport,
+    limits: InboundLimits,
+    connections: Arc<Semaphore>,
+    connections_per_ip: Arc<std::sync::Mutex<HashMap<IpAddr, usize>>>,
+    /// blocks and transactions are checked before they are passed to connector
+    chain_spec: Arc<ChainSpec>,
+    bans: Arc<std::sync::Mutex<BanManager>>,
+    gossip: Arc<Gossip>,
+    /// blocks requested by height are served from it
+    storage: Arc<Mutex<Storage>>,
+    pub(crate) connector_tx: Option<Tx<Inbound>>
+}
+
+/// Slot of an inbound connection, released when the connection is closed
+struct ConnectionSlot {
+    ip: IpAddr,
+    connections_per_ip: Arc<std::sync::Mutex<HashMap<IpAddr, usize>>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections_per_ip = self.connections_per_ip.lock().unwrap();
+        if let Some(count) = connections_per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections_per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Inventory known to a connected peer, forgotten when the connection is closed
+struct KnownInventory<'a> {
+    gossip: &'a Gossip,
+    node_id: Vec<u8>,
+}
+
+impl Drop for KnownInventory<'_> {
+    fn drop(&mut self) {
+        self.gossip.inventory.lock().unwrap().disconnected(&self.node_id);
+    }
 }
 
 impl Receiver {
 
-    pub async fn new(address: SocketAddr) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(address: SocketAddr,
+                     transport: Transport,
+                     handshake: watch::Receiver<Handshake>,
+                     limits: InboundLimits,
+                     chain_spec: ChainSpec,
+                     bans: Arc<std::sync::Mutex<BanManager>>,
+                     gossip: Arc<Gossip>,
+                     storage: Arc<Mutex<Storage>>)
+        -> Self
+    {
         Self {
             address,
+            handshake,
             listener: TcpListener::bind(address).await.unwrap(),
+            transport,
+            connections: Arc::new(Semaphore::new(limits.max_connections)),
+            connections_per_ip: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            chain_spec: Arc::new(chain_spec),
+            bans,
+            gossip,
+            storage,
+            limits,
             connector_tx: None
         }
     }
 
+    /// Every accepted connection is served by its own task until the peer closes it or gets banned,
+    /// connections over the limits and from banned peers are closed right away
     pub async fn run(&mut self) {
+        info!("accepting peer connections on {}", self.address);
         loop {
-            while let Ok((mut socket, _)) = self.listener.accept().await {
-                let processed = Self::process_incoming(self, &mut socket).await;
-                if processed.is_err() {
-                    error!("error processing incoming data")
-                }
+            while let Ok((socket, remote_address)) = self.listener.accept().await {
+                let Some(slot) = self.take_slot(remote_address.ip()) else {
+                    continue
+                };
+                let transport = self.transport.clone();
+                let handshake = self.handshake.borrow().clone();
+                let connector_tx = self.connector_tx.clone().unwrap();
+                let limits = self.limits.clone();
+                let chain_spec = self.chain_spec.clone();
+                let bans = self.bans.clone();
+                let gossip = self.gossip.clone();
+                let storage = self.storage.clone();
+                tokio::spawn(async move {
+                    let processed = Self::process_incoming(
+                        socket, &transport, &handshake, &limits, &chain_spec, &bans, &gossip, &storage, connector_tx).await;
+                    if let Err(e) = processed {
+                        error!("error processing incoming data from {}: {}", remote_address, e);
+                        if is_protocol_violation(&e) {
+                            bans.lock().unwrap().misbehaved(remote_address.ip(), Misbehaviour::ProtocolViolation);
+                        }
+                    }
+                    debug!("connection from {} closed", remote_address);
+                    drop(slot);
+                });
             }
         }
     }
 
-    async fn process_incoming(&mut self, socket: &mut TcpStream)
+    fn take_slot(&self, ip: IpAddr) -> Option<ConnectionSlot> {
+        if self.bans.lock().unwrap().is_banned(&ip) {
+            warn!("{} is banned, connection refused", ip);
+            return None
+        }
+        let Ok(permit) = self.connections.clone().try_acquire_owned() else {
+            warn!("too many inbound connections, connection from {} refused", ip);
+            return None
+        };
+        let mut connections_per_ip = self.connections_per_ip.lock().unwrap();
+        let count = connections_per_ip.entry(ip).or_insert(0);
+        if *count >= self.limits.max_connections_per_ip {
+            warn!("too many connections from {}, connection refused", ip);
+            return None
+        }
+        *count += 1;
+        Some(ConnectionSlot { ip, connections_per_ip: self.connections_per_ip.clone(), _permit: permit })
+    }
+
+    /// Invalid blocks and transactions are dropped and count against the peer.
+    /// Requests of the peer are answered and missing objects requested on the same connection,
+    /// a message with a request id gets a reply with its id: `Ack`, the answer or the `Error` it failed with.
+    /// A transaction is answered once the miner has admitted it to the pool or rejected it.
+    /// Blocks requested by height are written page by page
+    #[allow(clippy::too_many_arguments)]
+    async fn process_incoming(socket: TcpStream,
+                              transport: &Transport,
+                              handshake: &Handshake,
+                              limits: &InboundLimits,
+                              chain_spec: &ChainSpec,
+                              bans: &std::sync::Mutex<BanManager>,
+                              gossip: &Gossip,
+                              storage: &Arc<Mutex<Storage>>,
+                              tx: Tx<Inbound>)
         -> Result<(), LedgerError>
     {
-        let data = process_incoming_data(socket).await;
-        if data.is_ok() {
-            let tx = self.connector_tx.as_ref().unwrap();
-            let sent = tx.send(data.unwrap()).await;
-            if sent.is_err() {
-                error!("connector_tx: {}", sent.err().unwrap());
+        let ip = socket.peer_addr().map_err(|_| LedgerError::NetworkError)?.ip();
+        let (remote, mut reader, mut writer) = tokio::time::timeout(limits.read_timeout, accept(socket, transport, handshake))
+            .await
+            .map_err(|_| LedgerError::TimeoutError)??;
+        let _known = KnownInventory { gossip, node_id: remote.node_id.clone() };
+        while let Some(Envelope { id, message }) =
+            read_envelope_timeout(&mut reader, limits.idle_timeout, limits.read_timeout).await?
+        {
+            trace!("received {}", message.name());
+            if let Message::GetBlocks(range) = message {
+                let mut pages = Box::pin(block_pages(storage.clone(), range));
+                while let Some(page) = pages.next().await {
+                    let reply = Envelope { id, message: Message::Blocks(page) };
+                    write_envelope(&mut writer, reply).await.map_err(|_| LedgerError::NetworkError)?;
+                }
+                continue
+            }
+            let (mut replies, data) = match gossip.receive(chain_spec, &remote.node_id, message).await {
+                Ok(received) => received,
+                Err(misbehaviour) => {
+                    if id != 0 {
+                        let reply = Envelope { id, message: Message::Error(misbehaviour.error().code()) };
+                        write_envelope(&mut writer, reply).await.map_err(|_| LedgerError::NetworkError)?;
+                    }
+                    if bans.lock().unwrap().misbehaved(ip, misbehaviour) {
+                        warn!("banned peer {} disconnected", ip);
+                        return Ok(())
+                    }
+                    continue
+                }
+            };
+            if bans.lock().unwrap().is_banned(&ip) {
+                warn!("banned peer {} disconnected", ip);
+                return Ok(())
+            }
+            let (admitted, admission) = match data {
+                Some(Message::Transaction(_)) if id != 0 => {
+                    let (admitted, admission) = oneshot::channel();
+                    (Some(admitted), Some(admission))
+                }
+                _ => (None, None),
+            };
+            if id != 0 && replies.is_empty() && admission.is_none() {
+                replies.push(Message::Ack(()));
+            }
+            for reply in replies {
+                write_envelope(&mut writer, Envelope { id, message: reply }).await.map_err(|_| LedgerError::NetworkError)?;
+            }
+            let Some(data) = data else { continue };
+            if let Err(e) = tx.send(Inbound { data, origin: Some(ip), admitted }).await {
+                error!("connector_tx: {}", e);
                 return Err(LedgerError::SyncError)
             }
-        } else {
-            error!("failed to receive data from network {}", data.err().unwrap());
-            return Err(LedgerError::NetworkError)
+            let Some(admission) = admission else { continue };
+            let admitted = match tokio::time::timeout(limits.read_timeout, admission).await {
+                Ok(admitted) => admitted.unwrap_or(Err(LedgerError::SyncError)),
+                Err(_) => Err(LedgerError::TimeoutError),
+            };
+            let reply = match admitted {
+                Ok(()) => Message::Ack(()),
+                Err(e) => Message::Error(e.code()),
+            };
+            write_envelope(&mut writer, Envelope { id, message: reply }).await.map_err(|_| LedgerError::NetworkError)?;
         }
         Ok(())
     }
@@ -65,8 +265,249 @@ impl Receiver {
 impl Connect for Receiver {
     async fn connect(&mut self, connector: Arc<Mutex<Connector>>) {
         let mut connector = connector.lock().await;
-        let (tx, rx): (Tx<Data>, Rx<Data>) = channel(10);
+        let (tx, rx): (Tx<Inbound>, Rx<Inbound>) = channel(10);
         self.connector_tx = Some(tx);
         connector.receiver_rx = Arc::new(Mutex::new(Some(rx)));
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc::channel;
+    use tokio::sync::watch;
+    use network::connection::connect;
+    use network::handshake::Handshake;
+    use network::codec::WIRE_VERSION;
+    use errors::LedgerError;
+    use network::p2p::{read_envelope, read_message, write_envelope, write_message};
+    use network::transport::Transport;
+    use network::blocks::BlockRange;
+    use network::{Envelope, InventoryItem, Message};
+    use ursa::signatures::ed25519::Ed25519Sha512;
+    use ursa::signatures::SignatureScheme;
+    use client::Client;
+    use state::{Command, Transaction};
+    use state::chain_spec::ChainSpec;
+    use crate::bans::BanManager;
+    use crate::connector::{Connect, Connector, Inbound};
+    use crate::gossip::Gossip;
+    use crate::miner::Miner;
+    use crate::receiver::{InboundLimits, Receiver};
+    use crate::storage::Storage;
+
+    #[tokio::test]
+    async fn stalled_peer_does_not_block_others() {
+        let (address, mut data_rx) = start_receiver(limits(2, Duration::from_secs(10))).await;
+        let mut stalled = TcpStream::connect(address).await.unwrap();
+        // length prefix of a handshake that never comes
+        stalled.write_all(&100u32.to_be_bytes()).await.unwrap();
+        let (_, _, mut writer) = connect(address, &Transport::Plain, &handshake()).await.unwrap();
+        write_message(&mut writer, Message::Peers(peers())).await.unwrap();
+        let data = tokio::time::timeout(Duration::from_secs(5), data_rx.recv()).await.unwrap();
+        assert!(matches!(data.map(|inbound| inbound.data), Some(Message::Peers(_))));
+    }
+
+    #[tokio::test]
+    async fn stalled_peer_disconnected_after_read_timeout() {
+        let (address, _data_rx) = start_receiver(limits(2, Duration::from_secs(1))).await;
+        let mut stalled = TcpStream::connect(address).await.unwrap();
+        stalled.write_all(&100u32.to_be_bytes()).await.unwrap();
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), stalled.read(&mut buf)).await.unwrap();
+        assert!(matches!(read, Ok(0)));
+    }
+
+    #[tokio::test]
+    async fn connections_per_ip_limited() {
+        let (address, mut data_rx) = start_receiver(limits(1, Duration::from_secs(10))).await;
+        let (_, _, mut writer) = connect(address, &Transport::Plain, &handshake()).await.unwrap();
+        assert!(connect(address, &Transport::Plain, &handshake()).await.is_err());
+        // the first connection is still served
+        write_message(&mut writer, Message::Peers(peers())).await.unwrap();
+        assert!(matches!(data_rx.recv().await.map(|inbound| inbound.data), Some(Message::Peers(_))));
+        drop(writer);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(connect(address, &Transport::Plain, &handshake()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_count_against_peer() {
+        let (address, _data_rx) = start_receiver(limits(4, Duration::from_secs(10))).await;
+        let (public_key, private_key) = crypto::generate_keypair();
+        let transaction = Transaction::new_signed("test", 0, 1, vec![], &public_key, &private_key).unwrap();
+        let tag = Message::Transaction(transaction).tag();
+        // 3 protocol violations reach the default ban threshold
+        for _ in 0..3 {
+            let (_, mut reader, mut writer) = connect(address, &Transport::Plain, &handshake()).await.unwrap();
+            // header of a 4 GiB transaction, nothing is allocated for it
+            writer.get_mut().write_all(&[WIRE_VERSION, tag]).await.unwrap();
+            writer.get_mut().write_all(&0u32.to_be_bytes()).await.unwrap();
+            writer.get_mut().write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+            let mut buf = [0u8; 1];
+            assert_eq!(reader.get_mut().read(&mut buf).await.unwrap(), 0);
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(connect(address, &Transport::Plain, &handshake()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_transactions_dropped_and_peer_banned() {
+        let (address, mut data_rx) = start_receiver(limits(4, Duration::from_secs(10))).await;
+        let (_, mut reader, mut writer) = connect(address, &Transport::Plain, &handshake()).await.unwrap();
+        let (public_key, private_key) = crypto::generate_keypair();
+        let signed = Transaction::new_signed("test", 0, 1, vec![], &public_key, &private_key).unwrap();
+        let forged = Transaction { fee: 2, ..signed };
+        for _ in 0..5 {
+            write_message(&mut writer, Message::Transaction(forged.clone())).await.unwrap();
+        }
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), reader.get_mut().read(&mut buf)).await.unwrap();
+        assert!(matches!(read, Ok(0)));
+        assert!(data_rx.try_recv().is_err());
+        assert!(connect(address, &Transport::Plain, &handshake()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn transaction_requests_answered_with_result() {
+        let (address, mut data_rx) = start_receiver(limits(4, Duration::from_secs(10))).await;
+        let (_, mut reader, mut writer) = connect(address, &Transport::Plain, &handshake()).await.unwrap();
+        let (public_key, private_key) = crypto::generate_keypair();
+        let signed = Transaction::new_signed("test", 0, 1, vec![], &public_key, &private_key).unwrap();
+        let forged = Transaction { fee: 2, ..signed.clone() };
+        write_envelope(&mut writer, Envelope { id: 5, message: Message::Transaction(signed) }).await.unwrap();
+        write_envelope(&mut writer, Envelope { id: 6, message: Message::Transaction(forged) }).await.unwrap();
+        let inbound = data_rx.recv().await.unwrap();
+        assert!(matches!(inbound.data, Message::Transaction(_)));
+        inbound.admitted.unwrap().send(Ok(())).unwrap();
+        let accepted = read_envelope(&mut reader).await.unwrap().unwrap();
+        assert!(matches!(accepted, Envelope { id: 5, message: Message::Ack(()) }));
+        let rejected = read_envelope(&mut reader).await.unwrap().unwrap();
+        assert_eq!(rejected.id, 6);
+        assert_eq!(rejected.into_reply().err(), Some(LedgerError::BadSignature));
+    }
+
+    #[tokio::test]
+    async fn missing_inventory_requested() {
+        let (address, mut data_rx) = start_receiver(limits(4, Duration::from_secs(10))).await;
+        let mut peer = handshake();
+        peer.node_id = vec![7; 32];
+        let (_, mut reader, mut writer) = connect(address, &Transport::Plain, &peer).await.unwrap();
+        let (public_key, private_key) = crypto::generate_keypair();
+        let transaction = Transaction::new_signed("test", 0, 1, vec![], &public_key, &private_key).unwrap();
+        let item = InventoryItem::Transaction(transaction.id());
+        write_message(&mut writer, Message::Inventory(vec![item.clone()])).await.unwrap();
+        let request = tokio::time::timeout(Duration::from_secs(5), read_message(&mut reader)).await.unwrap();
+        assert!(matches!(request, Ok(Some(Message::GetData(items))) if items == vec![item]));
+        write_message(&mut writer, Message::Transaction(transaction.clone())).await.unwrap();
+        let data = tokio::time::timeout(Duration::from_secs(5), data_rx.recv()).await.unwrap();
+        assert!(matches!(data.map(|inbound| inbound.data), Some(Message::Transaction(t)) if t.id() == transaction.id()));
+    }
+
+    #[tokio::test]
+    async fn blocks_requested_by_height() {
+        let (address, _data_rx) = start_receiver(limits(4, Duration::from_secs(10))).await;
+        let (_, mut reader, mut writer) = connect(address, &Transport::Plain, &handshake()).await.unwrap();
+        let request = Message::GetBlocks(BlockRange::new(0, 10));
+        write_envelope(&mut writer, Envelope { id: 3, message: request }).await.unwrap();
+        let reply = read_envelope(&mut reader).await.unwrap().unwrap();
+        assert_eq!(reply.id, 3);
+        let Message::Blocks(page) = reply.message else { panic!("blocks are not served") };
+        // the node has the genesis block only
+        let genesis = ChainSpec::from_toml("chain_id = \"test\"").unwrap().genesis_block().unwrap();
+        assert_eq!(page.blocks.len(), 1);
+        assert_eq!(page.blocks[0].hash, genesis.hash);
+        assert!(!page.more && page.next.is_none());
+    }
+
+    #[tokio::test]
+    async fn client_told_why_transaction_rejected() {
+        let chain_spec = ChainSpec::from_toml(r#"
+            chain_id = "test"
+            [[accounts]]
+            public_key = "3b6a27bcceb6a42d62a3a8d02a6f0d73653215771de243a63ac048a18b59da29"
+            balances = { NATIVE = 10 }
+        "#).unwrap();
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+        let mut storage = Storage::new(1, chain_spec.clone()).unwrap();
+        let handshake = storage.watch_handshake(&public_key.0);
+        let mut miner = Miner::new(1, public_key, private_key, storage);
+        let bans = BanManager::new(100, Duration::from_secs(60), &tempfile::tempdir().unwrap().keep());
+        let mut receiver = Receiver::new(
+            "127.0.0.1:0".parse().unwrap(),
+            Transport::Plain,
+            handshake,
+            limits(4, Duration::from_secs(10)),
+            chain_spec.clone(),
+            Arc::new(std::sync::Mutex::new(bans)),
+            Arc::new(Gossip::new(miner.transaction_pool.clone())),
+            miner.storage.clone()).await;
+        let address = receiver.listener.local_addr().unwrap();
+        let connector = Arc::new(tokio::sync::Mutex::new(Connector::new()));
+        receiver.connect(connector.clone()).await;
+        miner.connect(connector.clone()).await;
+        connector.lock().await.start().await;
+        tokio::spawn(async move { receiver.run().await });
+        miner.run(false).await;
+
+        let mut client = Client::new(&chain_spec, vec![address]).unwrap();
+        let (signer, secret) = crypto::generate_keypair();
+        let create = Command::CreateAccount { public_key: "12345".to_string() };
+        let transaction = Transaction::new_signed("test", 0, 1, vec![create], &signer, &secret).unwrap();
+        assert_eq!(client.submit_transaction(transaction.clone()).await, Ok(()));
+        assert_eq!(client.submit_transaction(transaction).await, Err(LedgerError::DuplicateTransaction));
+        let overdraft = Command::TransferFunds {
+            account_from_id: 1,
+            account_to_id: 2,
+            value: 11,
+            asset_id: "NATIVE".to_string(),
+        };
+        let transaction = Transaction::new_signed("test", 1, 1, vec![overdraft], &signer, &secret).unwrap();
+        assert_eq!(client.submit_transaction(transaction).await, Err(LedgerError::InsufficientFunds));
+        assert_eq!(miner.transaction_pool.lock().await.len(), 1);
+    }
+
+    async fn start_receiver(limits: InboundLimits)
+        -> (std::net::SocketAddr, tokio::sync::mpsc::Receiver<Inbound>)
+    {
+        let (_, handshake_rx) = watch::channel(handshake());
+        let chain_spec = ChainSpec::from_toml("chain_id = \"test\"").unwrap();
+        let storage = Storage::new(1, chain_spec.clone()).unwrap();
+        let data_dir = tempfile::tempdir().unwrap().keep();
+        let bans = BanManager::new(100, Duration::from_secs(60), &data_dir);
+        let mut receiver = Receiver::new(
+            "127.0.0.1:0".parse().unwrap(),
+            Transport::Plain,
+            handshake_rx,
+            limits,
+            chain_spec,
+            std::sync::Arc::new(std::sync::Mutex::new(bans)),
+            std::sync::Arc::new(Gossip::new(Default::default())),
+            std::sync::Arc::new(tokio::sync::Mutex::new(storage))).await;
+        let address = receiver.listener.local_addr().unwrap();
+        let (data_tx, data_rx) = channel(10);
+        receiver.connector_tx = Some(data_tx);
+        tokio::spawn(async move { receiver.run().await });
+        (address, data_rx)
+    }
+
+    fn limits(max_connections_per_ip: usize, read_timeout: Duration) -> InboundLimits {
+        InboundLimits {
+            max_connections: 8,
+            max_connections_per_ip,
+            read_timeout,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+
+    fn handshake() -> Handshake {
+        Handshake::client("test", vec![1; 32])
+    }
+
+    fn peers() -> std::collections::HashMap<String, String> {
+        [("127.0.0.1:1235".to_string(), "10".to_string())].into_iter().collect()
+    }
+}
@@ -1,108 +1,374 @@
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
 //use std::sync::Mutex;
-use network::{Data, p2p::SendEvent, serialize_data};
-use state::Block;
-use crate::connector::{Connect, Connector};
+use errors::LedgerError;
+use network::Message;
+use network::blocks::BlockRange;
+use network::compact::CompactBlock;
+use network::connection::{ConnectionEvent, PeerConnection, PING_INTERVAL};
+use network::handshake::{features, Handshake};
+use network::transport::Transport;
+use state::chain_spec::ChainSpec;
+use crate::bans::{inspect, BanManager, Misbehaviour};
+use crate::connector::{Connect, Connector, Inbound};
+use crate::gossip::Gossip;
+use crate::peers::PeerManager;
 use async_trait::async_trait;
 use tokio::sync::mpsc::{
     channel,
     Receiver as Rx,
     Sender as Tx
 };
-use tracing::{error, trace};
+use tracing::{error, info, trace, warn};
 
 #[derive(Debug)]
 pub(crate) struct Sender {
-    peer_address: SocketAddr,
-    peers: Peers,
-    pub(crate) connector_rx: Option<tokio::sync::mpsc::Receiver<Data>>,
+    transport: Transport,
+    handshake: watch::Receiver<Handshake>,
+    peers: PeerManager,
+    /// long-lived connection to every peer of the peer table
+    connections: HashMap<SocketAddr, PeerConnection>,
+    /// handshakes of connected peers
+    remotes: HashMap<SocketAddr, Handshake>,
+    events_tx: Tx<ConnectionEvent>,
+    events_rx: Option<Rx<ConnectionEvent>>,
+    /// how often peer lists are exchanged with known peers
+    exchange_interval: Duration,
+    /// how often connected peers are pinged
+    ping_interval: Duration,
+    /// data received over outbound connections is checked the same way as in receiver
+    chain_spec: ChainSpec,
+    bans: Arc<std::sync::Mutex<BanManager>>,
+    gossip: Arc<Gossip>,
+    /// set while blocks are downloaded from a peer
+    syncing: Arc<AtomicBool>,
+    pub(crate) connector_rx: Option<tokio::sync::mpsc::Receiver<Message>>,
+    /// data received over outbound connections goes the same way as data of inbound ones
+    pub(crate) inbound_tx: Option<Tx<Inbound>>,
 }
 
 impl Sender {
 
-    pub fn new(peer_address: SocketAddr) -> Self {
+    pub fn new(transport: Transport,
+               handshake: watch::Receiver<Handshake>,
+               peers: PeerManager,
+               exchange_interval: Duration,
+               chain_spec: ChainSpec,
+               bans: Arc<std::sync::Mutex<BanManager>>,
+               gossip: Arc<Gossip>)
+        -> Self
+    {
+        let (events_tx, events_rx) = channel(100);
         Self {
-            peer_address,
-            peers: Peers::new(),
-            connector_rx: None
+            transport,
+            handshake,
+            peers,
+            connections: HashMap::new(),
+            remotes: HashMap::new(),
+            events_tx,
+            events_rx: Some(events_rx),
+            exchange_interval,
+            ping_interval: PING_INTERVAL,
+            chain_spec,
+            bans,
+            gossip,
+            syncing: Arc::new(AtomicBool::new(false)),
+            connector_rx: None,
+            inbound_tx: None,
         }
     }
 
+    /// Peers not answering a ping until the next one are disconnected
+    pub fn with_ping_interval(mut self, ping_interval: Duration) -> Self {
+        self.ping_interval = ping_interval;
+        self
+    }
+
+    /// Announces the node to known peers, then sends data from connector to the network
+    /// and periodically exchanges peer lists
     pub async fn run(&mut self) {
+        let (Some(mut connector_rx), Some(mut events_rx)) = (self.connector_rx.take(), self.events_rx.take()) else {
+            error!("sender is not connected");
+            return
+        };
+        let own_address = self.peers.own_address().to_string();
+        self.send_to_peers(Message::Peer(own_address));
+        let mut exchange = tokio::time::interval(self.exchange_interval);
+        exchange.tick().await;
         loop {
-            let peers = &self.peers;
-            let address = &self.peer_address;
-            if let Some(connector_rx) = self.connector_rx.as_mut() {
-                while let Some(data) = connector_rx.recv().await {
-                    match data {
-                        Data::Block(block) => {
-                            //trace!("get block from connector: {}", &block);
-                            Self::send_block_to_network(address, peers, block).await;
-                        }
-                        Data::Transaction(_) => {
-                            error!("error: transaction is not intended to be sent by peer")
-                        }
-                        Data::Peer(peer) => { todo!() }
-                        Data::Peers(peers) => { todo!() }
-                        Data::Blockchain(blocks) => { todo!() }
-                        Data::NodeResponse(_) => todo!()
+            tokio::select! {
+                data = connector_rx.recv() => {
+                    let Some(data) = data else { break };
+                    self.process_data(data);
+                }
+                Some(event) = events_rx.recv() => {
+                    self.process_connection_event(event).await;
+                }
+                _ = exchange.tick() => {
+                    self.exchange_peers();
+                    self.sync_blocks();
+                }
+            }
+        }
+    }
+
+    fn process_data(&mut self, data: Message) {
+        match data {
+            Message::Block(_) | Message::Transaction(_) => {
+                //trace!("get block or transaction from connector: {}", &data);
+                self.announce(data);
+            }
+            Message::Peer(peer) => {
+                let Ok(address) = peer.parse::<SocketAddr>() else {
+                    error!("invalid peer address: {}", peer);
+                    return
+                };
+                self.peers.add(address, chrono::Utc::now().timestamp());
+                self.update_connections();
+                // new node learns the network from our peer list
+                if let Some(connection) = self.connections.get(&address) {
+                    let _ = connection.send(Message::Peers(self.peers.to_data()));
+                }
+            }
+            Message::Peers(peers) => {
+                trace!("received {} peers", peers.len());
+                self.peers.merge(&peers);
+                self.update_connections();
+            }
+            data => {
+                error!("error: {} is not intended to be sent by peer", data)
+            }
+        }
+    }
+
+    async fn process_connection_event(&mut self, event: ConnectionEvent) {
+        match event {
+            ConnectionEvent::Connected(address, remote) => {
+                trace!("peer {} best height: {}", address, remote.best_height);
+                self.peers.record_success(&address);
+                self.remotes.insert(address, remote);
+                self.sync_blocks();
+            }
+            ConnectionEvent::Failed(address) => {
+                if let Some(remote) = self.remotes.remove(&address) {
+                    self.gossip.inventory.lock().unwrap().disconnected(&remote.node_id);
+                }
+                self.peers.record_failure(&address);
+                self.update_connections();
+            }
+            ConnectionEvent::Latency(address, latency) => {
+                trace!("peer {} latency: {} ms", address, latency.as_millis());
+                self.peers.record_latency(&address, latency);
+            }
+            ConnectionEvent::Violation(address) => {
+                self.misbehaved(address, Misbehaviour::ProtocolViolation);
+                self.peers.record_failure(&address);
+                self.update_connections();
+            }
+            ConnectionEvent::Received(address, data) => {
+                trace!("received {} from {}", data.name(), address);
+                let node_id = self.remotes.get(&address).map(|remote| remote.node_id.clone()).unwrap_or_default();
+                let (frames, data) = match self.gossip.receive(&self.chain_spec, &node_id, data).await {
+                    Ok(received) => received,
+                    Err(misbehaviour) => {
+                        self.misbehaved(address, misbehaviour);
+                        return
+                    }
+                };
+                if let Some(connection) = self.connections.get(&address) {
+                    for message in frames {
+                        let _ = connection.send(message);
+                    }
+                }
+                let Some(data) = data else { return };
+                if let Some(inbound_tx) = self.inbound_tx.as_ref() {
+                    if let Err(e) = inbound_tx.send(Inbound::from_peer(data, address.ip())).await {
+                        error!("inbound_tx: {}", e);
                     }
                 }
             }
         }
     }
 
-    async fn send_block_to_network(peer_address: &SocketAddr, peers: &Peers, block: Block) {
-        for (_, socket_addr) in peers.addresses.iter() {
-            if socket_addr.eq(peer_address) {
+    /// Peers supporting inventory get hash of a block or a transaction unless they already know it,
+    /// blocks go as compact blocks to peers supporting them. Other peers get the whole object
+    fn announce(&mut self, data: Message) {
+        self.update_connections();
+        let compact = match &data {
+            Message::Block(block) => Some(Message::CompactBlock(CompactBlock::new(block))),
+            _ => None,
+        };
+        let mut inventory = self.gossip.inventory.lock().unwrap();
+        let Some(item) = inventory.add(&data) else { return };
+        let announcement = Message::Inventory(vec![item.clone()]);
+        for (address, connection) in self.connections.iter() {
+            let remote = self.remotes.get(address).filter(|remote| remote.features & features::INVENTORY != 0);
+            let Some(remote) = remote else {
+                let _ = connection.send(data.clone());
+                continue
+            };
+            if !inventory.known_by(&remote.node_id, item.clone()) {
                 continue
             }
-            let block = block.clone();
-            let socket = TcpStream::connect(socket_addr).await;
-            if let Ok(mut socket) = socket {
-                tokio::spawn(async move {
-                    let res = network::p2p::send_data(
-                        &mut socket,
-                        serialize_data(&block), SendEvent::SendBlock)
-                        .await;
-                    if res.is_err() {
-                        error!("error while sending block to peer: {}", res.err().unwrap());
-                    }
-                });
-            } else {
-                error!("could not establish connection: {}", socket_addr)
+            match &compact {
+                Some(compact) if remote.features & features::COMPACT_BLOCKS != 0 => {
+                    let _ = connection.send(compact.clone());
+                }
+                _ => {
+                    let _ = connection.send(announcement.clone());
+                }
             }
         }
     }
+
+    /// Downloads blocks above the best block of the node from the connected peer with the best chain,
+    /// one download at a time. Blocks go the same way as blocks received from peers
+    fn sync_blocks(&mut self) {
+        let best_height = self.handshake.borrow().best_height;
+        let best_peer = self.remotes.iter()
+            .filter(|(_, remote)| remote.features & features::BLOCK_RANGES != 0)
+            .max_by_key(|(_, remote)| remote.best_height);
+        let Some((address, remote)) = best_peer else { return };
+        if remote.best_height <= best_height {
+            return
+        }
+        let (Some(connection), Some(inbound_tx)) = (self.connections.get(address), self.inbound_tx.as_ref()) else {
+            return
+        };
+        if self.syncing.swap(true, Ordering::SeqCst) {
+            return
+        }
+        info!("downloading blocks {}..={} from peer {}", best_height + 1, remote.best_height, address);
+        let range = BlockRange::new(best_height + 1, remote.best_height);
+        let connection = connection.clone();
+        let inbound_tx = inbound_tx.clone();
+        let chain_spec = self.chain_spec.clone();
+        let bans = self.bans.clone();
+        let syncing = self.syncing.clone();
+        tokio::spawn(async move {
+            if let Err(e) = download_blocks(&connection, range, &chain_spec, &bans, &inbound_tx).await {
+                warn!("download of blocks from peer {} failed: {}", connection.address(), e);
+            }
+            syncing.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Banned peer is removed from the peer table, its connection is closed
+    fn misbehaved(&mut self, address: SocketAddr, misbehaviour: Misbehaviour) {
+        if self.bans.lock().unwrap().misbehaved(address.ip(), misbehaviour) {
+            warn!("banned peer {} disconnected", address);
+            self.peers.remove(&address);
+            self.update_connections();
+        }
+    }
+
+    fn exchange_peers(&mut self) {
+        self.send_to_peers(Message::Peers(self.peers.to_data()));
+        let _ = self.peers.persist();
+        self.log_status();
+    }
+
+    /// Connected peers with their latency, fastest first
+    fn log_status(&self) {
+        let mut status = self.peers.by_latency().into_iter()
+            .filter(|(address, _)| self.remotes.contains_key(address))
+            .map(|(address, latency)| format!("{} ({} ms)", address, latency.as_millis()))
+            .collect::<Vec<_>>();
+        status.extend(self.remotes.keys()
+            .filter(|address| self.peers.latency(address).is_none())
+            .map(|address| format!("{} (-)", address)));
+        info!("connected to {} peers: {}", status.len(), status.join(", "));
+    }
+
+    fn send_to_peers(&mut self, message: Message) {
+        self.update_connections();
+        for connection in self.connections.values() {
+            let _ = connection.send(message.clone());
+        }
+    }
+
+    /// Opens connections to new peers of the peer table and closes connections to removed
+    /// and banned ones
+    fn update_connections(&mut self) {
+        let banned = {
+            let bans = self.bans.lock().unwrap();
+            self.peers.addresses().into_iter()
+                .filter(|address| bans.is_banned(&address.ip()))
+                .collect::<Vec<_>>()
+        };
+        for address in banned {
+            self.peers.remove(&address);
+        }
+        let addresses = self.peers.addresses();
+        self.connections.retain(|address, _| addresses.contains(address));
+        let mut inventory = self.gossip.inventory.lock().unwrap();
+        self.remotes.retain(|address, remote| {
+            let kept = addresses.contains(address);
+            if !kept {
+                inventory.disconnected(&remote.node_id);
+            }
+            kept
+        });
+        drop(inventory);
+        for address in addresses {
+            self.connections.entry(address).or_insert_with(|| {
+                PeerConnection::open(
+                    address,
+                    self.transport.clone(),
+                    self.handshake.clone(),
+                    Some(self.events_tx.clone()),
+                    self.ping_interval)
+            });
+        }
+    }
+}
+
+/// Requests blocks of `range` from the peer and passes them on in order. A range cut by the limit
+/// of the peer is continued from the cursor of its last page, until the peer has no more blocks
+async fn download_blocks(connection: &PeerConnection,
+                         mut range: BlockRange,
+                         chain_spec: &ChainSpec,
+                         bans: &std::sync::Mutex<BanManager>,
+                         inbound_tx: &Tx<Inbound>)
+    -> Result<(), LedgerError>
+{
+    loop {
+        let mut replies = connection.request_stream(Message::GetBlocks(range.clone()))?;
+        let next = loop {
+            let Message::Blocks(page) = replies.next().await? else {
+                return Err(LedgerError::WrongCommandError)
+            };
+            for block in page.blocks {
+                let data = Message::Block(block);
+                if let Some(misbehaviour) = inspect(chain_spec, &data) {
+                    let error = misbehaviour.error();
+                    bans.lock().unwrap().misbehaved(connection.address().ip(), misbehaviour);
+                    return Err(error)
+                }
+                inbound_tx.send(Inbound::from_peer(data, connection.address().ip())).await.map_err(|_| LedgerError::SyncError)?;
+            }
+            if !page.more {
+                break page.next
+            }
+        };
+        match next {
+            Some(from) if from > range.from => range.from = from,
+            Some(_) => return Err(LedgerError::SyncError),
+            None => return Ok(()),
+        }
+    }
 }
 
 #[async_trait]
 impl Connect for Sender {
     async fn connect(&mut self, connector: Arc<Mutex<Connector>>) {
         let mut connector = connector.lock().await;
-        let (tx, rx): (Tx<Data>, Rx<Data>) = channel(10);
+        let (tx, rx): (Tx<Message>, Rx<Message>) = channel(10);
         self.connector_rx = Some(rx);
         connector.sender_tx = Arc::new(Mutex::new(Some(tx)));
     }
 }
-
-#[derive(Debug)]
-struct Peers {
-    addresses: HashMap<u32, SocketAddr>,
-}
-
-impl Peers {
-    fn new() -> Self {
-        Self {
-            addresses: HashMap::from([
-                (1, utils::socket_addr("1234")),
-                (2, utils::socket_addr("1235")),
-                (3, utils::socket_addr("1236"))
-            ])
-        }
-    }
-}
\ No newline at end of file
@@ -1,84 +1,128 @@
+use std::sync::Arc;
+use futures::{stream, Stream};
 use tracing::{debug, error, info};
-use state::{Accounts, Asset, Assets, Block, MAX_TRANSACTIONS_IN_BLOCK, NATIVE_COIN};
+use state::{Accounts, Asset, Assets, Block, Command, Undo, NATIVE_COIN};
+use state::chain_spec::ChainSpec;
+use network::blocks::{BlockPage, BlockRange};
+use network::handshake::{features, Handshake, PROTOCOL_VERSION};
+use tokio::sync::{watch, Mutex};
 
-use crypto;
-use crypto::hash;
 use errors::LedgerError;
 use utils::{print_bytes, convert_timestamp_to_day_time};
 
 #[derive(Debug)]
 pub(crate) struct Storage {
     id: u64,
+    chain_spec: ChainSpec,
     blockchain: Vec<Block>,  // TODO persistence
     accounts: Accounts,
     /// Key is a tuple of format (account_id, asset_id)
-    assets: Assets
+    assets: Assets,
+    /// Node id and channel of the handshake, updated when a block is added
+    handshake_tx: Option<(Vec<u8>, watch::Sender<Handshake>)>,
 }
 
 impl Storage {
 
-    pub fn new(id: u64,) -> Self {
-        Self {
+    /// Storage starts with the genesis block derived from `chain_spec`
+    pub fn new(id: u64, chain_spec: ChainSpec) -> Result<Self, LedgerError> {
+        let genesis = chain_spec.genesis_block()?;
+        let mut storage = Self {
             id,
+            chain_spec,
             blockchain: Default::default(),
             accounts: Default::default(),
             assets: Default::default(),
-        }
+            handshake_tx: None,
+        };
+        storage.execute_transactions(&genesis)?;
+        info!("Genesis block {} added to node {} blockchain", print_bytes(&genesis.hash), id);
+        storage.blockchain.push(genesis);
+        Ok(storage)
     }
 
+    /// Block is added on top of the best block only, a block of another branch fails with `StaleBlock`.
+    /// There is no fork choice yet, so added blocks are never disconnected
     pub fn try_add_block(&mut self, block: Block) -> Result<(), LedgerError> {
         debug!("storage id: {}", &self.id);
-        let previous_block = self.blockchain.last();
-        if previous_block.is_some() && &block.id != &0u64 {
-            let previous_block = previous_block.unwrap();
-            return if Self::validate_block(self, &block, previous_block) {
-                // TODO      persistence < --- > state in memory???
-                for commands in block.transactions.iter().map(|transaction| &transaction.commands) {
-                    for command in commands {
-                        command.execute(&mut self.accounts, &mut self.assets)?
-                    }
-                };
-                let block_id = block.id;
-                self.blockchain.push(block);
-                info!("Block with id {} added to node {} blockchain", block_id, self.id);
-                Self::reward_for_mined_block(self);
-                Ok(())
-            } else {
-                Err(LedgerError::BlockError)
+        if block.previous_block_hash.is_none() {
+            return Self::check_genesis_block(self, &block)
+        }
+        let previous_block = self.blockchain.last().unwrap();
+        if &previous_block.hash != block.previous_block_hash.as_ref().unwrap() {
+            debug!("block {} is not on top of the best block {}", &block.id, print_bytes(&previous_block.hash));
+            return Err(LedgerError::StaleBlock)
+        }
+        if Self::validate_block(self, &block, previous_block) {
+            // TODO      persistence < --- > state in memory???
+            self.execute_transactions(&block)?;
+            self.reward_for_mined_block(&block.producer)?;
+            let block_id = block.id;
+            self.blockchain.push(block);
+            info!("Block with id {} added to node {} blockchain", block_id, self.id);
+            if let Some((node_id, handshake_tx)) = self.handshake_tx.as_ref() {
+                handshake_tx.send_replace(self.handshake(node_id));
             }
-        } else if previous_block.is_none() {
-            Self::try_add_genesis_block(self, block)?;
+            Ok(())
+        } else {
+            Err(LedgerError::BlockError)
         }
-        Ok(())
     }
 
     pub fn get_blockchain_by_ref(&self) -> &Vec<Block> {
         &self.blockchain
     }
 
-    fn try_add_genesis_block(&mut self, block: Block) -> Result<(), LedgerError>  {
-        if block.previous_block_hash.is_some() {
-            return Err(LedgerError::BlockError)
-        }
-        if block.id > 1 {
-            error!("invalid block id: {}", &block.id);
-            return Err(LedgerError::BlockError)
-        }
-        if block.previous_block_hash.is_some() {
-            error!("this is not genesis block");
-            return Err(LedgerError::BlockError)
+    pub fn chain_spec(&self) -> &ChainSpec {
+        &self.chain_spec
+    }
+
+    /// Copy of accounts and assets at the best block
+    pub fn state(&self) -> (Accounts, Assets) {
+        (self.accounts.clone(), self.assets.clone())
+    }
+
+    /// Handshake of the node with public key `node_id`, kept up to date with the best block
+    pub fn watch_handshake(&mut self, node_id: &[u8]) -> watch::Receiver<Handshake> {
+        let (handshake_tx, handshake_rx) = watch::channel(self.handshake(node_id));
+        self.handshake_tx = Some((node_id.to_vec(), handshake_tx));
+        handshake_rx
+    }
+
+    /// Handshake of the node with public key `node_id` at the current best block
+    pub fn handshake(&self, node_id: &[u8]) -> Handshake {
+        let best_block = self.blockchain.last().unwrap();
+        Handshake {
+            version: PROTOCOL_VERSION,
+            chain_id: self.chain_spec.chain_id.clone(),
+            genesis_hash: self.blockchain[0].hash.clone(),
+            node_id: node_id.to_vec(),
+            best_height: best_block.id,
+            best_hash: best_block.hash.clone(),
+            features: features::PEER_EXCHANGE | features::INVENTORY | features::COMPACT_BLOCKS | features::BLOCK_RANGES
+                | features::COMPRESSION,
         }
-        if block.transactions.len() > MAX_TRANSACTIONS_IN_BLOCK {
-            error!("transactions count exceeded: {}", &block.transactions.len());
-            return Err(LedgerError::BlockError)
+    }
+
+    /// Transactions of a block are applied all or none, a failed block leaves the state untouched
+    fn execute_transactions(&mut self, block: &Block) -> Result<(), LedgerError> {
+        let mut undo = Undo::default();
+        let executed = block.transactions.iter()
+            .flat_map(|transaction| transaction.commands.iter())
+            .try_for_each(|command| command.execute_with_undo(&mut self.accounts, &mut self.assets, &mut undo));
+        if let Err(e) = executed {
+            undo.rollback(&mut self.accounts, &mut self.assets);
+            return Err(e)
         }
-        if !Self::validate_hash(&block) {
-            error!("invalid block hash: {}", print_bytes(&block.hash));
-            return Err(LedgerError::BlockError)
+        Ok(())
+    }
+
+    /// Genesis block is never added from the network, it can only match the one from chain spec
+    fn check_genesis_block(&self, block: &Block) -> Result<(), LedgerError>  {
+        if self.blockchain[0].hash != block.hash {
+            error!("foreign genesis block: {}", print_bytes(&block.hash));
+            return Err(LedgerError::GenesisBlockError)
         }
-        let block_id = block.id;
-        self.blockchain.push(block);
-        info!("Genesis block with id {} added to node {} blockchain", block_id, self.id);
         Ok(())
     }
 
@@ -87,58 +131,314 @@ impl Storage {
         //     error!("invalid block id: {}", &block.id);
         //     return false
         // }
-        if &previous_block.hash != block.previous_block_hash.as_ref().unwrap() {
-            error!("invalid previous block hash: {}", print_bytes(&previous_block.hash));
-            return false
-        }
-        if block.transactions.len() > MAX_TRANSACTIONS_IN_BLOCK {
-            error!("transactions count exceeded: {}", &block.transactions.len());
-            return false
-        }
         if convert_timestamp_to_day_time(block.timestamp)
             <=
            convert_timestamp_to_day_time(previous_block.timestamp) {
             error!("invalid block timestamp: {}", &block.timestamp);
             return false
         }
-        if !Self::validate_hash(&block) {
+        Self::check_block(&self.chain_spec, block)
+    }
+
+    /// Checks of a block that do not depend on the state of the chain,
+    /// a block failing them is invalid whatever chain it is added to
+    pub fn check_block(chain_spec: &ChainSpec, block: &Block) -> bool {
+        if block.transactions.len() > chain_spec.limits.max_transactions {
+            error!("transactions count exceeded: {}", &block.transactions.len());
+            return false
+        }
+        if !Self::validate_hash(block) {
             error!("invalid block hash: {}", print_bytes(&block.hash));
             return false
         }
-
+        if !crypto::is_hash_valid(&block.hash, chain_spec.consensus.difficulty) {
+            error!("block hash does not meet difficulty: {}", print_bytes(&block.hash));
+            return false
+        }
+        if !Self::validate_signature(block) {
+            error!("invalid block signature: {}", print_bytes(&block.signature));
+            return false
+        }
+        for transaction in block.transactions.iter() {
+            if let Err(e) = transaction.verify(&chain_spec.chain_id) {
+                error!("invalid transaction in block {}: {}", &block.id, e);
+                return false
+            }
+        }
         true
     }
 
     fn validate_hash(block: &Block) -> bool {
-        let mut block_to_validate = block.clone();
-        block_to_validate.hash = vec![];
-        block_to_validate.nonce -= 1;
-        let hash_data = bincode::serialize::<Block>(&block_to_validate).unwrap();
-        let h = hash(hash_data.as_slice());
-        block_to_validate.hash.extend_from_slice(&h);
-        block_to_validate.hash == block.hash
-    }
-
-    fn validate_chain(&self, remote_block_chain: Vec<Block>) -> bool {
-        for (i, _) in remote_block_chain.iter().enumerate() {
-            if i == 0 {
-                continue
-            }
-            let previous_block = remote_block_chain.get(i-1).unwrap();
-            let current_block = remote_block_chain.get(i).unwrap();
-            if !Self::validate_block(self, current_block, previous_block) {
-                return false
+        block.compute_hash() == block.hash
+    }
+
+    /// Block hash must be signed by the block producer
+    fn validate_signature(block: &Block) -> bool {
+        crypto::verify_signature(&block.hash, &block.signature, &block.producer)
+    }
+
+    /// Block reward is added to the native balance of the producer's account,
+    /// the account is created on the first block of the producer
+    fn reward_for_mined_block(&mut self, producer: &[u8]) -> Result<(), LedgerError> {
+        let public_key = hex::encode(producer);
+        let account_id = match self.accounts.iter().find(|(_, account)| account.public_key() == public_key) {
+            Some((account_id, _)) => *account_id,
+            None => {
+                Command::CreateAccount { public_key }.execute(&mut self.accounts, &mut self.assets)?;
+                self.accounts.len() as u32
             }
+        };
+        let balance = self.assets.get(&(account_id, String::from(NATIVE_COIN))).map_or(0, Asset::value);
+        let reward = self.chain_spec.consensus.block_reward;
+        self.assets.insert((account_id, String::from(NATIVE_COIN)), Asset::new_with_value(balance.saturating_add(reward)));
+        Ok(())
+    }
+}
+
+/// Pages of the reply to `range`, the storage is locked for one page at a time
+/// and blocks added meanwhile are served as well
+pub(crate) fn block_pages(storage: Arc<Mutex<Storage>>, range: BlockRange) -> impl Stream<Item = BlockPage> {
+    stream::unfold(Some(range.from), move |next| {
+        let storage = storage.clone();
+        let range = range.clone();
+        async move {
+            let next = next?;
+            let page = range.page(storage.lock().await.get_blockchain_by_ref(), next);
+            let following = page.more.then(|| next + page.blocks.len() as u64);
+            Some((page, following))
         }
-        true
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ursa::signatures::ed25519::Ed25519Sha512;
+    use ursa::signatures::SignatureScheme;
+    use ursa::keys::{PrivateKey, PublicKey};
+    use errors::LedgerError;
+    use state::{Asset, Assets, Block, Command, Transaction};
+    use std::sync::Arc;
+    use futures::StreamExt;
+    use tokio::sync::Mutex;
+    use network::blocks::BlockRange;
+    use state::chain_spec::ChainSpec;
+    use crate::storage::{block_pages, Storage};
+
+    #[test]
+    fn handshake_follows_best_block() {
+        let mut storage = Storage::new(1, test_spec(0)).unwrap();
+        let genesis = storage.get_blockchain_by_ref()[0].clone();
+        let handshake_rx = storage.watch_handshake(&[7; 32]);
+        assert_eq!(handshake_rx.borrow().best_height, 0);
+        assert_eq!(handshake_rx.borrow().best_hash, genesis.hash);
+        storage.try_add_block(signed_block(1, &genesis, 0)).unwrap();
+        let handshake = handshake_rx.borrow().clone();
+        assert_eq!(handshake.genesis_hash, genesis.hash);
+        assert_eq!(handshake.best_height, 1);
+        assert_eq!(handshake.best_hash, storage.get_blockchain_by_ref()[1].hash);
+        assert_eq!(handshake.node_id, vec![7; 32]);
+    }
+
+    #[test]
+    fn storage_starts_with_genesis_block() {
+        let spec = test_spec(0);
+        let storage = Storage::new(1, spec.clone()).unwrap();
+        let blockchain = storage.get_blockchain_by_ref();
+        assert_eq!(blockchain.len(), 1);
+        assert_eq!(blockchain[0].hash, spec.genesis_block().unwrap().hash);
+    }
+
+    #[test]
+    fn add_signed_blocks_ok() {
+        let mut storage = Storage::new(1, test_spec(0)).unwrap();
+        let genesis = storage.get_blockchain_by_ref()[0].clone();
+        let block = signed_block(1, &genesis, 0);
+        let next_block = signed_block(2, &block, 0);
+        assert!(storage.try_add_block(block).is_ok());
+        assert!(storage.try_add_block(next_block).is_ok());
+        assert_eq!(storage.get_blockchain_by_ref().len(), 3);
     }
 
-    fn reward_for_mined_block(&mut self) {
-        self.assets.insert((1, String::from(NATIVE_COIN)), Asset::new_with_value(1));
+    #[tokio::test]
+    async fn blocks_served_in_order_of_height() {
+        let mut storage = Storage::new(1, test_spec(0)).unwrap();
+        let mut previous = storage.get_blockchain_by_ref()[0].clone();
+        for id in 1..=3 {
+            let block = signed_block(id, &previous, 0);
+            storage.try_add_block(block.clone()).unwrap();
+            previous = block;
+        }
+        let storage = Arc::new(Mutex::new(storage));
+        let pages = block_pages(storage.clone(), BlockRange::new(1, 10)).collect::<Vec<_>>().await;
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].blocks.iter().map(|b| b.id).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(pages[0].next, None);
+        let pages = block_pages(storage, BlockRange { from: 0, to: 3, limit: 2 }).collect::<Vec<_>>().await;
+        assert_eq!(pages[0].blocks.iter().map(|b| b.id).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(pages[0].next, Some(2));
     }
 
-    fn persist_block(block: &Block) {
+    #[test]
+    fn block_with_forged_signature_rejected() {
+        let mut storage = Storage::new(1, test_spec(0)).unwrap();
+        let genesis = storage.get_blockchain_by_ref()[0].clone();
+        let mut block = signed_block(1, &genesis, 0);
+        let (other_public_key, _) = Ed25519Sha512::new().keypair(None).unwrap();
+        block.producer = other_public_key.0.clone();
+        block.hash = block.compute_hash();
+        assert!(storage.try_add_block(block).is_err());
+        assert_eq!(storage.get_blockchain_by_ref().len(), 1);
+    }
 
+    #[test]
+    fn block_off_best_block_is_stale() {
+        let mut storage = Storage::new(1, test_spec(0)).unwrap();
+        let genesis = storage.get_blockchain_by_ref()[0].clone();
+        storage.try_add_block(signed_block(1, &genesis, 0)).unwrap();
+        assert_eq!(storage.try_add_block(signed_block(1, &genesis, 0)).err(), Some(LedgerError::StaleBlock));
+        assert_eq!(storage.get_blockchain_by_ref().len(), 2);
     }
-}
 
+    #[test]
+    fn failed_block_leaves_state_untouched() {
+        let spec = test_spec_with_accounts("[[accounts]]\npublic_key = \"aa\"\nbalances = { TEST = 10 }");
+        let mut storage = Storage::new(1, spec).unwrap();
+        let genesis = storage.get_blockchain_by_ref()[0].clone();
+        let (public_key, private_key) = crypto::generate_keypair();
+        let commands = vec![
+            Command::CreateAccount { public_key: "bb".to_string() },
+            Command::TransferFunds { account_from_id: 1, account_to_id: 2, value: 5, asset_id: "TEST".to_string() },
+            Command::TransferFunds { account_from_id: 1, account_to_id: 2, value: 50, asset_id: "TEST".to_string() },
+        ];
+        let transaction = Transaction::new_signed("test", 0, 1, commands, &public_key, &private_key).unwrap();
+        let block = signed_block_with_transactions(1, &genesis, 0, vec![transaction]);
+        assert!(storage.try_add_block(block).is_err());
+        let (accounts, assets) = storage.state();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(assets.len(), 1);
+        assert_eq!(balance(&assets, 1, "TEST"), Some(10));
+    }
+
+    #[test]
+    fn block_below_difficulty_rejected() {
+        let mut storage = Storage::new(1, test_spec(1)).unwrap();
+        let genesis = storage.get_blockchain_by_ref()[0].clone();
+        assert!(storage.try_add_block(signed_block(1, &genesis, 0)).is_err());
+        assert!(storage.try_add_block(signed_block(1, &genesis, 1)).is_ok());
+    }
+
+    #[test]
+    fn transaction_of_another_chain_rejected() {
+        let mut storage = Storage::new(1, test_spec(0)).unwrap();
+        let genesis = storage.get_blockchain_by_ref()[0].clone();
+        let (public_key, private_key) = crypto::generate_keypair();
+        let commands = vec![Command::CreateAccount { public_key: "pk".to_string() }];
+        let foreign = Transaction::new_signed("production", 0, 1, commands.clone(), &public_key, &private_key)
+            .unwrap();
+        let block = signed_block_with_transactions(1, &genesis, 0, vec![foreign]);
+        assert!(storage.try_add_block(block).is_err());
+        let own = Transaction::new_signed("test", 0, 1, commands, &public_key, &private_key).unwrap();
+        let block = signed_block_with_transactions(1, &genesis, 0, vec![own]);
+        assert!(storage.try_add_block(block).is_ok());
+    }
+
+    #[test]
+    fn foreign_genesis_block_rejected() {
+        let mut storage = Storage::new(1, test_spec(0)).unwrap();
+        let genesis = storage.get_blockchain_by_ref()[0].clone();
+        assert!(storage.try_add_block(genesis.clone()).is_ok());
+        let mut foreign_genesis = genesis;
+        foreign_genesis.timestamp += 1;
+        foreign_genesis.hash = foreign_genesis.compute_hash();
+        assert_eq!(storage.try_add_block(foreign_genesis).err(), Some(LedgerError::GenesisBlockError));
+        assert_eq!(storage.get_blockchain_by_ref().len(), 1);
+    }
+
+    #[test]
+    fn genesis_balances_survive_block() {
+        let spec = test_spec_with_accounts("[[accounts]]\npublic_key = \"aa\"\nbalances = { NATIVE = 10, TEST = 20 }");
+        let mut storage = Storage::new(1, spec).unwrap();
+        let genesis = storage.get_blockchain_by_ref()[0].clone();
+        let block = signed_block(1, &genesis, 0);
+        let producer = hex::encode(&block.producer);
+        storage.try_add_block(block).unwrap();
+        let (accounts, assets) = storage.state();
+        assert_eq!(balance(&assets, 1, "NATIVE"), Some(10));
+        assert_eq!(balance(&assets, 1, "TEST"), Some(20));
+        assert_eq!(accounts[&2].public_key(), producer);
+        assert_eq!(balance(&assets, 2, "NATIVE"), Some(1));
+    }
+
+    #[test]
+    fn reward_added_to_producer_balance() {
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+        let spec = test_spec_with_accounts(&format!(
+            "[[accounts]]\npublic_key = \"{}\"\nbalances = {{ NATIVE = 10 }}", hex::encode(&public_key.0)));
+        let mut storage = Storage::new(1, spec).unwrap();
+        let genesis = storage.get_blockchain_by_ref()[0].clone();
+        let block = mined_block(1, &genesis, 0, vec![], &public_key, &private_key);
+        let next_block = mined_block(2, &block, 0, vec![], &public_key, &private_key);
+        storage.try_add_block(block).unwrap();
+        storage.try_add_block(next_block).unwrap();
+        let (accounts, assets) = storage.state();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(balance(&assets, 1, "NATIVE"), Some(12));
+    }
+
+    fn balance(assets: &Assets, account_id: u32, asset_id: &str) -> Option<u32> {
+        assets.get(&(account_id, asset_id.to_string())).map(Asset::value)
+    }
+
+    fn test_spec(difficulty: usize) -> ChainSpec {
+        ChainSpec::from_toml(&format!(
+            "chain_id = \"test\"\n[consensus]\ndifficulty = {}\nblock_reward = 1", difficulty))
+            .unwrap()
+    }
+
+    fn test_spec_with_accounts(accounts: &str) -> ChainSpec {
+        ChainSpec::from_toml(&format!(
+            "chain_id = \"test\"\n[consensus]\ndifficulty = 0\nblock_reward = 1\n{}", accounts))
+            .unwrap()
+    }
+
+    /// Block on top of `previous_block`, with exactly `zero_bytes` leading zero bytes of hash
+    fn signed_block(id: u64, previous_block: &Block, zero_bytes: usize) -> Block {
+        signed_block_with_transactions(id, previous_block, zero_bytes, vec![])
+    }
+
+    fn signed_block_with_transactions(id: u64,
+                                      previous_block: &Block,
+                                      zero_bytes: usize,
+                                      transactions: Vec<Transaction>)
+        -> Block
+    {
+        let (public_key, private_key) = Ed25519Sha512::new().keypair(None).unwrap();
+        mined_block(id, previous_block, zero_bytes, transactions, &public_key, &private_key)
+    }
+
+    fn mined_block(id: u64,
+                   previous_block: &Block,
+                   zero_bytes: usize,
+                   transactions: Vec<Transaction>,
+                   public_key: &PublicKey,
+                   private_key: &PrivateKey)
+        -> Block
+    {
+        let mut block = Block {
+            id,
+            timestamp: previous_block.timestamp + 1,
+            producer: public_key.0.clone(),
+            previous_block_hash: Some(previous_block.hash.clone()),
+            transactions,
+            ..Default::default()
+        };
+        block.hash = block.compute_hash();
+        while block.hash.iter().take_while(|n| **n == 0).count() != zero_bytes {
+            block.nonce += 1;
+            block.hash = block.compute_hash();
+        }
+        block.signature = Ed25519Sha512::new().sign(&block.hash, private_key).unwrap();
+        block
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use errors::LedgerError;
+use crate::{Block, Command, Transaction, MAX_TRANSACTIONS_IN_BLOCK};
+
+/// Chain specification shared by every node of the network, read from a TOML file.
+/// The genesis block is derived from it deterministically
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChainSpec {
+    pub chain_id: String,
+    #[serde(default)]
+    pub genesis_timestamp: i64,
+    /// Hex-encoded hash of the genesis block, checked at node startup when present
+    pub genesis_hash: Option<String>,
+    #[serde(default)]
+    pub consensus: Consensus,
+    #[serde(default)]
+    pub limits: BlockLimits,
+    #[serde(default)]
+    pub accounts: Vec<GenesisAccount>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Consensus {
+    /// Required count of leading zero bytes of a block hash
+    pub difficulty: usize,
+    /// Amount of native coins rewarded for a mined block
+    pub block_reward: u32,
+}
+
+impl Default for Consensus {
+    fn default() -> Self {
+        Self { difficulty: 2, block_reward: 1 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlockLimits {
+    pub max_transactions: usize,
+    /// Count of pending transactions a miner waits for before mining a block
+    pub min_transactions: usize,
+}
+
+impl Default for BlockLimits {
+    fn default() -> Self {
+        Self { max_transactions: MAX_TRANSACTIONS_IN_BLOCK, min_transactions: 10 }
+    }
+}
+
+/// Account created in the genesis block, accounts get ids 1, 2, ... in order of declaration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisAccount {
+    pub public_key: String,
+    /// asset id -> value
+    #[serde(default)]
+    pub balances: BTreeMap<String, u32>,
+}
+
+impl ChainSpec {
+
+    pub fn load(path: &Path) -> Result<Self, LedgerError> {
+        let content = fs::read_to_string(path).map_err(|_| LedgerError::ChainSpecError)?;
+        Self::from_toml(&content)
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, LedgerError> {
+        let spec: Self = toml::from_str(content).map_err(|_| LedgerError::ChainSpecError)?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    fn validate(&self) -> Result<(), LedgerError> {
+        if self.chain_id.is_empty()
+            || self.chain_id.len() > u8::MAX as usize
+            || self.consensus.difficulty > 32
+            || self.limits.max_transactions == 0
+            || self.limits.min_transactions > self.limits.max_transactions
+        {
+            return Err(LedgerError::ChainSpecError)
+        }
+        Ok(())
+    }
+
+    /// Genesis block of the chain. It has neither producer nor signatures, so it is never
+    /// accepted from the network, every node builds it from the spec instead.
+    /// Fails if the hash differs from `genesis_hash`
+    pub fn genesis_block(&self) -> Result<Block, LedgerError> {
+        let mut commands = Vec::new();
+        for (i, account) in self.accounts.iter().enumerate() {
+            commands.push(Command::CreateAccount { public_key: account.public_key.clone() });
+            for (asset_id, value) in account.balances.iter() {
+                commands.push(Command::AddFunds {
+                    account_id: (i + 1) as u32,
+                    value: *value,
+                    asset_id: asset_id.clone(),
+                });
+            }
+        }
+        let mut block = Block {
+            id: 0,
+            timestamp: self.genesis_timestamp,
+            transactions: vec![Transaction {
+                chain_id: self.chain_id.clone(),
+                signer: vec![],
+                nonce: 0,
+                fee: 0,
+                commands,
+                signature: vec![],
+            }],
+            ..Default::default()
+        };
+        block.hash = block.compute_hash();
+        if let Some(genesis_hash) = self.genesis_hash.as_ref() {
+            if &hex::encode(&block.hash) != genesis_hash {
+                return Err(LedgerError::GenesisBlockError)
+            }
+        }
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use errors::LedgerError;
+    use crate::chain_spec::ChainSpec;
+    use crate::Command;
+
+    const CHAIN_SPEC: &str = include_str!("../../chain_spec.toml");
+
+    #[test]
+    fn repository_chain_spec_genesis_hash_ok() {
+        let spec = ChainSpec::from_toml(CHAIN_SPEC).unwrap();
+        let genesis = spec.genesis_block().unwrap();
+        assert_eq!(Some(hex::encode(&genesis.hash)), spec.genesis_hash);
+    }
+
+    #[test]
+    fn genesis_block_is_deterministic() {
+        let spec = test_spec();
+        let genesis = spec.genesis_block().unwrap();
+        assert_eq!(genesis.hash, spec.genesis_block().unwrap().hash);
+        assert_eq!(genesis.id, 0);
+        assert_eq!(genesis.previous_block_hash, None);
+        let commands = &genesis.transactions[0].commands;
+        assert_eq!(commands.len(), 4);
+        assert!(matches!(&commands[2],
+            Command::AddFunds { account_id: 1, value: 20, asset_id } if asset_id == "TEST"));
+    }
+
+    #[test]
+    fn genesis_depends_on_chain_id() {
+        let spec = test_spec();
+        let mut other = spec.clone();
+        other.chain_id = "other".to_string();
+        assert_ne!(spec.genesis_block().unwrap().hash, other.genesis_block().unwrap().hash);
+    }
+
+    #[test]
+    fn genesis_hash_mismatch_rejected() {
+        let mut spec = test_spec();
+        spec.genesis_hash = Some("00".repeat(32));
+        assert_eq!(spec.genesis_block().err(), Some(LedgerError::GenesisBlockError));
+    }
+
+    #[test]
+    fn genesis_depends_on_accounts() {
+        let spec = test_spec();
+        let mut other = spec.clone();
+        other.accounts[1].balances.insert("NATIVE".to_string(), 1);
+        assert_ne!(spec.genesis_block().unwrap().hash, other.genesis_block().unwrap().hash);
+    }
+
+    #[test]
+    fn invalid_spec_rejected() {
+        assert_eq!(ChainSpec::from_toml("chain_id = \"\"").err(), Some(LedgerError::ChainSpecError));
+        assert_eq!(ChainSpec::from_toml("genesis_timestamp = 1").err(), Some(LedgerError::ChainSpecError));
+        let spec = "chain_id = \"test\"\n[limits]\nmax_transactions = 1\nmin_transactions = 2";
+        assert_eq!(ChainSpec::from_toml(spec).err(), Some(LedgerError::ChainSpecError));
+        // misspelled key is not silently ignored
+        let spec = "chain_id = \"test\"\ngenesis_hsah = \"00\"";
+        assert_eq!(ChainSpec::from_toml(spec).err(), Some(LedgerError::ChainSpecError));
+        let spec = "chain_id = \"test\"\n[consensus]\ndifficulty = 1\nblock_reward = 1\nreward = 2";
+        assert_eq!(ChainSpec::from_toml(spec).err(), Some(LedgerError::ChainSpecError));
+    }
+
+    fn test_spec() -> ChainSpec {
+        ChainSpec::from_toml(r#"
+            chain_id = "test"
+            genesis_timestamp = 1690000000
+
+            [consensus]
+            difficulty = 1
+            block_reward = 5
+
+            [[accounts]]
+            public_key = "aa"
+            balances = { NATIVE = 10, TEST = 20 }
+
+            [[accounts]]
+            public_key = "bb"
+        "#).unwrap()
+    }
+}
@@ -1,3 +1,5 @@
+pub mod chain_spec;
+
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
@@ -15,6 +17,12 @@ pub struct Account {
     public_key: String,
 }
 
+impl Account {
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+}
+
 pub type Accounts = HashMap<u32, Account>;
 
 #[derive(Debug, Clone)]
@@ -26,14 +34,59 @@ impl Asset {
     pub fn new_with_value(value: u32) -> Self {
         Self { value }
     }
+
+    pub fn value(&self) -> u32 {
+        self.value
+    }
 }
 
 pub type Assets = HashMap<(u32, String), Asset>;
 
+/// Accounts and assets changed by commands, with their values before the change
+#[derive(Debug, Default)]
+pub struct Undo {
+    accounts: Vec<(u32, Option<Account>)>,
+    assets: Vec<((u32, String), Option<Asset>)>,
+}
+
+impl Undo {
+
+    /// Puts back the recorded values, latest change first
+    pub fn rollback(self, accounts: &mut Accounts, assets: &mut Assets) {
+        for (account_id, account) in self.accounts.into_iter().rev() {
+            match account {
+                Some(account) => accounts.insert(account_id, account),
+                None => accounts.remove(&account_id),
+            };
+        }
+        for (key, asset) in self.assets.into_iter().rev() {
+            match asset {
+                Some(asset) => assets.insert(key, asset),
+                None => assets.remove(&key),
+            };
+        }
+    }
+
+    fn record_asset(&mut self, assets: &Assets, account_id: u32, asset_id: &str) {
+        let key = (account_id, asset_id.to_string());
+        let asset = assets.get(&key).cloned();
+        self.assets.push((key, asset));
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
+    /// Chain the transaction is intended for, protects from replaying it on another network
+    pub chain_id: String,
+    /// Public key of the transaction author
+    pub signer: Vec<u8>,
+    /// Number of the transaction of its signer, a pending transaction is replaced by one with the same signer
+    /// and nonce paying a higher fee
+    pub nonce: u64,
     pub fee: u32,
     pub commands: Vec<Command>,
+    /// Signer's signature of [`Transaction::signing_data`]
+    pub signature: Vec<u8>,
 }
 
 unsafe impl Send for Transaction {}
@@ -55,7 +108,7 @@ impl PartialOrd<Self> for Transaction {
 
 impl Ord for Transaction {
     fn cmp(&self, other: &Self) -> Ordering {
-        let difference = i64::from(self.fee.clone()) - i64::from(other.fee.clone());
+        let difference = i64::from(self.fee) - i64::from(other.fee);
         match difference {
             d if d > 0 => Ordering::Greater,
             d if d < 0 => Ordering::Less,
@@ -70,7 +123,7 @@ impl Display for Transaction {
         write!(f, "transaction: fee : {}, commands: {}", self.fee, self.commands.iter()
             .map(|c| c.to_string())
             .reduce(|acc, c| acc + " " + c.as_str())
-            .unwrap())
+            .unwrap_or_default())
     }
 }
 
@@ -101,6 +154,9 @@ pub struct Block {
     pub id: u64,
     pub timestamp: i64,
     pub nonce: u32,
+    /// Public key of the node that mined the block
+    pub producer: Vec<u8>,
+    /// Producer's signature of the block hash
     pub signature: Vec<u8>,
     pub hash: Hash,
     pub previous_block_hash: Option<Hash>,
@@ -113,6 +169,7 @@ impl Display for Block {
                    id: {}, \n
                    timestamp: {}, \n
                    nonce: {}, \n
+                   producer: {}, \n
                    signature: {},  \n
                    hash: {}, \n
                    previous_block_hash: {}, \n
@@ -120,17 +177,173 @@ impl Display for Block {
                &self.id,
                &self.timestamp,
                &self.nonce,
+               print_bytes(&self.producer),
                print_bytes(&self.signature),
                print_bytes(&self.hash),
                print_bytes(&self.previous_block_hash.clone().unwrap_or("None".as_bytes().to_vec())),
                self.transactions.iter()
                     .map(|c| c.to_string())
                     .reduce(|acc, c| acc + " " + c.as_str())
-                    .unwrap())
+                    .unwrap_or_default())
+    }
+}
+
+impl Block {
+
+    /// Hash of the block as agreed by miners and validators: sha256 over [`Block::hash_data`].
+    pub fn compute_hash(&self) -> Hash {
+        crypto::hash(&self.hash_data())
+    }
+
+    /// Canonical, byte-exact pre-image of the block hash. All integers are big-endian,
+    /// `signature` and `hash` are not part of it.
+    ///
+    /// ```text
+    /// id                  : u64
+    /// timestamp           : i64
+    /// nonce               : u32
+    /// previous_block_hash : u8 (0 - None, 1 - Some) [+ bytes]
+    /// producer            : bytes
+    /// transactions        : u32 count + transaction * count
+    ///
+    /// transaction : chain_id bytes + signer bytes + nonce u64 + fee u32 + u32 count + command * count
+    ///               + signature bytes
+    /// command     : u8 tag (0 - CreateAccount, 1 - AddFunds, 2 - TransferFunds)
+    ///               + fields in declaration order
+    /// bytes       : u32 length + raw bytes (strings are UTF-8)
+    /// ```
+    pub fn hash_data(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.nonce.to_be_bytes());
+        match &self.previous_block_hash {
+            None => buf.push(0),
+            Some(hash) => {
+                buf.push(1);
+                encode_bytes(&mut buf, hash);
+            }
+        }
+        encode_bytes(&mut buf, &self.producer);
+        buf.extend_from_slice(&(self.transactions.len() as u32).to_be_bytes());
+        for transaction in self.transactions.iter() {
+            transaction.encode(&mut buf);
+        }
+        buf
+    }
+}
+
+impl Transaction {
+
+    /// Transaction for `chain_id` signed by the owner of ed25519 key pair
+    pub fn new_signed(chain_id: &str,
+                      nonce: u64,
+                      fee: u32,
+                      commands: Vec<Command>,
+                      public_key: &[u8],
+                      private_key: &[u8])
+        -> Result<Self, LedgerError>
+    {
+        let mut transaction = Self {
+            chain_id: chain_id.to_string(),
+            signer: public_key.to_vec(),
+            nonce,
+            fee,
+            commands,
+            signature: vec![],
+        };
+        transaction.signature = crypto::sign(&transaction.signing_data(), private_key)
+            .ok_or(LedgerError::BadSignature)?;
+        Ok(transaction)
+    }
+
+    /// Signed payload: canonical encoding of the transaction without signature
+    pub fn signing_data(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_bytes(&mut buf, self.chain_id.as_bytes());
+        encode_bytes(&mut buf, &self.signer);
+        buf.extend_from_slice(&self.nonce.to_be_bytes());
+        buf.extend_from_slice(&self.fee.to_be_bytes());
+        buf.extend_from_slice(&(self.commands.len() as u32).to_be_bytes());
+        for command in self.commands.iter() {
+            command.encode(&mut buf);
+        }
+        buf
+    }
+
+    /// Appends canonical encoding of the transaction, see [`Block::hash_data`]
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.signing_data());
+        encode_bytes(buf, &self.signature);
+    }
+
+    /// Id of the transaction in the network: sha256 over its canonical encoding, signature included
+    pub fn id(&self) -> Hash {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        crypto::hash(&buf)
+    }
+
+    /// Transaction must belong to `chain_id` and be signed by its signer
+    pub fn verify(&self, chain_id: &str) -> Result<(), LedgerError> {
+        if self.chain_id != chain_id {
+            return Err(LedgerError::WrongChainId)
+        }
+        if !crypto::verify_signature(&self.signing_data(), &self.signature, &self.signer) {
+            return Err(LedgerError::BadSignature)
+        }
+        Ok(())
     }
 }
 
 impl Command {
+
+    /// Appends canonical encoding of the command, see [`Block::hash_data`]
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Self::CreateAccount { public_key } => {
+                buf.push(0);
+                encode_bytes(buf, public_key.as_bytes());
+            }
+            Self::AddFunds { account_id, value, asset_id } => {
+                buf.push(1);
+                buf.extend_from_slice(&account_id.to_be_bytes());
+                buf.extend_from_slice(&value.to_be_bytes());
+                encode_bytes(buf, asset_id.as_bytes());
+            }
+            Self::TransferFunds { account_from_id, account_to_id, value, asset_id } => {
+                buf.push(2);
+                buf.extend_from_slice(&account_from_id.to_be_bytes());
+                buf.extend_from_slice(&account_to_id.to_be_bytes());
+                buf.extend_from_slice(&value.to_be_bytes());
+                encode_bytes(buf, asset_id.as_bytes());
+            }
+        }
+    }
+
+    /// Like [`Command::execute`], values of the entries it changes are recorded in `undo` first
+    pub fn execute_with_undo(&self,
+                             accounts: &mut Accounts,
+                             assets: &mut Assets,
+                             undo: &mut Undo)
+        -> Result<(), LedgerError>
+    {
+        match self {
+            Self::CreateAccount { .. } => {
+                let account_id = (accounts.len() + 1) as u32;
+                undo.accounts.push((account_id, accounts.get(&account_id).cloned()));
+            }
+            Self::AddFunds { account_id, asset_id, .. } => {
+                undo.record_asset(assets, *account_id, asset_id);
+            }
+            Self::TransferFunds { account_from_id, account_to_id, asset_id, .. } => {
+                undo.record_asset(assets, *account_from_id, asset_id);
+                undo.record_asset(assets, *account_to_id, asset_id);
+            }
+        }
+        self.execute(accounts, assets)
+    }
+
     pub fn execute(&self,
                    accounts: &mut Accounts,
                    assets: &mut Assets)
@@ -151,7 +364,7 @@ impl Command {
                 value,
                 asset_id,
             } => {
-                assets.insert((account_id.clone(), asset_id.clone()), Asset { value: value.clone() });
+                assets.insert((*account_id, asset_id.clone()), Asset { value: *value });
                 Ok(())
             },
 
@@ -161,12 +374,12 @@ impl Command {
                 value,
                 asset_id
             } => {
-                if let Some(account_asset) = assets.get(&(account_from_id.clone(), asset_id.clone())) {
+                if let Some(account_asset) = assets.get(&(*account_from_id, asset_id.clone())) {
                     if account_asset.value < *value {
                         return Err(LedgerError::InsufficientFunds)
                     }
-                    assets.remove(&(account_from_id.clone(), asset_id.clone()));
-                    assets.insert((account_to_id.clone(), asset_id.clone()), Asset { value: value.clone() });
+                    assets.remove(&(*account_from_id, asset_id.clone()));
+                    assets.insert((*account_to_id, asset_id.clone()), Asset { value: *value });
                     Ok(())
                 } else {
                     Err(LedgerError::NoSuchAsset)
@@ -176,7 +389,178 @@ impl Command {
     }
 }
 
+fn encode_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
 #[cfg(test)]
 mod tests {
+    use errors::LedgerError;
+    use crate::{Accounts, Asset, Assets, Block, Command, Transaction, Undo};
+
+    const GOLDEN_HASH_DATA: &str = concat!(
+        "0000000000000002",                                 // id
+        "0000000064bb5a80",                                 // timestamp
+        "0000004d",                                         // nonce
+        "01", "00000004", "0000abcd",                       // previous_block_hash
+        "00000003", "070809",                               // producer
+        "00000002",                                         // transactions count
+        "00000001", "63", "00000001", "05",                 // chain_id, signer
+        "0000000000000000",                                 // nonce
+        "0000000a", "00000001",                             // fee, commands count
+        "00", "00000002", "706b",                           // CreateAccount
+        "00000001", "06",                                   // signature
+        "00000001", "63", "00000001", "05",                 // chain_id, signer
+        "0000000000000001",                                 // nonce
+        "00000014", "00000002",                             // fee, commands count
+        "01", "00000001", "00000064", "00000001", "41",     // AddFunds
+        "02", "00000001", "00000002", "00000005", "00000001", "41", // TransferFunds
+        "00000001", "06");                                  // signature
+
+    const GOLDEN_HASH: &str = "afb6c160c1feb2bcaeaccb0dfa50bb74066581c9efccf2cfc65a90e75db77acb";
+
+    #[test]
+    fn block_hash_data_golden_vector() {
+        let block = golden_block();
+        assert_eq!(to_hex(&block.hash_data()), GOLDEN_HASH_DATA);
+    }
 
+    #[test]
+    fn block_hash_golden_vector() {
+        let block = golden_block();
+        assert_eq!(to_hex(&block.compute_hash()), GOLDEN_HASH);
+    }
+
+    #[test]
+    fn block_hash_ignores_signature_and_hash() {
+        let block = golden_block();
+        let mut other = block.clone();
+        other.signature = vec![9, 9, 9];
+        other.hash = vec![1];
+        assert_eq!(block.compute_hash(), other.compute_hash());
+    }
+
+    #[test]
+    fn genesis_block_hash_data() {
+        let block = Block::default();
+        assert_eq!(to_hex(&block.hash_data()), concat!(
+            "0000000000000000",
+            "0000000000000000",
+            "00000000",
+            "00",
+            "00000000",
+            "00000000"));
+    }
+
+    #[test]
+    fn signed_transaction_verified() {
+        let (public_key, private_key) = crypto::generate_keypair();
+        let transaction = Transaction::new_signed(
+            "test", 0, 1, vec![Command::CreateAccount { public_key: "pk".to_string() }],
+            &public_key, &private_key)
+            .unwrap();
+        assert_eq!(transaction.verify("test"), Ok(()));
+        assert_eq!(transaction.verify("production"), Err(LedgerError::WrongChainId));
+    }
+
+    #[test]
+    fn chain_id_is_signed() {
+        let (public_key, private_key) = crypto::generate_keypair();
+        let mut transaction = Transaction::new_signed(
+            "test", 0, 1, vec![], &public_key, &private_key)
+            .unwrap();
+        transaction.chain_id = "production".to_string();
+        assert_eq!(transaction.verify("production"), Err(LedgerError::BadSignature));
+        transaction.chain_id = "test".to_string();
+        transaction.fee = 2;
+        assert_eq!(transaction.verify("test"), Err(LedgerError::BadSignature));
+    }
+
+    #[test]
+    fn transaction_id_covers_signature() {
+        let (public_key, private_key) = crypto::generate_keypair();
+        let transaction = Transaction::new_signed("test", 0, 1, vec![], &public_key, &private_key).unwrap();
+        assert_eq!(transaction.id(), transaction.clone().id());
+        let mut other = transaction.clone();
+        other.fee = 2;
+        assert_ne!(transaction.id(), other.id());
+        let mut other = transaction.clone();
+        other.signature = vec![];
+        assert_ne!(transaction.id(), other.id());
+    }
+
+    #[test]
+    fn undo_restores_changed_entries() {
+        let mut accounts = Accounts::new();
+        let mut assets = Assets::new();
+        Command::CreateAccount { public_key: "aa".to_string() }.execute(&mut accounts, &mut assets).unwrap();
+        assets.insert((1, "A".to_string()), Asset::new_with_value(10));
+        let commands = [
+            Command::CreateAccount { public_key: "bb".to_string() },
+            Command::TransferFunds { account_from_id: 1, account_to_id: 2, value: 4, asset_id: "A".to_string() },
+            Command::AddFunds { account_id: 1, value: 7, asset_id: "B".to_string() },
+        ];
+        let mut undo = Undo::default();
+        for command in commands.iter() {
+            command.execute_with_undo(&mut accounts, &mut assets, &mut undo).unwrap();
+        }
+        assert_eq!(accounts.len(), 2);
+        undo.rollback(&mut accounts, &mut assets);
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[&1].public_key(), "aa");
+        assert_eq!(assets.len(), 1);
+        assert_eq!(assets[&(1, "A".to_string())].value(), 10);
+    }
+
+    #[test]
+    fn empty_block_and_transaction_displayed() {
+        // found by fuzzing: displaying a decoded block without transactions panicked
+        let transaction = Transaction { chain_id: "test".to_string(), signer: vec![], nonce: 0, fee: 1, commands: vec![], signature: vec![] };
+        let block = Block { transactions: vec![transaction], ..Default::default() };
+        assert!(block.to_string().contains("fee : 1, commands: "));
+        assert!(Block::default().to_string().contains("transactions:"));
+    }
+
+    fn golden_block() -> Block {
+        Block {
+            id: 2,
+            timestamp: 1_690_000_000,
+            nonce: 77,
+            producer: vec![7, 8, 9],
+            signature: vec![1, 2, 3],
+            hash: vec![],
+            previous_block_hash: Some(vec![0, 0, 0xab, 0xcd]),
+            transactions: vec![
+                Transaction {
+                    chain_id: "c".to_string(),
+                    signer: vec![5],
+                    nonce: 0,
+                    fee: 10,
+                    commands: vec![Command::CreateAccount { public_key: "pk".to_string() }],
+                    signature: vec![6],
+                },
+                Transaction {
+                    chain_id: "c".to_string(),
+                    signer: vec![5],
+                    nonce: 1,
+                    fee: 20,
+                    commands: vec![
+                        Command::AddFunds { account_id: 1, value: 100, asset_id: "A".to_string() },
+                        Command::TransferFunds {
+                            account_from_id: 1,
+                            account_to_id: 2,
+                            value: 5,
+                            asset_id: "A".to_string()
+                        },
+                    ],
+                    signature: vec![6],
+                },
+            ],
+        }
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
 }
\ No newline at end of file
@@ -18,8 +18,7 @@ pub fn socket_addr(port: &str) -> SocketAddr {
 }
 
 pub fn convert_timestamp_to_day_time(timestamp: i64) -> DateTime<Utc> {
-    let naive = NaiveDateTime::from_timestamp(timestamp, 0);
-    let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
+    let datetime: DateTime<Utc> = Utc.timestamp_opt(timestamp, 0).unwrap();
     //let date = datetime.format("%Y-%m-%d %H:%M:%S");
     datetime
 }